@@ -0,0 +1,75 @@
+//! Compression formats huniq can read and write.
+//!
+//! Compression is performed by piping data through the respective
+//! command line tool (`gzip`, `zstd`, …), so huniq does not need to
+//! link against any compression library.
+
+use anyhow::{Context, Result};
+use std::process::{Child, Command, Stdio};
+
+/// Length of the longest magic byte sequence in [Compression::detect]
+pub const MAGIC_LEN: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+}
+
+impl Compression {
+    /// Detect the compression format from the first bytes of a file
+    pub fn detect(head: &[u8]) -> Option<Compression> {
+        if head.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else if head.starts_with(b"BZh") {
+            Some(Compression::Bzip2)
+        } else {
+            None
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Compression> {
+        match name {
+            "gzip" | "gz" => Some(Compression::Gzip),
+            "zstd" | "zst" => Some(Compression::Zstd),
+            "xz" => Some(Compression::Xz),
+            "bzip2" | "bz2" => Some(Compression::Bzip2),
+            _ => None,
+        }
+    }
+
+    /// The command used to (de)compress this format
+    pub fn tool(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+            Compression::Bzip2 => "bzip2",
+        }
+    }
+
+    /// Spawn the tool decompressing `input` to a pipe
+    pub fn spawn_decompressor(self, input: Stdio) -> Result<Child> {
+        self.spawn(&["-d", "-c"], input, Stdio::piped())
+    }
+
+    /// Spawn the tool compressing data from a pipe to `output`
+    pub fn spawn_compressor(self, output: Stdio) -> Result<Child> {
+        self.spawn(&["-c"], Stdio::piped(), output)
+    }
+
+    fn spawn(self, args: &[&str], input: Stdio, output: Stdio) -> Result<Child> {
+        Command::new(self.tool())
+            .args(args)
+            .stdin(input)
+            .stdout(output)
+            .spawn()
+            .with_context(|| format!("could not run {}", self.tool()))
+    }
+}
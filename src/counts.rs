@@ -0,0 +1,231 @@
+//! Counting the number of occurrences of records.
+//!
+//! The data of the records is stored in an arena instead of allocating
+//! each record separately; the hash map just stores references into the
+//! arena. With tens of millions of distinct records, this saves a lot of
+//! allocator pressure and per-allocation overhead.
+//!
+//! The records and their counts are kept in a plain vector, the hash
+//! table only stores indices into it. This way the counts can be sorted
+//! in place, without copying every entry.
+
+use ahash::RandomState as ARandomState;
+use hashbrown::raw::RawTable;
+use std::cmp::Ordering;
+use std::hash::BuildHasher;
+use std::slice;
+
+/// Size of the blocks the arena allocates
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Bump allocator for record data. Data is never moved or freed
+/// before the arena is dropped, so references remain valid.
+#[derive(Default)]
+struct Arena {
+    /// Only the last block is being allocated from
+    blocks: Vec<Vec<u8>>,
+    /// Records too large to be stored in a regular block
+    large: Vec<Box<[u8]>>,
+}
+
+impl Arena {
+    fn alloc(&mut self, data: &[u8]) -> Key {
+        if data.len() > BLOCK_SIZE / 4 {
+            self.large.push(data.into());
+            return Key::new(self.large.last().unwrap());
+        }
+
+        let fits = matches!(self.blocks.last(), Some(b) if b.capacity() - b.len() >= data.len());
+        if !fits {
+            self.blocks.push(Vec::with_capacity(BLOCK_SIZE));
+        }
+        // Never exceeds the capacity, so the block is never reallocated
+        let block = self.blocks.last_mut().unwrap();
+        let start = block.len();
+        block.extend_from_slice(data);
+        Key::new(&block[start..])
+    }
+
+    /// Take over all the data of the other arena
+    fn absorb(&mut self, other: Arena) {
+        // Keep allocating from our current block, if any
+        let current = self.blocks.pop();
+        self.blocks.extend(other.blocks);
+        self.blocks.extend(current);
+        self.large.extend(other.large);
+    }
+}
+
+/// Reference to record data in an arena
+struct Key {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl Key {
+    fn new(data: &[u8]) -> Key {
+        Key {
+            ptr: data.as_ptr(),
+            len: data.len(),
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // Safety: Keys are only created by the arena, which is owned by
+        // the same Counts as the keys and never moves or frees data.
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+/// The number of occurrences of each record
+#[derive(Default)]
+pub struct Counts {
+    /// Records in insertion order, unless sorted
+    entries: Vec<(Key, u64)>,
+    /// Indices into entries; emptied when sorting and rebuilt on demand
+    index: RawTable<usize>,
+    hasher: ARandomState,
+    arena: Arena,
+}
+
+// Safety: The keys only point into the arena, which moves along with them
+unsafe impl Send for Counts {}
+
+impl Counts {
+    pub fn with_capacity(capacity: usize) -> Counts {
+        Counts {
+            entries: Vec::with_capacity(capacity),
+            index: RawTable::with_capacity(capacity),
+            ..Counts::default()
+        }
+    }
+
+    /// Count one occurrence of the given record, returning its position
+    /// in the order records were first seen; unless sorted.
+    ///
+    /// The record is hashed once and only copied into the
+    /// arena if it has not been seen before.
+    pub fn add(&mut self, record: &[u8]) -> usize {
+        self.add_count(record, 1)
+    }
+
+    /// Like [Counts::add], counting the given number of occurrences
+    pub fn add_count(&mut self, record: &[u8], n: u64) -> usize {
+        self.reindex();
+        let hash = self.hasher.hash_one(record);
+        let entries = &mut self.entries;
+        match self.index.get(hash, |&i| entries[i].0.as_slice() == record) {
+            Some(&i) => {
+                entries[i].1 += n;
+                i
+            }
+            None => {
+                let hasher = &self.hasher;
+                self.index.insert(hash, entries.len(), |&i| {
+                    hasher.hash_one(entries[i].0.as_slice())
+                });
+                entries.push((self.arena.alloc(record), n));
+                entries.len() - 1
+            }
+        }
+    }
+
+    /// Merge counts of records not present in these counts
+    pub fn merge_disjoint(&mut self, other: Counts) {
+        let Counts { entries, arena, .. } = other;
+        self.arena.absorb(arena);
+        self.entries.extend(entries);
+        // Indexes the new entries with our hasher
+        self.reindex();
+    }
+
+    /// Sort the records by their count, in place
+    pub fn sort_by<F>(&mut self, mut compare: F)
+    where
+        F: FnMut(u64, u64) -> Ordering,
+    {
+        // Free the index first, so sorting doesn't need any more memory
+        self.index = RawTable::new();
+        self.entries.sort_unstable_by(|a, b| compare(a.1, b.1));
+    }
+
+    /// Index any entries not yet in the index
+    fn reindex(&mut self) {
+        if self.index.len() == self.entries.len() {
+            return;
+        }
+        let (hasher, entries) = (&self.hasher, &self.entries);
+        self.index.reserve(entries.len() - self.index.len(), |&i| {
+            hasher.hash_one(entries[i].0.as_slice())
+        });
+        for i in self.index.len()..entries.len() {
+            let hash = hasher.hash_one(entries[i].0.as_slice());
+            self.index
+                .insert(hash, i, |&i| hasher.hash_one(entries[i].0.as_slice()));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The record at the given position, as returned by [Counts::add]
+    pub fn record(&self, i: usize) -> &[u8] {
+        self.entries[i].0.as_slice()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], u64)> {
+        self.entries.iter().map(|(k, v)| (k.as_slice(), *v))
+    }
+}
+
+/// All occurrences of each record, grouped by the record's key
+#[derive(Default)]
+pub struct Groups {
+    counts: Counts,
+    /// The group and the data of each occurrence, in input order
+    occurrences: Vec<(usize, Key)>,
+    arena: Arena,
+}
+
+// Safety: See Counts
+unsafe impl Send for Groups {}
+
+/// The occurrences of one key
+pub struct Group<'a> {
+    occurrences: &'a [(usize, Key)],
+}
+
+impl Groups {
+    pub fn with_capacity(capacity: usize) -> Groups {
+        Groups {
+            counts: Counts::with_capacity(capacity),
+            ..Groups::default()
+        }
+    }
+
+    /// Add an occurrence of the record identified by the given key
+    pub fn add(&mut self, key: &[u8], record: &[u8]) {
+        let group = self.counts.add(key);
+        self.occurrences.push((group, self.arena.alloc(record)));
+    }
+
+    /// The groups in the order their keys were first seen
+    pub fn iter(&mut self) -> impl Iterator<Item = Group<'_>> {
+        // Stable, so the occurrences within each group stay in input order
+        self.occurrences.sort_by_key(|&(group, _)| group);
+        self.occurrences
+            .chunk_by(|a, b| a.0 == b.0)
+            .map(|occurrences| Group { occurrences })
+    }
+}
+
+impl<'a> Group<'a> {
+    pub fn len(&self) -> u64 {
+        self.occurrences.len() as u64
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &'a [u8]> {
+        self.occurrences.iter().map(|(_, record)| record.as_slice())
+    }
+}
@@ -0,0 +1,121 @@
+//! Expanding the inputs given into the files to read: the files below
+//! directories when reading recursively, and the files matching glob
+//! patterns not expanded by the shell, like `'logs/*.log'`.
+
+use anyhow::{anyhow, Context, Result};
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// The order of the files a directory or pattern expands to
+#[derive(Clone, Copy)]
+pub enum Order {
+    /// Sorted by path
+    Name,
+    /// Least recently modified first
+    Modified,
+    /// As listed by the file system
+    Unsorted,
+}
+
+impl Order {
+    pub fn from_name(name: &str) -> Option<Order> {
+        match name {
+            "name" => Some(Order::Name),
+            "mtime" => Some(Order::Modified),
+            "none" => Some(Order::Unsorted),
+            _ => None,
+        }
+    }
+}
+
+/// Replace patterns by the files matching them and, if recursive,
+/// directories by the files below them; other inputs are kept as is
+pub fn expand(inputs: Vec<OsString>, recursive: bool, order: Order) -> Result<Vec<OsString>> {
+    let mut files = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let start = files.len();
+        let expanded = match is_pattern(&input) {
+            true => glob(&input, order)?,
+            false => vec![input],
+        };
+        for path in expanded {
+            match recursive && path != "-" && Path::new(&path).is_dir() {
+                true => walk(Path::new(&path), &mut files)?,
+                false => files.push(path),
+            }
+        }
+        sort(&mut files[start..], order);
+    }
+    Ok(files)
+}
+
+/// Whether the input is a pattern instead of the name of a file; a
+/// file existing by that name is read, like the shell does
+fn is_pattern(input: &OsStr) -> bool {
+    let wildcard = input
+        .as_bytes()
+        .iter()
+        .any(|b| matches!(b, b'*' | b'?' | b'['));
+    wildcard && fs::symlink_metadata(input).is_err()
+}
+
+/// The paths matching the pattern, using glob(3)
+fn glob(pattern: &OsStr, order: Order) -> Result<Vec<OsString>> {
+    let c_pattern = CString::new(pattern.as_bytes())?;
+    let flags = match order {
+        Order::Name => 0,
+        _ => libc::GLOB_NOSORT,
+    };
+    // Safety: glob_t is a plain C struct, for which zero is a valid
+    // value; glob fills it in and globfree releases what it allocated.
+    let mut matches: libc::glob_t = unsafe { mem::zeroed() };
+    let result = unsafe { libc::glob(c_pattern.as_ptr(), flags, None, &mut matches) };
+    let paths = match result {
+        0 => (0..matches.gl_pathc)
+            .map(|idx| {
+                let path = unsafe { CStr::from_ptr(*matches.gl_pathv.add(idx)) };
+                OsStr::from_bytes(path.to_bytes()).to_os_string()
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    unsafe { libc::globfree(&mut matches) };
+    match result {
+        0 => Ok(paths),
+        libc::GLOB_NOMATCH => Err(anyhow!("no files match {}", pattern.to_string_lossy())),
+        _ => Err(anyhow!("could not expand {}", pattern.to_string_lossy())),
+    }
+}
+
+/// Add the files below the directory, without following links to
+/// directories, so links can not lead into a loop
+fn walk(dir: &Path, files: &mut Vec<OsString>) -> Result<()> {
+    let entries = fs::read_dir(dir).with_context(|| format!("could not read {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("could not read {}", dir.display()))?;
+        match entry.file_type()?.is_dir() {
+            true => walk(&entry.path(), files)?,
+            false if entry.path().is_dir() => {}
+            false => files.push(entry.path().into_os_string()),
+        }
+    }
+    Ok(())
+}
+
+fn sort(files: &mut [OsString], order: Order) {
+    match order {
+        Order::Name => files.sort(),
+        // Stable, so files modified at once stay in order; files
+        // that can't be read are left to fail when opened
+        Order::Modified => files.sort_by_cached_key(|path| {
+            fs::metadata(path)
+                .and_then(|meta| meta.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+        }),
+        Order::Unsorted => {}
+    }
+}
@@ -0,0 +1,321 @@
+//! How the inputs are split into records.
+
+use bstr::{io::BufReadExt, ByteSlice};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Read};
+use std::slice;
+
+/// The way records are delimited
+pub enum Framing {
+    /// Records end with the given terminator
+    Terminated(Vec<u8>),
+    /// Records end with `\n`; a `\r` before it is not part of the record
+    Crlf,
+    /// CSV rows, which end with `\n` or `\r\n` outside of quotes
+    Csv,
+    /// Records are blocks of lines separated by one or more blank lines
+    Paragraph,
+    /// Records of the given number of bytes, without any terminator
+    Fixed(usize),
+    /// Records preceded by their length
+    Prefixed(Prefix),
+}
+
+/// Encodings of the length in front of length-prefixed records
+#[derive(Clone, Copy)]
+pub enum Prefix {
+    /// LEB128, as used by protobuf
+    Varint,
+    /// Four bytes, little endian
+    U32Le,
+}
+
+impl Prefix {
+    pub fn from_name(name: &str) -> Option<Prefix> {
+        match name {
+            "varint" => Some(Prefix::Varint),
+            "u32le" => Some(Prefix::U32Le),
+            _ => None,
+        }
+    }
+
+    /// Decode the length at the start of `data`, returning the length
+    /// of the prefix and the encoded length, if the prefix is complete
+    fn decode(self, data: &[u8]) -> Option<(usize, u64)> {
+        match self {
+            Prefix::Varint => {
+                let mut len = 0u64;
+                for (idx, &b) in data.iter().enumerate().take(10) {
+                    len |= u64::from(b & 0x7f) << (7 * idx);
+                    if b & 0x80 == 0 {
+                        return Some((idx + 1, len));
+                    }
+                }
+                None
+            }
+            Prefix::U32Le => {
+                let prefix = data.get(..4)?;
+                let len = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]);
+                Some((4, u64::from(len)))
+            }
+        }
+    }
+
+    /// The longest possible prefix
+    fn max_len(self) -> usize {
+        match self {
+            Prefix::Varint => 10,
+            Prefix::U32Le => 4,
+        }
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "truncated length-prefixed record",
+    )
+}
+
+impl Framing {
+    /// The terminator written after records
+    pub fn terminator(&self) -> &[u8] {
+        match self {
+            Framing::Terminated(t) => t,
+            Framing::Crlf => b"\r\n",
+            Framing::Csv => b"\n",
+            Framing::Paragraph => b"\n\n",
+            Framing::Fixed(_) | Framing::Prefixed(_) => b"",
+        }
+    }
+
+    /// The record without its terminator, which identifies it
+    pub fn key<'a>(&self, record: &'a [u8]) -> &'a [u8] {
+        match self {
+            Framing::Terminated(t) => record.strip_suffix(&t[..]).unwrap_or(record),
+            Framing::Crlf | Framing::Csv => match record.strip_suffix(b"\n") {
+                Some(key) => key.strip_suffix(b"\r").unwrap_or(key),
+                None => record,
+            },
+            Framing::Paragraph => {
+                let key = &record[newlines(record)..];
+                let end = key
+                    .iter()
+                    .rposition(|&b| b != b'\n')
+                    .map_or(0, |idx| idx + 1);
+                &key[..end]
+            }
+            Framing::Fixed(_) => record,
+            Framing::Prefixed(prefix) => match prefix.decode(record) {
+                Some((start, _)) => &record[start..],
+                None => record,
+            },
+        }
+    }
+
+    /// What needs to be appended to the record to terminate it
+    pub fn missing(&self, record: &[u8]) -> &[u8] {
+        let terminated = match self {
+            Framing::Terminated(t) => record.ends_with(t),
+            Framing::Crlf | Framing::Csv => record.ends_with(b"\n"),
+            Framing::Paragraph => {
+                let newlines = record.iter().rev().take_while(|&&b| b == b'\n').count();
+                return &b"\n\n"[newlines.min(2)..];
+            }
+            Framing::Fixed(_) | Framing::Prefixed(_) => true,
+        };
+        match terminated {
+            true => &[],
+            false => self.terminator(),
+        }
+    }
+
+    /// The length of the first record in `data` including its terminator.
+    ///
+    /// Returns `None` if `data` does not contain a whole record; unless at
+    /// the end of the input, where any remaining data is the last record.
+    pub fn record_end(&self, data: &[u8], eof: bool) -> Option<usize> {
+        let end = match self {
+            Framing::Terminated(t) => find(data, t).map(|idx| idx + t.len()),
+            Framing::Crlf => data.find_byte(b'\n').map(|idx| idx + 1),
+            Framing::Csv => {
+                let mut quoted = false;
+                data.iter()
+                    .position(|&b| {
+                        quoted ^= b == b'"';
+                        b == b'\n' && !quoted
+                    })
+                    .map(|idx| idx + 1)
+            }
+            Framing::Paragraph => {
+                let start = newlines(data);
+                match data[start..].find(b"\n\n") {
+                    Some(idx) => {
+                        let sep = start + idx;
+                        let end = sep + newlines(&data[sep..]);
+                        // More blank lines may follow
+                        Some(end).filter(|&end| end < data.len() || eof)
+                    }
+                    None => None,
+                }
+            }
+            Framing::Fixed(n) => Some(*n).filter(|&n| n <= data.len()),
+            // Truncated records are an error, rather than a record of their own
+            Framing::Prefixed(prefix) => {
+                let (start, len) = prefix.decode(data)?;
+                let end = usize::try_from(len).ok()?.checked_add(start)?;
+                return Some(end).filter(|&end| end <= data.len());
+            }
+        };
+        match end {
+            None if eof && !data.is_empty() => Some(data.len()),
+            end => end,
+        }
+    }
+
+    /// Invoke `f` for every record read from `r`, including its
+    /// terminator if present, until `f` returns false.
+    pub fn for_each_record<R, F>(&self, mut r: R, mut f: F) -> io::Result<()>
+    where
+        R: BufRead,
+        F: FnMut(&[u8]) -> io::Result<bool>,
+    {
+        let mut record = Vec::new();
+        let mut open = true;
+        match self {
+            Framing::Terminated(t) if t.len() == 1 => {
+                return r.for_byte_record_with_terminator(t[0], f)
+            }
+            Framing::Crlf => return r.for_byte_record_with_terminator(b'\n', f),
+            Framing::Terminated(t) => {
+                // Split on the last byte and join the parts until
+                // the record ends with the whole terminator
+                r.for_byte_record_with_terminator(*t.last().unwrap(), |part| {
+                    record.extend_from_slice(part);
+                    if !record.ends_with(t) {
+                        return Ok(true);
+                    }
+                    open = f(&record)?;
+                    record.clear();
+                    Ok(open)
+                })?;
+            }
+            Framing::Csv => {
+                // Join the lines while a quoted field is open, which is
+                // while an odd number of quotes was read; escaped
+                // quotes come in pairs
+                let mut quoted = false;
+                r.for_byte_record_with_terminator(b'\n', |line| {
+                    quoted ^= line.iter().filter(|&&b| b == b'"').count() % 2 == 1;
+                    if quoted || !record.is_empty() {
+                        record.extend_from_slice(line);
+                    }
+                    if quoted {
+                        return Ok(true);
+                    }
+                    open = match record.is_empty() {
+                        true => f(line)?,
+                        false => f(&record)?,
+                    };
+                    record.clear();
+                    Ok(open)
+                })?;
+            }
+            Framing::Paragraph => {
+                // A line with content after a blank line starts the next record
+                let mut content = false;
+                r.for_byte_record_with_terminator(b'\n', |line| {
+                    if line != b"\n" {
+                        if content && record.ends_with(b"\n\n") {
+                            open = f(&record)?;
+                            record.clear();
+                        }
+                        content = true;
+                    }
+                    record.extend_from_slice(line);
+                    Ok(open)
+                })?;
+            }
+            &Framing::Fixed(n) => {
+                while open {
+                    let avail = r.fill_buf()?;
+                    if avail.is_empty() {
+                        break;
+                    }
+                    let mut used = 0;
+                    while open && used < avail.len() {
+                        // Whole records are passed on without copying them
+                        if record.is_empty() && avail.len() - used >= n {
+                            open = f(&avail[used..used + n])?;
+                            used += n;
+                            continue;
+                        }
+                        let take = (n - record.len()).min(avail.len() - used);
+                        record.extend_from_slice(&avail[used..used + take]);
+                        used += take;
+                        if record.len() == n {
+                            open = f(&record)?;
+                            record.clear();
+                        }
+                    }
+                    r.consume(used);
+                }
+            }
+            &Framing::Prefixed(prefix) => {
+                while open {
+                    let (start, len) = loop {
+                        if let Some(decoded) = prefix.decode(&record) {
+                            break decoded;
+                        }
+                        let mut byte = 0;
+                        match r.read(slice::from_mut(&mut byte))? {
+                            0 if record.is_empty() => return Ok(()),
+                            0 => return Err(truncated()),
+                            _ if record.len() == prefix.max_len() => return Err(truncated()),
+                            _ => record.push(byte),
+                        }
+                    };
+                    (&mut r).take(len).read_to_end(&mut record)?;
+                    if record.len() as u64 != start as u64 + len {
+                        return Err(truncated());
+                    }
+                    open = f(&record)?;
+                    record.clear();
+                }
+            }
+        }
+        if open && !record.is_empty() {
+            f(&record)?;
+        }
+        Ok(())
+    }
+
+    /// Invoke `f` for every record in `data`, including its
+    /// terminator if present, until `f` returns false.
+    pub fn for_each_record_in<F>(&self, data: &[u8], mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> io::Result<bool>,
+    {
+        let mut rest = data;
+        while !rest.is_empty() {
+            let end = self.record_end(rest, true).ok_or_else(truncated)?;
+            if !f(&rest[..end])? {
+                break;
+            }
+            rest = &rest[end..];
+        }
+        Ok(())
+    }
+}
+
+/// The number of newlines at the start of `data`
+fn newlines(data: &[u8]) -> usize {
+    data.iter().position(|&b| b != b'\n').unwrap_or(data.len())
+}
+
+fn find(data: &[u8], terminator: &[u8]) -> Option<usize> {
+    match *terminator {
+        [byte] => data.find_byte(byte),
+        _ => data.find(terminator),
+    }
+}
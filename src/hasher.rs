@@ -0,0 +1,213 @@
+//! The hash functions records are compared by.
+//!
+//! All are seeded explicitly, so the hashes of one run can be saved
+//! and compared with those of the next; see [crate::state].
+
+use ahash::{AHasher, RandomState as ARandomState};
+use std::convert::TryInto;
+use std::fs::File;
+#[allow(deprecated)] // the only SipHash in std that takes keys
+use std::hash::SipHasher;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read};
+
+/// The hash functions to choose from
+#[derive(Clone, Copy)]
+pub enum Algorithm {
+    /// aHash, the fastest
+    Ahash,
+    /// SipHash-2-4, a keyed pseudorandom function, so collisions can't
+    /// be provoked without knowing the seeds; slower
+    Siphash,
+    /// wyhash (final version 4), about as fast as aHash and the same on
+    /// every platform of the same byte order
+    Wyhash,
+    // There is no XXH3: it needs a 192 byte secret and separate code
+    // paths for every range of lengths, which wyhash and aHash are as
+    // fast as without
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match name {
+            "ahash" => Some(Algorithm::Ahash),
+            "siphash" => Some(Algorithm::Siphash),
+            "wyhash" => Some(Algorithm::Wyhash),
+            _ => None,
+        }
+    }
+
+    /// The hash function with the given seeds
+    pub fn hasher(self, seeds: [u64; 4]) -> RecordHasher {
+        match self {
+            Algorithm::Ahash => RecordHasher::Ahash(ARandomState::with_seeds(
+                seeds[0], seeds[1], seeds[2], seeds[3],
+            )),
+            // Just two keys; the others are ignored
+            Algorithm::Siphash => RecordHasher::Siphash(seeds[0], seeds[1]),
+            // Just one seed and the default secret
+            Algorithm::Wyhash => RecordHasher::Wyhash(seeds[0]),
+        }
+    }
+}
+
+/// Seeds for a second hash function, derived from those of the first
+/// so the hashes of one record by both are unrelated; mixed with fixed
+/// keys, so the same seeds give the same others in every run
+pub fn other_seeds(seeds: [u64; 4]) -> [u64; 4] {
+    let mix = ARandomState::with_seeds(
+        0xb7e1_5162_8aed_2a6a,
+        0xbf71_5880_9cf4_f3c7,
+        0x62e7_160f_38b4_da56,
+        0xa784_d904_5190_cfef,
+    );
+    [0u64, 1, 2, 3].map(|i| mix.hash_one((seeds, i)))
+}
+
+/// Seeds from the random number generator of the operating system;
+/// there is no fallback to anything predictable
+pub fn secure_seeds() -> io::Result<[u64; 4]> {
+    let mut bytes = [0; 32];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok([0, 1, 2, 3].map(|i| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap())))
+}
+
+/// A seeded hash function
+pub enum RecordHasher {
+    Ahash(ARandomState),
+    Siphash(u64, u64),
+    Wyhash(u64),
+}
+
+impl BuildHasher for RecordHasher {
+    type Hasher = RecordHashState;
+
+    #[allow(deprecated)]
+    fn build_hasher(&self) -> RecordHashState {
+        match self {
+            RecordHasher::Ahash(state) => RecordHashState::Ahash(state.build_hasher()),
+            RecordHasher::Siphash(k0, k1) => {
+                RecordHashState::Siphash(SipHasher::new_with_keys(*k0, *k1))
+            }
+            RecordHasher::Wyhash(seed) => RecordHashState::Wyhash(WyHasher(*seed)),
+        }
+    }
+}
+
+pub enum RecordHashState {
+    Ahash(AHasher),
+    #[allow(deprecated)]
+    Siphash(SipHasher),
+    Wyhash(WyHasher),
+}
+
+impl Hasher for RecordHashState {
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            RecordHashState::Ahash(hasher) => hasher.write(bytes),
+            RecordHashState::Siphash(hasher) => hasher.write(bytes),
+            RecordHashState::Wyhash(hasher) => hasher.write(bytes),
+        }
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        match self {
+            RecordHashState::Ahash(hasher) => hasher.write_usize(n),
+            RecordHashState::Siphash(hasher) => hasher.write_usize(n),
+            RecordHashState::Wyhash(hasher) => hasher.write_usize(n),
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        match self {
+            RecordHashState::Ahash(hasher) => hasher.finish(),
+            RecordHashState::Siphash(hasher) => hasher.finish(),
+            RecordHashState::Wyhash(hasher) => hasher.finish(),
+        }
+    }
+}
+
+/// wyhash of everything written, each write hashed with the hash of
+/// those before as its seed
+pub struct WyHasher(u64);
+
+impl Hasher for WyHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0 = wyhash(bytes, self.0);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The default secret of wyhash
+const WY_SECRET: [u64; 4] = [
+    0x2d35_8dcc_aa6c_78a5,
+    0x8bb8_4b93_962e_acc9,
+    0x4b33_a62e_d433_d4a3,
+    0x4d5a_2da5_1de1_aa47,
+];
+
+/// The 128 bit product of both, as its lower and upper half
+fn wymum(a: u64, b: u64) -> (u64, u64) {
+    let r = u128::from(a) * u128::from(b);
+    (r as u64, (r >> 64) as u64)
+}
+
+fn wymix(a: u64, b: u64) -> u64 {
+    let (a, b) = wymum(a, b);
+    a ^ b
+}
+
+fn read8(p: &[u8]) -> u64 {
+    u64::from_le_bytes(p[..8].try_into().unwrap())
+}
+
+fn read4(p: &[u8]) -> u64 {
+    u64::from(u32::from_le_bytes(p[..4].try_into().unwrap()))
+}
+
+/// wyhash final version 4, like `wyhash(bytes, len, seed, _wyp)` in
+/// the reference implementation
+fn wyhash(bytes: &[u8], seed: u64) -> u64 {
+    let s = WY_SECRET;
+    let len = bytes.len();
+    let mut seed = seed ^ wymix(seed ^ s[0], s[1]);
+    let (a, b) = match len {
+        0 => (0, 0),
+        1..=3 => {
+            let a = u64::from(bytes[0]) << 16
+                | u64::from(bytes[len >> 1]) << 8
+                | u64::from(bytes[len - 1]);
+            (a, 0)
+        }
+        4..=16 => {
+            let q = (len >> 3) << 2;
+            let a = read4(bytes) << 32 | read4(&bytes[q..]);
+            let b = read4(&bytes[len - 4..]) << 32 | read4(&bytes[len - 4 - q..]);
+            (a, b)
+        }
+        _ => {
+            let mut p = bytes;
+            if p.len() >= 48 {
+                let (mut see1, mut see2) = (seed, seed);
+                while p.len() >= 48 {
+                    seed = wymix(read8(p) ^ s[1], read8(&p[8..]) ^ seed);
+                    see1 = wymix(read8(&p[16..]) ^ s[2], read8(&p[24..]) ^ see1);
+                    see2 = wymix(read8(&p[32..]) ^ s[3], read8(&p[40..]) ^ see2);
+                    p = &p[48..];
+                }
+                seed ^= see1 ^ see2;
+            }
+            while p.len() > 16 {
+                seed = wymix(read8(p) ^ s[1], read8(&p[8..]) ^ seed);
+                p = &p[16..];
+            }
+            // The last 16 bytes, overlapping those before if need be
+            (read8(&bytes[len - 16..]), read8(&bytes[len - 8..]))
+        }
+    };
+    let (a, b) = wymum(a ^ s[1], b ^ seed);
+    wymix(a ^ s[0] ^ len as u64, b ^ s[1])
+}
@@ -0,0 +1,414 @@
+//! Opening of the inputs records are read from.
+
+use crate::compress::{Compression, MAGIC_LEN};
+use crate::framing::Framing;
+use crate::stats;
+use anyhow::{Context, Result};
+use bstr::ByteSlice;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, stdin, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::mem;
+use std::path::Path;
+use std::process::{Child, ChildStdout, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// How inputs are opened
+#[derive(Clone, Copy)]
+pub struct InputOpts {
+    /// Never reach the end of the input; see [Follow]
+    pub follow: bool,
+    /// Check stdin for compression, too
+    pub decompress_stdin: bool,
+    /// Memory-map regular files instead of reading them
+    pub mmap: bool,
+    /// Read uncompressed inputs with [crate::uring::UringReader]
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub io_uring: bool,
+}
+
+/// An opened input
+pub enum Input {
+    Reader(Box<dyn BufRead>),
+    #[cfg(unix)]
+    Mapped(Mmap),
+}
+
+impl Input {
+    /// Invoke `f` for every record in the input, including its
+    /// terminator if present, until `f` returns false.
+    pub fn for_each_record<F>(self, framing: &Framing, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> io::Result<bool>,
+    {
+        let mut bytes = 0;
+        let result = self.records(framing, |record| {
+            bytes += record.len() as u64;
+            f(record)
+        });
+        stats::add_bytes_read(bytes);
+        result
+    }
+
+    /// Like [Input::for_each_record], without counting the bytes read
+    fn records<F>(self, framing: &Framing, f: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> io::Result<bool>,
+    {
+        match self {
+            Input::Reader(r) => framing.for_each_record(r, f),
+            #[cfg(unix)]
+            Input::Mapped(map) => framing.for_each_record_in(map.as_slice(), f),
+        }
+    }
+
+    /// Invoke `f` for consecutive chunks of the input of roughly the
+    /// given size, until `f` returns false. Chunks only ever end after
+    /// a record, so no record is split between two chunks.
+    pub fn for_each_chunk<F>(self, framing: &Framing, size: usize, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(Vec<u8>) -> bool,
+    {
+        let mut bytes = 0;
+        let result = self.chunks(framing, size, |chunk| {
+            bytes += chunk.len() as u64;
+            f(chunk)
+        });
+        stats::add_bytes_read(bytes);
+        result
+    }
+
+    /// Like [Input::for_each_chunk], without counting the bytes read
+    fn chunks<F>(self, framing: &Framing, size: usize, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(Vec<u8>) -> bool,
+    {
+        let terminator = match framing {
+            Framing::Terminated(t) if t.len() == 1 => t[0],
+            Framing::Crlf => b'\n',
+            &Framing::Fixed(n) => return self.for_each_fixed_chunk((size / n).max(1) * n, f),
+            _ => return self.for_each_chunk_of_records(framing, size, f),
+        };
+        match self {
+            Input::Reader(mut r) => {
+                let mut chunk = Vec::with_capacity(size);
+                loop {
+                    let avail = r.fill_buf()?;
+                    if avail.is_empty() {
+                        break;
+                    }
+                    let len = avail.len();
+                    chunk.extend_from_slice(avail);
+                    r.consume(len);
+
+                    if chunk.len() < size {
+                        continue;
+                    }
+                    if let Some(idx) = chunk.rfind_byte(terminator) {
+                        let rest = chunk[idx + 1..].to_vec();
+                        chunk.truncate(idx + 1);
+                        if !f(mem::replace(&mut chunk, rest)) {
+                            return Ok(());
+                        }
+                        chunk.reserve(size);
+                    }
+                }
+                if !chunk.is_empty() {
+                    f(chunk);
+                }
+                Ok(())
+            }
+            #[cfg(unix)]
+            Input::Mapped(map) => {
+                let mut rest = map.as_slice();
+                while !rest.is_empty() {
+                    let end = match rest.get(size..).and_then(|r| r.find_byte(terminator)) {
+                        Some(idx) => size + idx + 1,
+                        None => rest.len(),
+                    };
+                    if !f(rest[..end].to_vec()) {
+                        break;
+                    }
+                    rest = &rest[end..];
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Like [Input::for_each_chunk], for records of a fixed size that
+    /// evenly divides the size of the chunks
+    fn for_each_fixed_chunk<F>(self, size: usize, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(Vec<u8>) -> bool,
+    {
+        match self {
+            Input::Reader(mut r) => loop {
+                let mut chunk = Vec::with_capacity(size);
+                (&mut r).take(size as u64).read_to_end(&mut chunk)?;
+                if chunk.is_empty() || !f(chunk) {
+                    return Ok(());
+                }
+            },
+            #[cfg(unix)]
+            Input::Mapped(map) => {
+                for chunk in map.as_slice().chunks(size) {
+                    if !f(chunk.to_vec()) {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Like [Input::for_each_chunk], but collects the chunks record by
+    /// record, for framings where the end of a record can not be found
+    /// by looking for a single byte from the end of the chunk
+    fn for_each_chunk_of_records<F>(
+        self,
+        framing: &Framing,
+        size: usize,
+        mut f: F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(Vec<u8>) -> bool,
+    {
+        let mut chunk = Vec::with_capacity(size);
+        self.records(framing, |record| {
+            chunk.extend_from_slice(record);
+            if chunk.len() < size {
+                return Ok(true);
+            }
+            Ok(f(mem::replace(&mut chunk, Vec::with_capacity(size))))
+        })?;
+        if !chunk.is_empty() {
+            f(chunk);
+        }
+        Ok(())
+    }
+}
+
+/// Open the given input for reading; `-` designates stdin.
+///
+/// Compressed files are detected by their magic bytes and decompressed
+/// transparently; stdin is only checked for compression if requested.
+/// Followed inputs are never decompressed or memory-mapped.
+pub fn open_input(path: &OsStr, opts: InputOpts) -> Result<Input> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if opts.io_uring && !opts.follow {
+        return open_uring(path);
+    }
+
+    if path == "-" {
+        return Ok(Input::Reader(match (opts.follow, opts.decompress_stdin) {
+            (true, _) => Box::new(BufReader::new(Follow(stdin().lock()))),
+            (false, true) => open_stdin_decompressed()?,
+            (false, false) => Box::new(stdin().lock()),
+        }));
+    }
+
+    let display = Path::new(path).display();
+    let mut file = File::open(path).with_context(|| format!("could not open {}", display))?;
+    if opts.follow {
+        return Ok(Input::Reader(Box::new(BufReader::new(Follow(file)))));
+    }
+
+    let mut head = [0u8; MAGIC_LEN];
+    let len =
+        read_head(&mut file, &mut head).with_context(|| format!("could not read {}", display))?;
+    file.seek(SeekFrom::Start(0))?;
+    match Compression::detect(&head[..len]) {
+        #[cfg(unix)]
+        None if opts.mmap => match Mmap::map(&file)? {
+            Some(map) => Ok(Input::Mapped(map)),
+            None => Ok(Input::Reader(Box::new(BufReader::new(file)))),
+        },
+        None => Ok(Input::Reader(Box::new(BufReader::new(file)))),
+        Some(compression) => {
+            let reader = Decompressor::spawn(compression, Stdio::from(file))
+                .with_context(|| format!("could not decompress {}", display))?;
+            Ok(Input::Reader(Box::new(BufReader::new(reader))))
+        }
+    }
+}
+
+/// A read-only memory mapping of an entire file
+#[cfg(unix)]
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+#[cfg(unix)]
+impl Mmap {
+    /// Map the given file; returns None if the file is not a regular,
+    /// non-empty file and thus can not (or need not) be mapped.
+    fn map(file: &File) -> io::Result<Option<Mmap>> {
+        use std::convert::TryFrom;
+        use std::os::unix::io::AsRawFd;
+
+        let meta = file.metadata()?;
+        let len = match usize::try_from(meta.len()) {
+            Ok(len) if meta.is_file() && len > 0 => len,
+            _ => return Ok(None),
+        };
+
+        // Safety: We map the file read only; the mapping is only ever
+        // exposed as an immutable slice tied to the lifetime of Mmap.
+        // Like every program using mmap, we can not protect against the
+        // file being truncated by another process while we read it.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Ok(None);
+        }
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Some(Mmap { ptr, len }))
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// Open the given input for reading through io_uring, decompressing
+/// it if necessary
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn open_uring(path: &OsStr) -> Result<Input> {
+    use crate::uring::UringReader;
+
+    if path == "-" {
+        let reader = UringReader::new(None).context("could not set up io_uring")?;
+        return Ok(Input::Reader(Box::new(reader)));
+    }
+
+    let display = Path::new(path).display();
+    let mut file = File::open(path).with_context(|| format!("could not open {}", display))?;
+    let mut head = [0u8; MAGIC_LEN];
+    let len =
+        read_head(&mut file, &mut head).with_context(|| format!("could not read {}", display))?;
+    file.seek(SeekFrom::Start(0))?;
+    match Compression::detect(&head[..len]) {
+        None => {
+            let reader = UringReader::new(Some(file)).context("could not set up io_uring")?;
+            Ok(Input::Reader(Box::new(reader)))
+        }
+        Some(compression) => {
+            let reader = Decompressor::spawn(compression, Stdio::from(file))
+                .with_context(|| format!("could not decompress {}", display))?;
+            Ok(Input::Reader(Box::new(BufReader::new(reader))))
+        }
+    }
+}
+
+/// Open stdin, decompressing it if it starts with known magic bytes
+fn open_stdin_decompressed() -> Result<Box<dyn BufRead>> {
+    let compression = Compression::detect(stdin().lock().fill_buf()?);
+    let compression = match compression {
+        None => return Ok(Box::new(stdin().lock())),
+        Some(c) => c,
+    };
+
+    let mut reader =
+        Decompressor::spawn(compression, Stdio::piped()).context("could not decompress stdin")?;
+    let mut feed = reader.child.stdin.take().unwrap();
+    // Whatever was peeked at is still in the stdin buffer, so just
+    // copying stdin to the decompressor forwards the complete input.
+    // Errors surface through the exit status of the decompressor.
+    thread::spawn(move || io::copy(&mut stdin().lock(), &mut feed));
+    Ok(Box::new(BufReader::new(reader)))
+}
+
+/// Read as much of the beginning of a file as fits into buf
+fn read_head(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Reads the output of a decompression tool running as a child process
+struct Decompressor {
+    child: Child,
+    stdout: ChildStdout,
+    tool: &'static str,
+}
+
+impl Decompressor {
+    fn spawn(compression: Compression, input: Stdio) -> Result<Decompressor> {
+        let mut child = compression.spawn_decompressor(input)?;
+        let stdout = child.stdout.take().unwrap();
+        Ok(Decompressor {
+            child,
+            stdout,
+            tool: compression.tool(),
+        })
+    }
+}
+
+impl Read for Decompressor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.stdout.read(buf)?;
+        if len == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "{} failed: {}",
+                    self.tool, status
+                )));
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl Drop for Decompressor {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// How long to wait before checking for new data when following an input
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reader that never reaches the end of its input: Once all data has
+/// been read, it waits for more data to be appended, like `tail -f`.
+struct Follow<R>(R);
+
+impl<R: Read> Read for Follow<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match self.0.read(buf)? {
+                0 => thread::sleep(FOLLOW_INTERVAL),
+                n => return Ok(n),
+            }
+        }
+    }
+}
@@ -0,0 +1,361 @@
+//! Canonical forms of JSON documents, so documents differing just in
+//! formatting compare equal, and of numbers.
+//!
+//! The canonical form has no whitespace, the members of objects sorted
+//! by name, of which only the last of each name is kept, strings
+//! escaped only where necessary and numbers written in the shortest
+//! way, so `1.0`, `1` and `10e-1` are the same. Numbers are compared
+//! by their decimal value, not rounded to any precision.
+
+use crate::template::{write_json, NonUtf8};
+use std::fmt::Write as _;
+
+/// Objects and arrays nested deeper than this are rejected, so parsing
+/// can't overflow the stack
+const MAX_DEPTH: usize = 128;
+
+/// The canonical form of the document; none if it isn't valid JSON
+pub fn canonical(text: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(text).ok()?;
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        pos: 0,
+        depth: 0,
+    };
+    let mut out = Vec::with_capacity(text.len());
+    parser.value(&mut out)?;
+    parser.skip_space();
+    match parser.pos == text.len() {
+        true => Some(out),
+        false => None,
+    }
+}
+
+struct Parser<'a> {
+    /// Valid UTF-8
+    text: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn skip_space(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skip whitespace, then the byte if it comes next
+    fn eat(&mut self, b: u8) -> bool {
+        self.skip_space();
+        let found = self.peek() == Some(b);
+        self.pos += found as usize;
+        found
+    }
+
+    fn literal(&mut self, literal: &[u8]) -> Option<()> {
+        let found = self.text[self.pos..].starts_with(literal);
+        self.pos += literal.len();
+        found.then_some(())
+    }
+
+    fn value(&mut self, out: &mut Vec<u8>) -> Option<()> {
+        self.skip_space();
+        match self.peek()? {
+            b'{' => self.nested(|parser| parser.object(out)),
+            b'[' => self.nested(|parser| parser.array(out)),
+            b'"' => {
+                let s = self.string()?;
+                write_json(out, s.as_bytes(), NonUtf8::Lossy).ok()
+            }
+            b't' => self
+                .literal(b"true")
+                .map(|_| out.extend_from_slice(b"true")),
+            b'f' => self
+                .literal(b"false")
+                .map(|_| out.extend_from_slice(b"false")),
+            b'n' => self
+                .literal(b"null")
+                .map(|_| out.extend_from_slice(b"null")),
+            _ => self.number(out),
+        }
+    }
+
+    fn nested(&mut self, f: impl FnOnce(&mut Self) -> Option<()>) -> Option<()> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return None;
+        }
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn object(&mut self, out: &mut Vec<u8>) -> Option<()> {
+        self.pos += 1;
+        let mut members = Vec::new();
+        if !self.eat(b'}') {
+            loop {
+                self.skip_space();
+                if self.peek() != Some(b'"') {
+                    return None;
+                }
+                let name = self.string()?;
+                if !self.eat(b':') {
+                    return None;
+                }
+                let mut value = Vec::new();
+                self.value(&mut value)?;
+                members.push((name, value));
+                if self.eat(b'}') {
+                    break;
+                }
+                if !self.eat(b',') {
+                    return None;
+                }
+            }
+        }
+        // Stable, so the last member of each name comes last
+        members.sort_by(|(a, _), (b, _)| a.cmp(b));
+        out.push(b'{');
+        let mut first = true;
+        for (idx, (name, value)) in members.iter().enumerate() {
+            if members.get(idx + 1).is_some_and(|(next, _)| next == name) {
+                continue;
+            }
+            if !first {
+                out.push(b',');
+            }
+            first = false;
+            write_json(out, name.as_bytes(), NonUtf8::Lossy).ok()?;
+            out.push(b':');
+            out.extend_from_slice(value);
+        }
+        out.push(b'}');
+        Some(())
+    }
+
+    fn array(&mut self, out: &mut Vec<u8>) -> Option<()> {
+        self.pos += 1;
+        out.push(b'[');
+        if !self.eat(b']') {
+            loop {
+                self.value(out)?;
+                if self.eat(b']') {
+                    break;
+                }
+                if !self.eat(b',') {
+                    return None;
+                }
+                out.push(b',');
+            }
+        }
+        out.push(b']');
+        Some(())
+    }
+
+    /// The string starting at the opening quote, unescaped; unpaired
+    /// surrogates are replaced
+    fn string(&mut self) -> Option<String> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let start = self.pos;
+            while !matches!(self.peek()?, b'"' | b'\\' | 0..=0x1f) {
+                self.pos += 1;
+            }
+            // Only stops at ASCII, so this is at a character boundary
+            s.push_str(std::str::from_utf8(&self.text[start..self.pos]).unwrap());
+            let b = self.peek()?;
+            self.pos += 1;
+            match b {
+                b'"' => return Some(s),
+                b'\\' => {}
+                _ => return None,
+            }
+            let escaped = match self.peek()? {
+                b'"' => '"',
+                b'\\' => '\\',
+                b'/' => '/',
+                b'b' => '\u{8}',
+                b'f' => '\u{c}',
+                b'n' => '\n',
+                b'r' => '\r',
+                b't' => '\t',
+                b'u' => {
+                    self.pos += 1;
+                    let unit = self.hex()?;
+                    let pair = (0xd800..0xdc00).contains(&unit)
+                        && self.text[self.pos..].starts_with(b"\\u");
+                    if pair {
+                        let pos = self.pos;
+                        self.pos += 2;
+                        let low = self.hex()?;
+                        if (0xdc00..0xe000).contains(&low) {
+                            let c = 0x10000 + ((unit - 0xd800) << 10) + (low - 0xdc00);
+                            s.push(char::from_u32(c).unwrap());
+                            continue;
+                        }
+                        self.pos = pos;
+                    }
+                    s.push(char::from_u32(unit).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    continue;
+                }
+                _ => return None,
+            };
+            self.pos += 1;
+            s.push(escaped);
+        }
+    }
+
+    /// Four hex digits
+    fn hex(&mut self) -> Option<u32> {
+        let digits = self.text.get(self.pos..self.pos + 4)?;
+        if !digits.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        self.pos += 4;
+        u32::from_str_radix(std::str::from_utf8(digits).unwrap(), 16).ok()
+    }
+
+    fn digits(&mut self) -> &[u8] {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        &self.text[start..self.pos]
+    }
+
+    fn number(&mut self, out: &mut Vec<u8>) -> Option<()> {
+        let negative = self.eat(b'-');
+        let int = self.digits().to_vec();
+        if int.is_empty() || (int.len() > 1 && int[0] == b'0') {
+            return None;
+        }
+        let mut frac = Vec::new();
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            frac = self.digits().to_vec();
+            if frac.is_empty() {
+                return None;
+            }
+        }
+        let mut exp: i64 = 0;
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.pos += 1;
+            let negative = self.peek() == Some(b'-');
+            if matches!(self.peek(), Some(b'-' | b'+')) {
+                self.pos += 1;
+            }
+            let digits = self.digits();
+            if digits.is_empty() {
+                return None;
+            }
+            // Saturates, so absurd exponents only lose precision
+            // beyond any practical use
+            for &d in digits {
+                exp = exp.saturating_mul(10).saturating_add(i64::from(d - b'0'));
+            }
+            if negative {
+                exp = -exp;
+            }
+        }
+        write_number(out, negative, &int, &frac, exp);
+        Some(())
+    }
+}
+
+/// The canonical form of a number written more loosely than JSON
+/// allows, like `01`, `+1`, `1.` or `.5`; none if it isn't a number
+pub fn canonical_number(text: &[u8]) -> Option<Vec<u8>> {
+    let (negative, text) = match text.first()? {
+        b'-' => (true, &text[1..]),
+        b'+' => (false, &text[1..]),
+        _ => (false, text),
+    };
+    let mantissa_len = text
+        .iter()
+        .position(|&b| b == b'e' || b == b'E')
+        .unwrap_or(text.len());
+    let (mantissa, exp) = text.split_at(mantissa_len);
+    let (int, frac) = match mantissa.iter().position(|&b| b == b'.') {
+        Some(idx) => (&mantissa[..idx], &mantissa[idx + 1..]),
+        None => (mantissa, &[][..]),
+    };
+    let digits = |d: &[u8]| d.iter().all(u8::is_ascii_digit);
+    if int.len() + frac.len() == 0 || !digits(int) || !digits(frac) {
+        return None;
+    }
+    let exp = match exp {
+        [] => 0,
+        [_, exp @ ..] => {
+            let (negative, exp) = match exp.first() {
+                Some(b'-') => (true, &exp[1..]),
+                Some(b'+') => (false, &exp[1..]),
+                _ => (false, exp),
+            };
+            if exp.is_empty() || !digits(exp) {
+                return None;
+            }
+            let exp = exp.iter().fold(0i64, |exp, &d| {
+                exp.saturating_mul(10).saturating_add(i64::from(d - b'0'))
+            });
+            match negative {
+                true => -exp,
+                false => exp,
+            }
+        }
+    };
+    let mut out = Vec::new();
+    write_number(&mut out, negative, int, frac, exp);
+    Some(out)
+}
+
+/// Write the number `int.frac * 10^exp` in its shortest form, like
+/// JavaScript does
+fn write_number(out: &mut Vec<u8>, negative: bool, int: &[u8], frac: &[u8], exp: i64) {
+    let mut digits: Vec<u8> = int.iter().chain(frac).copied().collect();
+    let mut exp = exp.saturating_sub(frac.len() as i64);
+    let leading = digits.iter().take_while(|&&d| d == b'0').count();
+    digits.drain(..leading);
+    while digits.last() == Some(&b'0') {
+        digits.pop();
+        exp = exp.saturating_add(1);
+    }
+    // Without a sign, as negative zero equals zero
+    if digits.is_empty() {
+        out.push(b'0');
+        return;
+    }
+    if negative {
+        out.push(b'-');
+    }
+    let len = digits.len() as i64;
+    // The position of the decimal point, relative to the first digit
+    let point = len.saturating_add(exp);
+    let mut s = String::new();
+    let digits = std::str::from_utf8(&digits).unwrap();
+    if exp >= 0 && point <= 21 {
+        s.push_str(digits);
+        s.extend((0..exp).map(|_| '0'));
+    } else if point > 0 && point <= 21 {
+        let (int, frac) = digits.split_at(point as usize);
+        let _ = write!(s, "{}.{}", int, frac);
+    } else if point <= 0 && point > -6 {
+        s.push_str("0.");
+        s.extend((0..-point).map(|_| '0'));
+        s.push_str(digits);
+    } else {
+        let (first, rest) = digits.split_at(1);
+        s.push_str(first);
+        if !rest.is_empty() {
+            let _ = write!(s, ".{}", rest);
+        }
+        let _ = write!(s, "e{}", point - 1);
+    }
+    out.extend_from_slice(s.as_bytes());
+}
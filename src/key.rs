@@ -0,0 +1,949 @@
+//! Selecting the part of each record that decides whether records are
+//! duplicates, like some of its fields, or having a command derive it.
+
+use crate::framing::Framing;
+use crate::input::Input;
+use crate::json;
+use crate::normalize::{self, Form};
+use crate::regex::Regex;
+use crate::script::Script;
+use crate::template::write_csv;
+use ahash::RandomState as ARandomState;
+use anyhow::{anyhow, Context, Result};
+use bstr::ByteSlice;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+
+/// How the key of each record is selected
+#[derive(Default)]
+pub struct Selector {
+    /// The fields making up the key
+    fields: Option<Fields>,
+    /// The columns of CSV records making up the key
+    columns: Option<Vec<RangeInclusive<usize>>>,
+    /// Number of leading fields ignored, like `uniq -f`
+    skip_fields: usize,
+    /// Number of characters ignored after those fields, like `uniq -s`
+    skip_chars: usize,
+    /// Number of characters compared after those skipped, like `uniq -w`
+    check_chars: Option<usize>,
+    /// The positions of the bytes making up the key, starting at one
+    bytes: Option<RangeInclusive<usize>>,
+    /// Extracts the key, along with what to do with records it
+    /// doesn't match
+    regex: Option<(Regex, Unmatched)>,
+    /// Use the canonical form of records that are JSON, along with what
+    /// to do with records that aren't
+    json: Option<Unmatched>,
+    /// Makes the key of the whole record
+    script: Option<Script>,
+    /// Parts of the key to leave out, wherever they match
+    strip: Vec<Regex>,
+    /// The Unicode normal form the key is put into
+    form: Option<Form>,
+    /// Ignore leading and trailing whitespace
+    trim: bool,
+    /// Replace runs of whitespace by a single space
+    squeeze_space: bool,
+    /// Compare keys that are numbers by their value
+    numeric: bool,
+    /// Compare keys that are IP addresses by the network of this size
+    /// they are in
+    ip: Option<IpPrefix>,
+    /// Case differences to ignore in the key
+    fold: Option<Fold>,
+    /// Compare the contents of the files the keys are the paths of,
+    /// along with what to do with files that can't be read
+    paths: Option<Unmatched>,
+    /// Keys that are hashes already, and how they are written
+    hashes: Option<HashFormat>,
+}
+
+/// Timestamps at the start of log lines, along with the blanks after
+/// them: syslog like `Oct 14 16:29:01`, ISO 8601 like
+/// `2024-10-14T16:29:01.123+02:00`, bracketed like apache's
+/// `[10/Oct/2024:16:29:01 +0200]` and dmesg's `[   12.345678]`
+const TIMESTAMPS: &str = concat!(
+    r"^(?:[A-Z][a-z]{2} [ \d]\d \d{2}:\d{2}:\d{2}",
+    r"|\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}(?::\d{2}(?:[.,]\d+)?)?(?:Z|[+-]\d{2}:?\d{2})?",
+    r"|\[[^\]]*\d{2}:\d{2}:\d{2}[^\]]*\]",
+    r"|\[ *\d+\.\d+\])[ \t]*",
+);
+
+/// What to do with records the key can not be extracted from
+#[derive(Clone, Copy, PartialEq)]
+pub enum Unmatched {
+    /// Use the whole record as the key
+    Record,
+    /// Leave out the record
+    Drop,
+    /// Print the record without comparing it, when removing duplicates;
+    /// otherwise leave it out
+    Pass,
+}
+
+impl Unmatched {
+    pub fn from_name(name: &str) -> Option<Unmatched> {
+        match name {
+            "line" => Some(Unmatched::Record),
+            "drop" => Some(Unmatched::Drop),
+            "pass" => Some(Unmatched::Pass),
+            _ => None,
+        }
+    }
+}
+
+/// How case is folded
+#[derive(Clone, Copy)]
+pub enum Fold {
+    /// Just the ASCII letters
+    Ascii,
+    /// All characters, like comparing the uppercase of the text in
+    /// lowercase, so `STRASSE` and `straße` are the same
+    Unicode,
+}
+
+impl Fold {
+    pub fn from_name(name: &str) -> Option<Fold> {
+        match name {
+            "ascii" => Some(Fold::Ascii),
+            "unicode" => Some(Fold::Unicode),
+            _ => None,
+        }
+    }
+}
+
+/// How hashes given as keys are written
+#[derive(Clone, Copy)]
+pub enum HashFormat {
+    /// Up to 16 hex digits, optionally preceded by `0x`
+    Hex,
+    /// Decimal digits
+    Decimal,
+}
+
+impl HashFormat {
+    pub fn from_name(name: &str) -> Option<HashFormat> {
+        match name {
+            "hex" => Some(HashFormat::Hex),
+            "decimal" => Some(HashFormat::Decimal),
+            _ => None,
+        }
+    }
+
+    /// The hash the text is; none if it isn't one
+    fn parse(self, text: &[u8]) -> Option<u64> {
+        let (digits, radix) = match self {
+            HashFormat::Hex => {
+                let digits = match text {
+                    [b'0', b'x' | b'X', digits @ ..] => digits,
+                    digits => digits,
+                };
+                (digits, 16)
+            }
+            HashFormat::Decimal => (text, 10),
+        };
+        // Rules out signs, which from_str_radix accepts
+        if digits.is_empty() || !digits.iter().all(|b| (*b as char).is_digit(radix)) {
+            return None;
+        }
+        u64::from_str_radix(std::str::from_utf8(digits).ok()?, radix).ok()
+    }
+
+    /// The hash written in a uniform way, which parses to the same hash
+    fn write(self, hash: u64) -> Vec<u8> {
+        match self {
+            HashFormat::Hex => format!("{:016x}", hash).into_bytes(),
+            HashFormat::Decimal => hash.to_string().into_bytes(),
+        }
+    }
+}
+
+/// The lengths of the network prefixes IP addresses are compared by
+#[derive(Clone, Copy)]
+pub struct IpPrefix {
+    v4: u32,
+    v6: u32,
+}
+
+impl Default for IpPrefix {
+    /// The whole addresses
+    fn default() -> IpPrefix {
+        IpPrefix { v4: 32, v6: 128 }
+    }
+}
+
+impl IpPrefix {
+    /// Parse a length like `/24` for both families, or `/24,/64` for
+    /// IPv4 and IPv6 respectively
+    pub fn parse(v: &str) -> Result<IpPrefix> {
+        let err = || {
+            anyhow!(
+                "invalid prefix `{}`: expected a length like /24, or /24,/64 for IPv4 and IPv6",
+                v
+            )
+        };
+        let len = |v: &str, max| {
+            let len: u32 = v
+                .strip_prefix('/')
+                .unwrap_or(v)
+                .parse()
+                .map_err(|_| err())?;
+            match len <= max {
+                true => Ok(len),
+                false => Err(err()),
+            }
+        };
+        Ok(match v.split_once(',') {
+            Some((v4, v6)) => IpPrefix {
+                v4: len(v4, 32)?,
+                v6: len(v6, 128)?,
+            },
+            None => {
+                let len = len(v, 128)?;
+                IpPrefix {
+                    v4: len.min(32),
+                    v6: len,
+                }
+            }
+        })
+    }
+}
+
+/// Fields selected by their positions, starting at one
+struct Fields {
+    ranges: Vec<RangeInclusive<usize>>,
+    /// Separates the fields; none for runs of blanks, ignoring
+    /// leading ones, like awk does
+    delim: Option<Vec<u8>>,
+}
+
+impl Selector {
+    /// Select the fields given like `2,4-6`
+    pub fn fields(mut self, list: &str, delim: Option<Vec<u8>>) -> Result<Selector> {
+        let ranges = parse_list(list, "fields")?;
+        if delim.as_ref().is_some_and(|d| d.is_empty()) {
+            return Err(anyhow!("the field delimiter must not be empty"));
+        }
+        self.fields = Some(Fields { ranges, delim });
+        Ok(self)
+    }
+
+    /// Select the columns of CSV records given like `2,4-6`, unquoted
+    /// if just one is selected, or written as CSV again
+    pub fn csv_columns(mut self, list: &str) -> Result<Selector> {
+        self.columns = Some(parse_list(list, "columns")?);
+        Ok(self)
+    }
+
+    /// Ignore the given number of leading fields, each blanks followed
+    /// by other characters; the blanks before the next field remain
+    pub fn skip_fields(mut self, n: usize) -> Selector {
+        self.skip_fields = n;
+        self
+    }
+
+    /// Ignore the given number of characters, after any fields skipped;
+    /// bytes that are not valid UTF-8 count as characters of their own
+    pub fn skip_chars(mut self, n: usize) -> Selector {
+        self.skip_chars = n;
+        self
+    }
+
+    /// Compare no more than the given number of characters, after
+    /// those skipped
+    pub fn check_chars(mut self, n: usize) -> Selector {
+        self.check_chars = Some(n);
+        self
+    }
+
+    /// Select the bytes given like `5-20`, regardless of any characters
+    pub fn bytes(mut self, range: &str) -> Result<Selector> {
+        let range = parse_range(range)
+            .ok_or_else(|| anyhow!("invalid byte range `{}`; expected e.g. 5-20", range))?;
+        self.bytes = Some(range);
+        Ok(self)
+    }
+
+    /// Use what the regex matches as the key; the first group if it
+    /// has any
+    pub fn regex(mut self, regex: Regex, unmatched: Unmatched) -> Selector {
+        self.regex = Some((regex, unmatched));
+        self
+    }
+
+    /// Use what the script makes of the record as the key
+    pub fn script(mut self, script: Script) -> Selector {
+        self.script = Some(script);
+        self
+    }
+
+    /// Compare records as JSON, ignoring differences in formatting;
+    /// see [json::canonical]
+    pub fn json(mut self, unmatched: Unmatched) -> Selector {
+        self.json = Some(unmatched);
+        self
+    }
+
+    /// Remove whatever the regex matches from the key, before any other
+    /// normalizations
+    pub fn strip(mut self, regex: Regex) -> Selector {
+        self.strip.push(regex);
+        self
+    }
+
+    /// Remove common timestamps from the start of the key
+    pub fn ignore_timestamps(self) -> Selector {
+        self.strip(Regex::new(TIMESTAMPS).unwrap())
+    }
+
+    /// Put the key into the Unicode normal form, before folding case
+    pub fn normalize_unicode(mut self, form: Form) -> Selector {
+        self.form = Some(form);
+        self
+    }
+
+    /// Ignore whitespace at the start and end of the key
+    pub fn trim(mut self) -> Selector {
+        self.trim = true;
+        self
+    }
+
+    /// Treat any run of whitespace in the key like a single space
+    pub fn squeeze_space(mut self) -> Selector {
+        self.squeeze_space = true;
+        self
+    }
+
+    /// Compare keys that are numbers by their value, so `1`, `1.0`
+    /// and `01` are the same
+    pub fn numeric(mut self) -> Selector {
+        self.numeric = true;
+        self
+    }
+
+    /// Compare keys that are IP addresses in their canonical form, or
+    /// by the network they are in
+    pub fn ip(mut self, prefix: IpPrefix) -> Selector {
+        self.ip = Some(prefix);
+        self
+    }
+
+    /// Take keys that are hashes as they are, instead of hashing them
+    pub fn hashes(mut self, format: HashFormat) -> Selector {
+        self.hashes = Some(format);
+        self
+    }
+
+    /// The hash the (normalized) key is, if taking hashes as they are
+    pub fn precomputed(&self, key: &[u8]) -> Option<u64> {
+        self.hashes.and_then(|format| format.parse(key))
+    }
+
+    /// Ignore differences in case
+    pub fn fold_case(mut self, fold: Fold) -> Selector {
+        self.fold = Some(fold);
+        self
+    }
+
+    /// Compare the contents of the files the keys are the paths of,
+    /// instead of the paths
+    pub fn paths(mut self, unreadable: Unmatched) -> Selector {
+        self.paths = Some(unreadable);
+        self
+    }
+
+    /// Whether records without a key are printed as is when removing
+    /// duplicates
+    pub fn passes_unmatched(&self) -> bool {
+        let pass = Some(Unmatched::Pass);
+        matches!(self.regex, Some((_, Unmatched::Pass))) || self.json == pass || self.paths == pass
+    }
+
+    /// The key of the record, without its terminator; none if it has
+    /// none and is to be left out. Only copied if the selected parts
+    /// are not contiguous, or have to be normalized.
+    pub fn select<'a>(&self, record: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        let key = self.extract(record)?;
+        // Opened as given, as normalizing might name another file; the
+        // contents are compared as they are
+        match self.paths {
+            Some(unreadable) => match (contents(&key), unreadable) {
+                (Ok(digest), _) => Some(Cow::Owned(digest)),
+                (Err(_), Unmatched::Record) => Some(self.normalize(key)),
+                (Err(_), _) => None,
+            },
+            None => Some(self.normalize(key)),
+        }
+    }
+
+    /// Apply the normalizations, like folding case, to the key
+    pub fn normalize<'a>(&self, mut key: Cow<'a, [u8]>) -> Cow<'a, [u8]> {
+        for regex in &self.strip {
+            key = strip(key, regex);
+        }
+        let key = match self.form {
+            Some(form) => normalize::normalize(key, form),
+            None => key,
+        };
+        let key = match self.trim {
+            true => trim(key),
+            false => key,
+        };
+        let key = match self.squeeze_space {
+            true => squeeze_space(key),
+            false => key,
+        };
+        let key = match self.numeric {
+            true => match json::canonical_number(&key) {
+                Some(number) if number != *key => Cow::Owned(number),
+                _ => key,
+            },
+            false => key,
+        };
+        let key = match self.ip {
+            Some(prefix) => match ip(&key, prefix) {
+                Some(ip) if ip != *key => Cow::Owned(ip),
+                _ => key,
+            },
+            None => key,
+        };
+        let key = match self.fold {
+            Some(fold) => fold_case(key, fold),
+            None => key,
+        };
+        match self.hashes.map(|format| (format, format.parse(&key))) {
+            Some((format, Some(hash))) => match format.write(hash) {
+                uniform if uniform != *key => Cow::Owned(uniform),
+                _ => key,
+            },
+            _ => key,
+        }
+    }
+
+    fn extract<'a>(&self, record: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        if let Some(script) = &self.script {
+            return Some(Cow::Owned(script.key(record)));
+        }
+        if let Some(unmatched) = self.json {
+            return match (json::canonical(record), unmatched) {
+                (Some(key), _) => Some(Cow::Owned(key)),
+                (None, Unmatched::Record) => Some(Cow::Borrowed(record)),
+                (None, _) => None,
+            };
+        }
+        if let Some((regex, unmatched)) = &self.regex {
+            return match (regex.extract(record), unmatched) {
+                (Some(key), _) => Some(Cow::Borrowed(key)),
+                (None, Unmatched::Record) => Some(Cow::Borrowed(record)),
+                (None, _) => None,
+            };
+        }
+        if let Some(columns) = &self.columns {
+            return Some(Cow::Owned(csv_columns(record, columns)));
+        }
+        if let Some(range) = &self.bytes {
+            let end = record.len().min(*range.end());
+            let start = (*range.start() - 1).min(end);
+            return Some(Cow::Borrowed(&record[start..end]));
+        }
+        let key = match &self.fields {
+            Some(fields) => fields.select(record),
+            None => Cow::Borrowed(record),
+        };
+        let mut start = skip_fields(&key, self.skip_fields);
+        start += chars_len(&key[start..], self.skip_chars);
+        let end = match self.check_chars {
+            Some(n) => start + chars_len(&key[start..], n),
+            None => key.len(),
+        };
+        Some(match (start, end) {
+            (0, end) if end == key.len() => key,
+            range => part(key, range),
+        })
+    }
+}
+
+/// Size of the blocks files are hashed in
+const CONTENTS_BLOCK: u64 = 1 << 16;
+
+/// A digest of the contents of the file at the path, streamed in
+/// blocks; it starts with a zero byte, which no path contains, so it
+/// never equals the path of an unreadable file
+fn contents(path: &[u8]) -> io::Result<Vec<u8>> {
+    let file = File::open(OsStr::from_bytes(path))?;
+    // Two independent hashes, so files are about as unlikely to be
+    // wrongly taken as the same as any other records
+    let mut hashers = [(1, 2, 3, 4), (5, 6, 7, 8)]
+        .map(|(k0, k1, k2, k3)| ARandomState::with_seeds(k0, k1, k2, k3).build_hasher());
+    let (mut block, mut len) = (Vec::with_capacity(CONTENTS_BLOCK as usize), 0u64);
+    loop {
+        // Whole blocks, so the digest doesn't depend on how reads split
+        block.clear();
+        let n = (&file).take(CONTENTS_BLOCK).read_to_end(&mut block)?;
+        for hasher in &mut hashers {
+            hasher.write(&block);
+        }
+        len += n as u64;
+        if (n as u64) < CONTENTS_BLOCK {
+            break;
+        }
+    }
+    let mut digest = vec![0];
+    digest.extend_from_slice(&len.to_le_bytes());
+    for hasher in &hashers {
+        digest.extend_from_slice(&hasher.finish().to_le_bytes());
+    }
+    Ok(digest)
+}
+
+/// The key without any matches of the regex
+fn strip<'a>(key: Cow<'a, [u8]>, regex: &Regex) -> Cow<'a, [u8]> {
+    let matches = regex.matches(&key);
+    if matches.iter().all(|(start, end)| start == end) {
+        return key;
+    }
+    let mut stripped = Vec::with_capacity(key.len());
+    let mut pos = 0;
+    for (start, end) in matches {
+        stripped.extend_from_slice(&key[pos..start]);
+        pos = end;
+    }
+    stripped.extend_from_slice(&key[pos..]);
+    Cow::Owned(stripped)
+}
+
+/// The key without leading and trailing whitespace
+fn trim(key: Cow<[u8]>) -> Cow<[u8]> {
+    let start = key.len() - key.trim_start().len();
+    let end = start + key[start..].trim_end().len();
+    part(key, (start, end))
+}
+
+/// The key with each run of whitespace replaced by a single space
+fn squeeze_space(key: Cow<[u8]>) -> Cow<[u8]> {
+    let mut squeezed = Vec::with_capacity(key.len());
+    let mut blank = false;
+    for (start, end, c) in key.char_indices() {
+        match c.is_whitespace() {
+            true if blank => {}
+            true => squeezed.push(b' '),
+            false => squeezed.extend_from_slice(&key[start..end]),
+        }
+        blank = c.is_whitespace();
+    }
+    match squeezed == *key {
+        true => key,
+        false => Cow::Owned(squeezed),
+    }
+}
+
+/// The canonical form of the address, or of the network it is in
+/// unless the prefix covers all of it; IPv4 addresses mapped to IPv6
+/// are the same as the IPv4 ones. None if the key isn't an address.
+fn ip(key: &[u8], prefix: IpPrefix) -> Option<Vec<u8>> {
+    let addr: IpAddr = std::str::from_utf8(key).ok()?.parse().ok()?;
+    let addr = match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        v4 => v4,
+    };
+    Some(
+        match addr {
+            IpAddr::V4(v4) if prefix.v4 < 32 => {
+                let mask = u32::MAX.checked_shl(32 - prefix.v4).unwrap_or(0);
+                format!("{}/{}", Ipv4Addr::from(u32::from(v4) & mask), prefix.v4)
+            }
+            IpAddr::V6(v6) if prefix.v6 < 128 => {
+                let mask = u128::MAX.checked_shl(128 - prefix.v6).unwrap_or(0);
+                format!("{}/{}", Ipv6Addr::from(u128::from(v6) & mask), prefix.v6)
+            }
+            addr => addr.to_string(),
+        }
+        .into_bytes(),
+    )
+}
+
+/// The key with its case folded; records that are not valid UTF-8
+/// keep the bytes not part of a character
+fn fold_case(key: Cow<[u8]>, fold: Fold) -> Cow<[u8]> {
+    match fold {
+        _ if key.is_ascii() => match key.iter().any(u8::is_ascii_uppercase) {
+            true => Cow::Owned(key.to_ascii_lowercase()),
+            false => key,
+        },
+        Fold::Ascii => Cow::Owned(key.to_ascii_lowercase()),
+        Fold::Unicode => {
+            let mut folded = Vec::with_capacity(key.len());
+            for (start, end, c) in key.char_indices() {
+                let invalid =
+                    c == char::REPLACEMENT_CHARACTER && &key[start..end] != "\u{FFFD}".as_bytes();
+                match invalid {
+                    true => folded.extend_from_slice(&key[start..end]),
+                    false => {
+                        for c in c.to_uppercase().flat_map(char::to_lowercase) {
+                            let mut buf = [0; 4];
+                            folded.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                        }
+                    }
+                }
+            }
+            Cow::Owned(folded)
+        }
+    }
+}
+
+/// The position after the first `n` fields
+fn skip_fields(key: &[u8], n: usize) -> usize {
+    let blank = |b: &u8| *b == b' ' || *b == b'\t';
+    let mut pos = 0;
+    for _ in 0..n {
+        pos += key[pos..].iter().take_while(|b| blank(b)).count();
+        pos += key[pos..].iter().take_while(|b| !blank(b)).count();
+    }
+    pos
+}
+
+/// The length in bytes of the first `n` characters
+fn chars_len(key: &[u8], n: usize) -> usize {
+    match n {
+        0 => 0,
+        n => key
+            .char_indices()
+            .nth(n)
+            .map_or(key.len(), |(start, _, _)| start),
+    }
+}
+
+/// The bytes from `start` to `end` of the key
+fn part(key: Cow<[u8]>, (start, end): (usize, usize)) -> Cow<[u8]> {
+    match key {
+        Cow::Borrowed(key) => Cow::Borrowed(&key[start..end]),
+        Cow::Owned(mut key) => {
+            key.truncate(end);
+            key.drain(..start);
+            Cow::Owned(key)
+        }
+    }
+}
+
+impl Fields {
+    fn select<'a>(&self, record: &'a [u8]) -> Cow<'a, [u8]> {
+        let delim = self.delim.as_deref().unwrap_or(b" ");
+        let spans = self.spans(record);
+        let start = spans.first().map_or(0, |&(start, _)| start);
+        // Contiguous if each field follows the previous one
+        let contiguous = spans.windows(2).all(|w| w[1].0 == w[0].1 + delim.len())
+            && (self.delim.is_some() || spans.len() <= 1);
+        if contiguous {
+            let end = spans.last().map_or(0, |&(_, end)| end);
+            return Cow::Borrowed(&record[start..end]);
+        }
+        let mut key = Vec::with_capacity(record.len());
+        for (idx, &(start, end)) in spans.iter().enumerate() {
+            if idx > 0 {
+                key.extend_from_slice(delim);
+            }
+            key.extend_from_slice(&record[start..end]);
+        }
+        Cow::Owned(key)
+    }
+
+    /// Start and end of each selected field, in the order of the record
+    fn spans(&self, record: &[u8]) -> Vec<(usize, usize)> {
+        let mut spans = Vec::new();
+        let mut add = |pos: usize, span: (usize, usize)| {
+            if self.ranges.iter().any(|range| range.contains(&pos)) {
+                spans.push(span);
+            }
+        };
+        match &self.delim {
+            Some(delim) => {
+                let mut start = 0;
+                for (pos, end) in record
+                    .find_iter(delim)
+                    .chain(Some(record.len()))
+                    .enumerate()
+                {
+                    add(pos + 1, (start, end));
+                    start = end + delim.len();
+                }
+            }
+            None => {
+                let mut pos = 0;
+                let mut start = None;
+                for (idx, &b) in record.iter().chain(Some(&b' ')).enumerate() {
+                    match (start, b == b' ' || b == b'\t') {
+                        (None, false) => start = Some(idx),
+                        (Some(begin), true) => {
+                            pos += 1;
+                            add(pos, (begin, idx));
+                            start = None;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        spans
+    }
+}
+
+/// The selected columns of the CSV record; a single one unquoted
+fn csv_columns(record: &[u8], columns: &[RangeInclusive<usize>]) -> Vec<u8> {
+    let mut selected = Vec::new();
+    let mut value = Vec::new();
+    let mut rest = record;
+    let mut pos = 0;
+    let mut done = false;
+    while !done {
+        pos += 1;
+        value.clear();
+        let end = match rest.first() {
+            Some(b'"') => {
+                // Up to the closing quote, with quotes escaped by another;
+                // anything after it up to the comma is kept
+                let mut idx = 1;
+                while idx < rest.len() {
+                    match (rest[idx], rest.get(idx + 1)) {
+                        (b'"', Some(b'"')) => {
+                            value.push(b'"');
+                            idx += 2;
+                        }
+                        (b'"', _) => {
+                            idx += 1;
+                            break;
+                        }
+                        (b, _) => {
+                            value.push(b);
+                            idx += 1;
+                        }
+                    }
+                }
+                let end = idx + rest[idx..].find_byte(b',').unwrap_or(rest.len() - idx);
+                value.extend_from_slice(&rest[idx..end]);
+                end
+            }
+            _ => {
+                let end = rest.find_byte(b',').unwrap_or(rest.len());
+                value.extend_from_slice(&rest[..end]);
+                end
+            }
+        };
+        done = end == rest.len();
+        rest = &rest[(end + 1).min(rest.len())..];
+        if columns.iter().any(|range| range.contains(&pos)) {
+            selected.push(value.clone());
+        }
+    }
+    match selected.len() {
+        1 => selected.pop().unwrap(),
+        _ => {
+            let mut key = Vec::new();
+            for (idx, value) in selected.iter().enumerate() {
+                if idx > 0 {
+                    key.push(b',');
+                }
+                // Writing to a Vec can't fail
+                write_csv(&mut key, value).unwrap();
+            }
+            key
+        }
+    }
+}
+
+/// Parse a list of positions and ranges like `2,4-6`
+fn parse_list(list: &str, what: &str) -> Result<Vec<RangeInclusive<usize>>> {
+    list.split(',')
+        .map(parse_range)
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| anyhow!("invalid {} `{}`; expected e.g. 2 or 2,4-6", what, list))
+}
+
+/// Parse a field position like `2`, or a range like `4-6`, `4-` or `-6`
+fn parse_range(v: &str) -> Option<RangeInclusive<usize>> {
+    let pos = |v: &str| v.parse::<usize>().ok().filter(|&n| n > 0);
+    match v.split_once('-') {
+        None => pos(v).map(|n| n..=n),
+        Some(("", "")) => None,
+        Some((start, end)) => {
+            let start = match start {
+                "" => 1,
+                start => pos(start)?,
+            };
+            let end = match end {
+                "" => usize::MAX,
+                end => pos(end)?,
+            };
+            Some(start..=end)
+        }
+    }
+}
+
+/// Most records waiting for their keys from a key command before
+/// waiting for the command to catch up
+const PENDING: usize = 1 << 16;
+
+/// Invoke `f` for every record in the input along with its key, printed
+/// by the shell command for the record, until `f` returns false.
+///
+/// A single process of the command is fed the records, without their
+/// terminators, and has to print one key for each in turn; both are
+/// terminated by `\0` if the records are, by newlines otherwise. The
+/// command may buffer its output, as records are passed on only once
+/// their keys arrive.
+pub fn for_each_command_key<F>(
+    command: &OsStr,
+    input: Input,
+    framing: &Framing,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(&[u8], &[u8]) -> io::Result<bool>,
+{
+    let name = command.to_string_lossy();
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .map(KeyCommand)
+        .with_context(|| format!("could not run key command `{}`", name))?;
+    let delim = match framing.terminator() {
+        b"\0" => b'\0',
+        _ => b'\n',
+    };
+
+    let (record_tx, record_rx) = mpsc::channel::<Vec<u8>>();
+    let mut stdin = BufWriter::new(child.0.stdin.take().unwrap());
+    let writer = thread::spawn(move || -> io::Result<()> {
+        // Flushed whenever no more records are ready, so the command
+        // is never kept waiting for records already read
+        loop {
+            let mut key = match record_rx.try_recv() {
+                Ok(key) => key,
+                Err(TryRecvError::Empty) => {
+                    stdin.flush()?;
+                    match record_rx.recv() {
+                        Ok(key) => key,
+                        Err(_) => break,
+                    }
+                }
+                Err(TryRecvError::Disconnected) => break,
+            };
+            key.push(delim);
+            stdin.write_all(&key)?;
+        }
+        stdin.flush()
+    });
+    let (key_tx, key_rx) = mpsc::channel::<Vec<u8>>();
+    let stdout = BufReader::new(child.0.stdout.take().unwrap());
+    let reader = thread::spawn(move || -> io::Result<()> {
+        for key in stdout.split(delim) {
+            if key_tx.send(key?).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+
+    let invalid =
+        |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("key command {}", msg));
+    let mut pending = VecDeque::new();
+    let mut deliver = |pending: &mut VecDeque<Vec<u8>>, key: Vec<u8>| {
+        let record: Vec<u8> = pending
+            .pop_front()
+            .ok_or_else(|| invalid("printed more keys than it was given records"))?;
+        f(&record, &key)
+    };
+    // Whether the records were cut short by `f`
+    let fed = (|| -> Result<bool> {
+        let mut done = false;
+        input.for_each_record(framing, |record| {
+            if record_tx.send(framing.key(record).to_vec()).is_err() {
+                return Err(invalid("stopped reading records"));
+            }
+            pending.push_back(record.to_vec());
+            loop {
+                let key = match pending.len() > PENDING {
+                    true => key_rx.recv().ok(),
+                    false => match key_rx.try_recv() {
+                        Ok(key) => Some(key),
+                        Err(TryRecvError::Empty) => return Ok(true),
+                        Err(TryRecvError::Disconnected) => None,
+                    },
+                };
+                let key =
+                    key.ok_or_else(|| invalid("printed fewer keys than it was given records"))?;
+                if !deliver(&mut pending, key)? {
+                    done = true;
+                    return Ok(false);
+                }
+            }
+        })?;
+        drop(record_tx);
+        if !done {
+            for key in key_rx.iter() {
+                if !deliver(&mut pending, key)? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(done)
+    })();
+    // Unless all keys were read, the command may still be waiting to
+    // write them; killed, so both threads end and can be joined
+    if !matches!(fed, Ok(false)) {
+        child.kill();
+    }
+    let status = child.0.wait();
+    let written = writer.join().unwrap();
+    let read = reader.join().unwrap();
+    if fed? {
+        return Ok(());
+    }
+    let status = status?;
+    if !status.success() {
+        return Err(anyhow!("key command `{}` failed: {}", name, status));
+    }
+    if !pending.is_empty() {
+        return Err(invalid("printed fewer keys than it was given records").into());
+    }
+    written?;
+    read?;
+    Ok(())
+}
+
+/// The shell running a key command, in a process group of its own;
+/// killed unless it has exited once dropped, like when `f` panics
+struct KeyCommand(Child);
+
+impl KeyCommand {
+    /// Kill the shell along with any processes it started, which may
+    /// still hold its output open otherwise; only while it has not been
+    /// waited for, so its process group can't have been reused
+    fn kill(&mut self) {
+        unsafe { libc::kill(-(self.0.id() as libc::pid_t), libc::SIGKILL) };
+    }
+}
+
+impl Drop for KeyCommand {
+    fn drop(&mut self) {
+        if let Ok(None) = self.0.try_wait() {
+            self.kill();
+            let _ = self.0.wait();
+        }
+    }
+}
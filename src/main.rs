@@ -1,14 +1,67 @@
+mod compress;
+mod counts;
+mod files;
+mod framing;
+mod hasher;
+mod input;
+mod json;
+mod key;
+mod normalize;
+mod output;
+mod parallel;
+mod regex;
+mod script;
+mod server;
+mod signal;
+mod similar;
+mod sketch;
+mod spill;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod state;
+mod stats;
+mod store;
+mod template;
+mod unicode_tables;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod window;
+
 use ahash::RandomState as ARandomState;
 use anyhow::{anyhow, Result};
-use bstr::{io::BufReadExt, ByteSlice};
-use clap::{Arg, Command};
+use bstr::ByteSlice;
+use clap::{Arg, ArgMatches, Command};
+use compress::Compression;
+use counts::{Counts, Groups};
+use framing::{Framing, Prefix};
+use hasher::{Algorithm, RecordHasher};
+use input::{open_input, InputOpts};
+use key::{Fold, HashFormat, IpPrefix, Selector, Unmatched};
+use output::{Output, DEFAULT_BUFFER_SIZE};
+use server::Client;
+use similar::{BkTree, Similar};
+use sketch::{Bloom, CountMin, Cuckoo, HyperLogLog, TopK};
+use spill::Spill;
+use stats::{Counted, Stats};
+use std::borrow::Cow;
 use std::cmp::Ordering;
-use std::collections::{hash_map, HashMap, HashSet};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::hash::BuildHasherDefault;
 use std::hash::{BuildHasher, Hasher};
-use std::io::{stdin, stdout, BufRead, Write};
+use std::io::{self, Write};
 use std::mem;
+use std::ops::RangeInclusive;
+use std::os::unix::ffi::OsStrExt;
+use std::process;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{default::Default, slice};
+use store::Store;
+use template::{write_csv, write_tsv, NonUtf8, Row, Template};
+use window::{Expiring, Lru, Recent};
 
 /// A no-operation hasher. Used as part of the uniq implementation,
 /// because in there we manually hash the data and just store the
@@ -33,35 +86,471 @@ impl Hasher for IdentityHasher {
 
 /// Hash the given value with the given BuildHasher. Now.
 fn hash<T: BuildHasher, U: std::hash::Hash + ?Sized>(build: &T, v: &U) -> u64 {
-    let mut s = build.build_hasher();
-    v.hash(&mut s);
-    s.finish()
+    build.hash_one(v)
+}
+
+/// Parse a size in bytes with an optional binary unit suffix,
+/// e.g. `4096`, `64K`, `16M` or `4G`.
+fn parse_size(v: &str) -> Result<usize> {
+    let (num, shift) = match v.as_bytes().last().map(u8::to_ascii_uppercase) {
+        Some(b'K') => (&v[..v.len() - 1], 10),
+        Some(b'M') => (&v[..v.len() - 1], 20),
+        Some(b'G') => (&v[..v.len() - 1], 30),
+        Some(b'T') => (&v[..v.len() - 1], 40),
+        _ => (v, 0),
+    };
+    num.parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| anyhow!("invalid size `{}`; expected e.g. 4096, 64K, 16M or 4G", v))
+}
+
+/// Parse a duration with an optional unit suffix, e.g. `30`, `30s`,
+/// `10m`, `2h` or `1d`; seconds by default.
+fn parse_duration(v: &str) -> Result<Duration> {
+    let (num, unit) = match v.as_bytes().last() {
+        Some(b's') => (&v[..v.len() - 1], 1),
+        Some(b'm') => (&v[..v.len() - 1], 60),
+        Some(b'h') => (&v[..v.len() - 1], 60 * 60),
+        Some(b'd') => (&v[..v.len() - 1], 24 * 60 * 60),
+        _ => (v, 1),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .map(Duration::from_secs)
+        .ok_or_else(|| anyhow!("invalid duration `{}`; expected e.g. 30s, 10m or 2h", v))
+}
+
+/// Parse the seeds of the hash function from up to 64 hex digits,
+/// e.g. `0x1234` or `c0ffee`, padded with zeros in front
+fn parse_seed(v: &str) -> Result<[u64; 4]> {
+    let digits = v.strip_prefix("0x").unwrap_or(v);
+    let valid = !digits.is_empty() && digits.len() <= 64;
+    if !valid || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(anyhow!(
+            "invalid seed `{}`; expected up to 64 hex digits",
+            v
+        ));
+    }
+    let padded = format!("{:0>64}", digits);
+    let mut seeds = [0; 4];
+    for (idx, seed) in seeds.iter_mut().enumerate() {
+        *seed = u64::from_str_radix(&padded[idx * 16..(idx + 1) * 16], 16)?;
+    }
+    Ok(seeds)
+}
+
+/// Get the bytes of an argument like the delimiter, interpreting
+/// the escapes `\\`, `\0`, `\t`, `\n`, `\r` and `\xHH`
+fn parse_escaped(v: &OsStr, what: &str) -> Result<Vec<u8>> {
+    let v = <[u8]>::from_os_str(v)
+        .ok_or_else(|| anyhow!("the {} is not representable as bytes", what))?;
+    let invalid = || anyhow!("invalid escape sequence in {} `{}`", what, v.as_bstr());
+
+    let mut delim = Vec::with_capacity(v.len());
+    let mut rest = v.iter();
+    while let Some(&b) = rest.next() {
+        if b != b'\\' {
+            delim.push(b);
+            continue;
+        }
+        delim.push(match rest.next().ok_or_else(invalid)? {
+            b'\\' => b'\\',
+            b'0' => b'\0',
+            b't' => b'\t',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b'x' => {
+                let hex = [
+                    *rest.next().ok_or_else(invalid)?,
+                    *rest.next().ok_or_else(invalid)?,
+                ];
+                std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(invalid)?
+            }
+            _ => return Err(invalid()),
+        });
+    }
+    Ok(delim)
+}
+
+/// Settings shared by the different modes of operation
+struct Opts {
+    /// How records are delimited
+    framing: Framing,
+    /// Which part of each record is compared
+    key: Selector,
+    /// Shell command printing the key of each record instead; see
+    /// [key::for_each_command_key]
+    key_command: Option<OsString>,
+    /// Delimiter written after records instead of their own terminators
+    out_delim: Option<Vec<u8>>,
+    /// Add a delimiter to the last record if it is missing
+    include_trailing: bool,
+    /// How to open the inputs; following applies to the last input only
+    input: InputOpts,
+    /// Number of threads to hash records on; one means no extra threads
+    threads: usize,
+    /// Expected number of distinct records, used to preallocate the sets
+    capacity: usize,
+    /// The hash function records are compared by
+    algorithm: Algorithm,
+    /// Seeds of the hash function given, or from the operating system
+    /// for untrusted input; otherwise they are random or from a state
+    seeds: Option<[u64; 4]>,
+    /// How to remember the records seen when removing duplicates
+    backend: Backend,
+    /// Treat records within this edit distance of one seen as that
+    /// one when removing duplicates; see [BkTree]
+    max_edit_distance: Option<usize>,
+    /// Keep the records seen when removing duplicates, instead of just
+    /// their hashes, so records with the same hash are never mistaken
+    /// for each other
+    exact: bool,
+    /// Warn about different records with the same hash when removing
+    /// duplicates
+    detect_collisions: bool,
+    /// Once this many distinct records are held in memory, continue
+    /// removing duplicates on disk; see [spill]
+    spill: Option<usize>,
+    /// Limit on the memory used by the set of seen records
+    max_memory: Option<(usize, Overflow)>,
+    /// Forget all records seen after this many records
+    reset_every: Option<u64>,
+    /// Forget all records seen once this much time has passed
+    reset_interval: Option<Duration>,
+    /// Continue from the records seen, or counted, in a previous run
+    load_state: Option<OsString>,
+    /// Save the records seen, or counted, for a later run
+    dump_state: Option<OsString>,
+    /// Files of records to treat as seen before reading the inputs
+    filter_files: Vec<OsString>,
+    /// Write all distinct records, including those of the filter files,
+    /// to this file
+    dump_lines: Option<OsString>,
+    /// Ask the server listening on this socket instead of remembering
+    /// the records seen; see [server]
+    connect: Option<OsString>,
+    /// Remember the records seen in this file instead of in memory;
+    /// see [store]
+    store: Option<OsString>,
+    /// Read previous counts instead of records; see [parse_count]
+    merge: bool,
+    /// Where to print the hash of each record when removing duplicates
+    print_hash: Option<HashPosition>,
+}
+
+/// Where the hash is printed along with the record
+#[derive(Clone, Copy)]
+enum HashPosition {
+    Prefix,
+    Suffix,
+}
+
+impl HashPosition {
+    fn from_name(name: &str) -> Option<HashPosition> {
+        match name {
+            "prefix" => Some(HashPosition::Prefix),
+            "suffix" => Some(HashPosition::Suffix),
+            _ => None,
+        }
+    }
+}
+
+/// What to do once the set of seen records would exceed the memory limit
+#[derive(Clone, Copy)]
+enum Overflow {
+    /// Fail before running out of memory
+    Abort,
+    /// Continue removing duplicates on disk; see [spill]
+    Spill,
+    /// Continue with a [Bloom] filter of the size of the limit
+    Approximate,
+}
+
+impl Overflow {
+    fn from_name(name: &str) -> Option<Overflow> {
+        match name {
+            "abort" => Some(Overflow::Abort),
+            "spill" => Some(Overflow::Spill),
+            "approximate" => Some(Overflow::Approximate),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Backend {
+    /// A hash set of the hashes of the records
+    HashSet,
+    /// A hash set of 128 bit hashes of the records
+    Wide,
+    /// A [Bloom] filter with the given false positive rate
+    Bloom(f64),
+    /// A [Cuckoo] filter
+    Cuckoo,
+    /// Just the given number of most recently seen records
+    Window(usize),
+    /// The given number of last records, duplicates included
+    Last(usize),
+    /// The records first seen within the given duration
+    Expire(Duration),
+    /// Like [Backend::Expire], in a [Cuckoo] filter
+    ExpireCuckoo(Duration),
+    /// The records not similar to one seen before them, by the given
+    /// threshold; see [similar]
+    Similar(f64),
+}
+
+impl Opts {
+    /// The delimiter written after records
+    fn out_delim(&self) -> &[u8] {
+        match &self.out_delim {
+            Some(out_delim) => out_delim,
+            None => self.framing.terminator(),
+        }
+    }
+
+    /// The part of the record deciding whether it is a duplicate; none
+    /// if the record is to be left out, see [key::Unmatched]
+    fn key<'a>(&self, record: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        self.key.select(self.framing.key(record))
+    }
+
+    /// The hash function, with random seeds unless given
+    fn hasher(&self) -> RecordHasher {
+        self.algorithm
+            .hasher(self.seeds.unwrap_or_else(state::random_seeds))
+    }
+
+    /// Fail unless the seeds of a state are the ones given, if any
+    fn check_seeds(&self, loaded: [u64; 4]) -> Result<()> {
+        match self.seeds {
+            Some(seeds) if seeds != loaded => {
+                Err(anyhow!("the state was saved with another seed than --seed"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The hash of the key, unless it is a hash already; see
+    /// [Selector::precomputed]
+    fn hash<T: BuildHasher>(&self, hasher: &T, key: &[u8]) -> u64 {
+        match self.key.precomputed(key) {
+            Some(hash) => hash,
+            None => hash(hasher, key),
+        }
+    }
+
+    /// Invoke `f` for every record in the input along with its key,
+    /// like [input::Input::for_each_record]
+    fn for_each_record<F>(&self, path: &OsStr, input: InputOpts, mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], Option<Cow<[u8]>>) -> io::Result<bool>,
+    {
+        let records = open_input(path, input)?;
+        match &self.key_command {
+            Some(command) => {
+                key::for_each_command_key(command, records, &self.framing, |line, key| {
+                    f(line, Some(self.key.normalize(Cow::Borrowed(key))))
+                })
+            }
+            None => Ok(records.for_each_record(&self.framing, |line| f(line, self.key(line)))?),
+        }
+    }
+
+    /// Write the record, terminated as configured
+    fn write_record(&self, sink: &mut dyn Write, record: &[u8]) -> io::Result<()> {
+        match &self.out_delim {
+            Some(out_delim) => {
+                sink.write_all(self.framing.key(record))?;
+                if self.include_trailing || self.framing.missing(record).is_empty() {
+                    sink.write_all(out_delim)?;
+                }
+            }
+            None => {
+                sink.write_all(record)?;
+                if self.include_trailing {
+                    sink.write_all(self.framing.missing(record))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the record like [Opts::write_record], along with its hash
+    /// if asked to
+    fn write_hashed(&self, sink: &mut dyn Write, record: &[u8], hash: u64) -> io::Result<()> {
+        let position = match self.print_hash {
+            Some(position) => position,
+            None => return self.write_record(sink, record),
+        };
+        let key = self.framing.key(record);
+        // The key is a part of the record
+        let start = key.as_ptr() as usize - record.as_ptr() as usize;
+        let mut hashed = Vec::with_capacity(record.len() + 17);
+        hashed.extend_from_slice(&record[..start]);
+        match position {
+            HashPosition::Prefix => {
+                write!(hashed, "{:016x}\t", hash)?;
+                hashed.extend_from_slice(key);
+            }
+            HashPosition::Suffix => {
+                hashed.extend_from_slice(key);
+                write!(hashed, "\t{:016x}", hash)?;
+            }
+        }
+        hashed.extend_from_slice(&record[start + key.len()..]);
+        self.write_record(sink, &hashed)
+    }
+
+    /// Write the record as read, terminated even if it was the last
+    fn write_terminated(&self, sink: &mut dyn Write, record: &[u8]) -> io::Result<()> {
+        sink.write_all(record)?;
+        sink.write_all(self.framing.missing(record))
+    }
 }
 
+#[derive(Clone, Copy)]
 enum Sort {
     Ascending,
     Descending,
 }
 
-/// Remove duplicates from stdin and print to stdout, counting
-/// the number of occurrences.
-fn count_cmd(delim: u8, sort: Option<Sort>) -> Result<()> {
-    let mut set = HashMap::<Vec<u8>, u64, ARandomState>::default();
-    for line in stdin().lock().split(delim) {
-        match set.entry(line?) {
-            hash_map::Entry::Occupied(mut e) => {
-                *e.get_mut() += 1;
-            }
-            hash_map::Entry::Vacant(e) => {
-                e.insert(1);
+/// Which records count mode prints, and how
+struct Report {
+    sort: Option<Sort>,
+    /// Print every occurrence of the records, grouped by record
+    groups: Option<Grouping>,
+    /// How to print each record along with its count; `None` prints
+    /// just the records
+    template: Option<Template>,
+    /// Only print records occurring this many times
+    occurrences: RangeInclusive<u64>,
+    /// Only print this many of the most frequent (descending)
+    /// or least frequent (ascending) records
+    top: Option<(usize, Sort)>,
+    /// Count with a [CountMin] sketch instead of exactly; only for `top`
+    approximate: bool,
+    /// Insert the records into this database instead of printing them
+    #[cfg(feature = "sqlite")]
+    sqlite: Option<OsString>,
+}
+
+/// How records of multiple inputs are combined, instead of printing
+/// the records of any input once
+#[derive(Clone, Copy)]
+enum SetOp {
+    /// The records present in all inputs
+    Intersect,
+    /// The records of the first input not present in any other
+    Difference,
+    /// All records, each preceded by the inputs it is present in
+    Membership,
+}
+
+#[derive(Clone, Copy)]
+enum Grouping {
+    /// No separation between the groups
+    Adjacent,
+    /// An empty record between the groups
+    Separate,
+    /// An empty record before each group
+    Prepend,
+    /// An empty record after each group
+    Append,
+    /// An empty record before each group and after the last one
+    Both,
+}
+
+impl Grouping {
+    fn from_name(name: &str) -> Option<Grouping> {
+        match name {
+            "none" => Some(Grouping::Adjacent),
+            "separate" => Some(Grouping::Separate),
+            "prepend" => Some(Grouping::Prepend),
+            "append" => Some(Grouping::Append),
+            "both" => Some(Grouping::Both),
+            _ => None,
+        }
+    }
+}
+
+impl Report {
+    /// Whether the records have to be printed in the order they were first seen
+    fn ordered(&self) -> bool {
+        self.template.is_none() && self.sort.is_none()
+    }
+}
+
+/// Remove duplicates from the inputs and print to the output,
+/// counting the number of occurrences.
+///
+/// With multiple threads, records are counted in shards; see [parallel].
+/// This loses the order the records were first seen in, so it is only
+/// done if the records are printed with their counts.
+fn count_cmd(
+    out: &mut dyn Write,
+    inputs: &[OsString],
+    opts: &Opts,
+    report: &Report,
+    stats: &mut Stats,
+) -> Result<()> {
+    if let (true, Some((n, _))) = (report.approximate, report.top) {
+        return approximate_top(out, inputs, opts, report, n, stats);
+    }
+    let mut set = match opts.threads {
+        1 => count_records(inputs, opts, stats)?,
+        _ if report.ordered() || opts.merge => count_records(inputs, opts, stats)?,
+        threads => {
+            let counted = parallel::count_sharded(
+                inputs,
+                opts.input,
+                &opts.framing,
+                threads,
+                opts.capacity,
+                |line| opts.key(line),
+            )?;
+            match opts.load_state {
+                Some(_) => {
+                    let mut set = load_counts(opts)?;
+                    for (line, count) in counted.iter() {
+                        set.add_count(line, count);
+                    }
+                    set
+                }
+                None => counted,
             }
         }
+    };
+    if let Some(path) = &opts.dump_state {
+        state::write_counts(path, set.len(), set.iter())?;
     }
+    let total = set.iter().map(|(_, count)| count).sum();
+    stats.records += total;
+    stats.unique += set.len() as u64;
 
-    let result = if let Some(sort) = sort {
-        sort_and_print(delim, sort, &set)
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &report.sqlite {
+        let db = sqlite::Database::open(path)?;
+        let rows = set
+            .iter()
+            .filter(|(_, count)| report.occurrences.contains(count));
+        let result = db.insert_counts(rows);
+        mem::forget(set);
+        return result;
+    }
+
+    let out_delim = opts.out_delim();
+    let result = if let Some((n, sort)) = report.top {
+        print_out(out, out_delim, report, total, top(&set, n, sort, report))
+    } else if let Some(sort) = report.sort {
+        sort_and_print(out, out_delim, report, total, sort, &mut set)
     } else {
-        print_out(delim, set.iter().map(|(k, v)| (k.as_slice(), *v)))
+        print_out(out, out_delim, report, total, set.iter())
     };
 
     mem::forget(set); // app can now exit, so we don't need to wait for this memory to be freed piecemeal
@@ -69,75 +558,1089 @@ fn count_cmd(delim: u8, sort: Option<Sort>) -> Result<()> {
     result
 }
 
-type DataAndCount<'a> = (&'a [u8], u64);
+/// Print each record once, preceded by the number of its occurrences
+/// in each input, separated by `sep`; written with `write_record`
+fn per_file_cmd(
+    out: &mut dyn Write,
+    inputs: &[OsString],
+    opts: &Opts,
+    report: &Report,
+    (sep, write_record): (&[u8], RecordWriter),
+    stats: &mut Stats,
+) -> Result<()> {
+    // One count per input for each record, in the order of the records
+    // in set
+    let mut set = Counts::with_capacity(opts.capacity);
+    let mut counts = Vec::with_capacity(opts.capacity * inputs.len());
+    for (idx, path) in inputs.iter().enumerate() {
+        if signal::interrupted() {
+            break;
+        }
+        opts.for_each_record(path, opts.input, |_, key| {
+            let key = match key {
+                Some(key) => key,
+                None => return Ok(!signal::interrupted()),
+            };
+            let pos = set.add(&key);
+            if pos * inputs.len() == counts.len() {
+                counts.resize(counts.len() + inputs.len(), 0u64);
+            }
+            counts[pos * inputs.len() + idx] += 1;
+            Ok(!signal::interrupted())
+        })?;
+    }
+
+    for ((line, total), counts) in set.iter().zip(counts.chunks(inputs.len())) {
+        if !report.occurrences.contains(&total) {
+            continue;
+        }
+        for count in counts {
+            write!(out, "{}", count)?;
+            out.write_all(sep)?;
+        }
+        write_record(out, line)?;
+        out.write_all(opts.out_delim())?;
+    }
+
+    stats.records += set.iter().map(|(_, count)| count).sum::<u64>();
+    stats.unique += set.len() as u64;
+    mem::forget(set); // app can now exit, so we don't need to wait for this memory to be freed piecemeal
+    Ok(())
+}
+
+type RecordWriter = fn(&mut dyn Write, &[u8]) -> io::Result<()>;
+
+/// Print the `n` most frequent records, estimating the counts with
+/// a [CountMin] sketch and tracking just the `n` records with the
+/// highest estimates, instead of counting every distinct record
+fn approximate_top(
+    out: &mut dyn Write,
+    inputs: &[OsString],
+    opts: &Opts,
+    report: &Report,
+    n: usize,
+    stats: &mut Stats,
+) -> Result<()> {
+    let hasher = opts.hasher();
+    let mut sketch = CountMin::new();
+    let mut top = TopK::new(n);
+    let mut distinct = HyperLogLog::new();
+    let mut records = 0;
+    for path in inputs {
+        if signal::interrupted() {
+            break;
+        }
+        opts.for_each_record(path, opts.input, |_, key| {
+            records += 1;
+            let key = match key {
+                Some(key) => key,
+                None => return Ok(!signal::interrupted()),
+            };
+            let h = opts.hash(&hasher, &key);
+            distinct.insert(h);
+            if signal::stats_requested() {
+                let unique = distinct.estimate().round() as u64;
+                stats.print_progress(records, unique, &mut io::stderr())?;
+            }
+            let count = sketch.add(h);
+            if report.occurrences.contains(&count) {
+                top.offer(&key, count);
+            }
+            Ok(!signal::interrupted())
+        })?;
+    }
+    stats.records += records;
+    stats.unique += distinct.estimate().round() as u64;
+
+    let top = top.into_sorted_vec();
+    let data = top.iter().map(|(line, count)| (&line[..], *count));
+    print_out(out, opts.out_delim(), report, records, data)
+}
+
+/// Count the occurrences of each record in the inputs
+fn count_records(inputs: &[OsString], opts: &Opts, stats: &Stats) -> Result<Counts> {
+    let mut set = load_counts(opts)?;
+    let mut records = 0;
+    for path in inputs {
+        if signal::interrupted() {
+            break;
+        }
+        opts.for_each_record(path, opts.input, |line, key| {
+            match opts.merge {
+                true => {
+                    let key = opts.framing.key(line);
+                    let (count, key) = parse_count(key).ok_or_else(|| {
+                        let msg = format!("not a count and a record: `{}`", key.as_bstr());
+                        io::Error::new(io::ErrorKind::InvalidData, msg)
+                    })?;
+                    set.add_count(key, count);
+                }
+                false => {
+                    if let Some(key) = key {
+                        set.add(&key);
+                    }
+                }
+            };
+            records += 1;
+            if signal::stats_requested() {
+                stats.print_progress(records, set.len() as u64, &mut io::stderr())?;
+            }
+            Ok(!signal::interrupted())
+        })?;
+    }
+    Ok(set)
+}
+
+/// Split a record printed by count mode into the count and the
+/// original record, like `   3 foo`
+fn parse_count(line: &[u8]) -> Option<(u64, &[u8])> {
+    let line = &line[line.iter().take_while(|&&b| b == b' ').count()..];
+    let digits = line.iter().take_while(|b| b.is_ascii_digit()).count();
+    let count = line[..digits].to_str().ok()?.parse().ok()?;
+    match line.get(digits) {
+        Some(b' ') | Some(b'\t') => Some((count, &line[digits + 1..])),
+        _ => None,
+    }
+}
+
+/// The counts of a previous run, if any are to be loaded
+fn load_counts(opts: &Opts) -> Result<Counts> {
+    let mut set = Counts::with_capacity(opts.capacity);
+    if let Some(path) = &opts.load_state {
+        state::read_counts(path, |line, count| {
+            set.add_count(line, count);
+        })?;
+    }
+    Ok(set)
+}
+
+/// Print all occurrences of each record, grouped by record in the
+/// order the records were first seen.
+fn group_cmd(
+    out: &mut dyn Write,
+    inputs: &[OsString],
+    opts: &Opts,
+    report: &Report,
+    grouping: Grouping,
+    stats: &mut Stats,
+) -> Result<()> {
+    let mut groups = Groups::with_capacity(opts.capacity);
+    for path in inputs {
+        opts.for_each_record(path, opts.input, |line, key| {
+            if let Some(key) = key {
+                groups.add(&key, line);
+            }
+            Ok(true)
+        })?;
+    }
+
+    let mut first = true;
+    for group in groups.iter() {
+        stats.records += group.len();
+        stats.unique += 1;
+        if !report.occurrences.contains(&group.len()) {
+            continue;
+        }
+        match grouping {
+            Grouping::Separate if !first => out.write_all(opts.out_delim())?,
+            Grouping::Prepend | Grouping::Both => out.write_all(opts.out_delim())?,
+            _ => {}
+        }
+        first = false;
+        for record in group.records() {
+            opts.write_record(out, record)?;
+        }
+        if let Grouping::Append = grouping {
+            out.write_all(opts.out_delim())?;
+        }
+    }
+    if let (Grouping::Both, false) = (grouping, first) {
+        out.write_all(opts.out_delim())?;
+    }
+
+    mem::forget(groups); // app can now exit, so we don't need to wait for this memory to be freed piecemeal
 
-/// Sorts the lines by occurence, then prints them
-// TODO: this could be done more efficiently by reusing the memory of the HashMap
-fn sort_and_print(delim: u8, sort: Sort, set: &HashMap<Vec<u8>, u64, ARandomState>) -> Result<()> {
-    let mut seq: Vec<DataAndCount> = set.iter().map(|(k, v)| (k.as_slice(), *v)).collect();
+    Ok(())
+}
+
+type DataAndCount<'a> = (&'a [u8], u64);
 
-    let comparator: fn(&DataAndCount, &DataAndCount) -> Ordering = match sort {
-        Sort::Ascending => |a, b| a.1.cmp(&b.1),
-        Sort::Descending => |a, b| b.1.cmp(&a.1),
+/// Sorts the lines by occurence in place, then prints them
+fn sort_and_print(
+    out: &mut dyn Write,
+    delim: &[u8],
+    report: &Report,
+    total: u64,
+    sort: Sort,
+    set: &mut Counts,
+) -> Result<()> {
+    let comparator: fn(u64, u64) -> Ordering = match sort {
+        Sort::Ascending => |a, b| a.cmp(&b),
+        Sort::Descending => |a, b| b.cmp(&a),
     };
-    seq.as_mut_slice().sort_by(comparator);
-    print_out(delim, seq)
+    set.sort_by(comparator);
+    print_out(out, delim, report, total, set.iter())
 }
 
-/// Prints the sequence of counts and data items, separated by delim
-fn print_out<'a, I>(delim: u8, data: I) -> Result<()>
+/// The `n` first records when sorted by occurrence.
+///
+/// Uses a heap of `n` records instead of sorting all records.
+fn top<'a>(set: &'a Counts, n: usize, sort: Sort, report: &Report) -> Vec<DataAndCount<'a>> {
+    let mut heap = BinaryHeap::with_capacity(n + 1);
+    for (line, count) in set.iter() {
+        if !report.occurrences.contains(&count) {
+            continue;
+        }
+        // The record to drop first is at the top of the heap
+        let rank = match sort {
+            Sort::Ascending => count,
+            Sort::Descending => u64::MAX - count,
+        };
+        heap.push((rank, line, count));
+        if heap.len() > n {
+            heap.pop();
+        }
+    }
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|(_, line, count)| (line, count))
+        .collect()
+}
+
+/// Prints the sequence of counts and data items, separated by delim.
+///
+/// The total is the number of all records, for percentages.
+fn print_out<'a, I>(
+    out: &mut dyn Write,
+    delim: &[u8],
+    report: &Report,
+    total: u64,
+    data: I,
+) -> Result<()>
 where
     I: IntoIterator<Item = DataAndCount<'a>>,
 {
-    let out = stdout();
-    let mut out = out.lock();
+    let (mut rank, mut sum) = (0, 0);
     for (line, count) in data {
-        write!(out, "{} ", count)?;
-        out.write_all(line)?;
-        out.write_all(slice::from_ref(&delim))?;
+        if !report.occurrences.contains(&count) {
+            continue;
+        }
+        rank += 1;
+        sum += count;
+        match &report.template {
+            Some(template) => {
+                let row = Row {
+                    line,
+                    count,
+                    rank,
+                    sum,
+                    total,
+                };
+                template.write(out, &row)?;
+            }
+            None => out.write_all(line)?,
+        }
+        out.write_all(delim)?;
     }
 
     Ok(())
 }
 
-/// Remove duplicates from stdin and print to stdout.
-fn uniq_cmd(delim: u8, include_trailing: bool) -> Result<()> {
+/// Remove duplicates from the inputs and print to the output.
+///
+/// The set of seen records is shared between all inputs, so a record
+/// is printed only once even if it occurs in multiple inputs.
+///
+/// Records suppressed as duplicates are written to `dupes`, if given.
+///
+/// When following, the last input is read indefinitely and each
+/// unique record is flushed to the output as soon as it is encountered.
+///
+/// With multiple threads, records are hashed in parallel; see [parallel].
+fn uniq_cmd(
+    out: &mut dyn Write,
+    mut dupes: Option<&mut dyn Write>,
+    inputs: &[OsString],
+    opts: &Opts,
+    stats: &mut Stats,
+) -> Result<()> {
     // Line processing/output ///////////////////////
-    let out = stdout();
-    let inp = stdin();
-    let hasher = ARandomState::new();
-    let mut out = out.lock();
-    let mut set = HashSet::<u64, BuildHasherDefault<IdentityHasher>>::default();
-
-    inp.lock().for_byte_record_with_terminator(delim, |line| {
-        let tok = trim_end(line, delim);
-        if set.insert(hash(&hasher, &tok)) {
-            out.write_all(line)?;
+    let Opts {
+        ref framing,
+        input,
+        threads,
+        capacity,
+        backend,
+        spill: spill_after,
+        max_memory,
+        reset_every,
+        reset_interval,
+        ..
+    } = *opts;
+    let mut set = match (&opts.connect, &opts.store) {
+        (Some(path), _) => Seen::Remote(Client::connect(path)?),
+        (_, Some(path)) => Seen::Store(Store::open(path)?),
+        _ => Seen::new(backend, capacity),
+    };
+    let mut seeds = None;
+    match &set {
+        Seen::Remote(client) => seeds = Some(client.seeds()),
+        Seen::Store(store) => seeds = Some(store.seeds()),
+        _ => {}
+    }
+    if let (Some(path), Seen::Exact(hashes)) = (&opts.load_state, &mut set) {
+        seeds = state::read_seen(path, |hash| {
+            hashes.insert(hash);
+        })?;
+        if let Some(seeds) = seeds {
+            opts.check_seeds(seeds)?;
+        }
+    }
+    // Seeded explicitly, so the hashes can be saved for later runs
+    let seeds = seeds.or(opts.seeds).unwrap_or_else(state::random_seeds);
+    let hasher = opts.algorithm.hasher(seeds);
+    // Keys close to one seen are hashed like that one; locked only to
+    // share it, as the records are read on one thread then
+    let typos = opts.max_edit_distance.map(|n| Mutex::new(BkTree::new(n)));
+    // The keys themselves, numbered in the order seen, so they stand in
+    // for hashes that never collide
+    let exact = opts.exact.then(|| Mutex::new(Counts::default()));
+    // For the upper half of wide hashes, and telling apart records
+    // with the same hash
+    let high_hasher = opts.algorithm.hasher(hasher::other_seeds(seeds));
+    // A second, independent hash for each hash seen, which differs for
+    // different records with the same hash; and whether that was reported
+    type Checks = HashMap<u64, (u64, bool), BuildHasherDefault<IdentityHasher>>;
+    let checks = opts
+        .detect_collisions
+        .then(|| Mutex::new(Checks::default()));
+    let hash_key = |key: &[u8]| {
+        let mut typos = typos.as_ref().map(|typos| typos.lock().unwrap());
+        let key = match typos.as_mut() {
+            Some(typos) => typos.find_or_insert(key),
+            None => key,
+        };
+        match (backend, &exact) {
+            // Near duplicates are found by the fingerprints of the keys
+            (Backend::Similar(_), _) => u128::from(similar::simhash(key)),
+            // The number of the key is put into the set as it is
+            (_, Some(exact)) => u128::from(exact.lock().unwrap().add(key) as u64),
+            (Backend::Wide, _) => {
+                u128::from(hash(&high_hasher, key)) << 64 | u128::from(hash(&hasher, key))
+            }
+            _ => {
+                let h = opts.hash(&hasher, key);
+                if let Some(checks) = &checks {
+                    let check = hash(&high_hasher, key);
+                    let mut checks = checks.lock().unwrap();
+                    let (seen, reported) = checks.entry(h).or_insert((check, false));
+                    if *seen != check && !*reported {
+                        *reported = true;
+                        let _ = writeln!(
+                            io::stderr(),
+                            "huniq: warning: `{}` has the same hash as another record, \
+                             so it is taken for a duplicate",
+                            key.as_bstr()
+                        );
+                    }
+                }
+                u128::from(h)
+            }
+        }
+    };
+    let mut lines = match &opts.dump_lines {
+        Some(path) => Some(Output::open(Some(path), None, DEFAULT_BUFFER_SIZE)?),
+        None => None,
+    };
+    for path in &opts.filter_files {
+        let input = InputOpts {
+            follow: false,
+            ..input
+        };
+        opts.for_each_record(path, input, |line, key| {
+            let h = match key {
+                Some(key) => hash_key(&key),
+                None => return Ok(true),
+            };
+            if let (true, Some(lines)) = (set.insert(h)?, &mut lines) {
+                opts.write_terminated(lines, line)?;
+            }
+            Ok(true)
+        })?;
+    }
 
-            if include_trailing && tok.len() == line.len() {
-                out.write_all(&[delim])?;
+    let (mut records, mut unique) = (0, 0);
+    let mut spill: Option<Spill> = None;
+    let (mut since_reset, mut last_reset) = (0, Instant::now());
+    let progress = &*stats;
+    let passes = opts.key.passes_unmatched();
+    let mut emit = |line: &[u8], hash: Option<u128>, follow: bool| -> io::Result<()> {
+        records += 1;
+        if signal::stats_requested() {
+            progress.print_progress(records, unique, &mut io::stderr())?;
+        }
+        let mut hash = match hash {
+            Some(hash) => hash,
+            // Printed right away, even ahead of records spilled
+            None if passes => {
+                opts.write_record(out, line)?;
+                if follow {
+                    out.flush()?;
+                }
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        // All but the wide hashes are just the lower half
+        let mut narrow = hash as u64;
+        if let Some(spill) = spill.as_mut() {
+            return spill.add(line, narrow);
+        }
+        let lines_due = reset_every == Some(since_reset);
+        let time_due = reset_interval.is_some_and(|ttl| last_reset.elapsed() >= ttl);
+        let due = lines_due || time_due || signal::reset_requested();
+        // Shared and stored sets are kept
+        if due && !matches!(set, Seen::Remote(_) | Seen::Store(_)) {
+            set = Seen::new(backend, capacity);
+            // The keys are forgotten too and numbered afresh, starting
+            // with the key of this record
+            if let Some(exact) = &exact {
+                let mut exact = exact.lock().unwrap();
+                let before = mem::take(&mut *exact);
+                hash = u128::from(exact.add(before.record(narrow as usize)) as u64);
+                narrow = hash as u64;
+            }
+            if let Some(checks) = &checks {
+                *checks.lock().unwrap() = Checks::default();
             }
+            since_reset = 0;
+            last_reset = Instant::now();
+        }
+        since_reset += 1;
+        if let (Seen::Exact(hashes), Some((max, overflow))) = (&mut set, max_memory) {
+            // Growing doubles the table, which takes a byte of metadata
+            // along with each hash and is at most 7/8 full
+            let grown = (hashes.capacity() + 1) * 2 * 9 * 8 / 7;
+            if hashes.len() == hashes.capacity() && grown > max && !hashes.contains(&narrow) {
+                match overflow {
+                    Overflow::Abort => {
+                        return Err(io::Error::other(format!(
+                            "more than {} distinct records do not fit into --max-memory",
+                            hashes.len()
+                        )))
+                    }
+                    Overflow::Spill => {
+                        let spilling = spill.insert(Spill::new(hashes.drain(), max / 16)?);
+                        return spilling.add(line, narrow);
+                    }
+                    Overflow::Approximate => {
+                        set = Seen::Bloom(Bloom::with_memory(max, hashes.drain()));
+                    }
+                }
+            }
+        }
+        let first = set.insert(hash)?;
+        if let (Seen::Exact(hashes), Some(limit)) = (&mut set, spill_after) {
+            if first && hashes.len() > limit {
+                // Not printed yet, so this is the first record spilled
+                hashes.remove(&narrow);
+                let spilling = spill.insert(Spill::new(hashes.drain(), limit)?);
+                return spilling.add(line, narrow);
+            }
+        }
+        if let (true, Some(lines)) = (first, &mut lines) {
+            opts.write_terminated(lines, line)?;
+        }
+        let sink: &mut dyn Write = match first {
+            true => {
+                unique += 1;
+                &mut *out
+            }
+            false => match dupes.as_mut() {
+                Some(dupes) => &mut **dupes,
+                None => return Ok(()),
+            },
+        };
+
+        opts.write_hashed(sink, line, narrow)?;
+        if follow {
+            sink.flush()?;
         }
-        Ok(true)
-    })?;
+        Ok(())
+    };
+
+    if threads > 1 && !input.follow {
+        let hash_record = |line: &[u8]| opts.key(line).map(|key| hash_key(&key));
+        parallel::for_each_hashed_record(
+            inputs,
+            input,
+            framing,
+            threads,
+            hash_record,
+            |line, h| Ok(emit(line, h, false)?),
+        )?;
+    } else {
+        for (idx, path) in inputs.iter().enumerate() {
+            let follow = input.follow && idx + 1 == inputs.len();
+            let input = InputOpts { follow, ..input };
+            opts.for_each_record(path, input, |line, key| {
+                emit(line, key.map(|key| hash_key(&key)), follow)?;
+                Ok(true)
+            })?;
+        }
+    }
+
+    if let Some(spill) = spill {
+        spill.finish(|line, hash, first| {
+            if let (true, Some(lines)) = (first, &mut lines) {
+                opts.write_terminated(lines, line)?;
+            }
+            let sink: &mut dyn Write = match first {
+                true => {
+                    unique += 1;
+                    &mut *out
+                }
+                false => match dupes.as_mut() {
+                    Some(dupes) => &mut **dupes,
+                    None => return Ok(()),
+                },
+            };
+            opts.write_hashed(sink, line, hash)
+        })?;
+    }
 
+    stats.records += records;
+    stats.unique += unique;
+    if let Some(lines) = lines {
+        lines.commit()?;
+    }
+    if let (Some(path), Seen::Exact(hashes)) = (&opts.dump_state, &set) {
+        state::write_seen(path, seeds, hashes.len(), hashes.iter().copied())?;
+    }
     mem::forget(set); // app can now exit, so we don't need to wait for this memory to be freed piecemeal
 
     Ok(())
 }
 
-fn trim_end(record: &[u8], delim: u8) -> &[u8] {
-    match record.last_byte() {
-        Some(b) if b == delim => &record[..record.len() - 1],
-        _ => record,
+/// The records seen when removing duplicates
+enum Seen {
+    Exact(HashSet<u64, BuildHasherDefault<IdentityHasher>>),
+    Wide(HashSet<u128, BuildHasherDefault<IdentityHasher>>),
+    Bloom(Bloom),
+    Cuckoo(Cuckoo),
+    Window(Lru<BuildHasherDefault<IdentityHasher>>),
+    Last(Recent<BuildHasherDefault<IdentityHasher>>),
+    Expire(Expiring<HashSet<u64, BuildHasherDefault<IdentityHasher>>>),
+    ExpireCuckoo(Expiring<Cuckoo>),
+    Similar(Similar),
+    Remote(Client),
+    Store(Store<BuildHasherDefault<IdentityHasher>>),
+}
+
+impl Seen {
+    fn new(backend: Backend, capacity: usize) -> Seen {
+        // The filters are sized for a hundred million records unless told otherwise
+        let filter_capacity = if capacity == 0 { 100_000_000 } else { capacity };
+        match backend {
+            Backend::HashSet => Seen::Exact(HashSet::with_capacity_and_hasher(
+                capacity,
+                Default::default(),
+            )),
+            Backend::Wide => Seen::Wide(HashSet::with_capacity_and_hasher(
+                capacity,
+                Default::default(),
+            )),
+            Backend::Bloom(fpr) => Seen::Bloom(Bloom::new(filter_capacity, fpr)),
+            Backend::Cuckoo => Seen::Cuckoo(Cuckoo::new(filter_capacity)),
+            Backend::Window(n) => Seen::Window(Lru::new(n)),
+            Backend::Last(n) => Seen::Last(Recent::new(n)),
+            Backend::Expire(ttl) => Seen::Expire(Expiring::new(HashSet::default(), ttl)),
+            Backend::ExpireCuckoo(ttl) => {
+                Seen::ExpireCuckoo(Expiring::new(Cuckoo::new(filter_capacity), ttl))
+            }
+            Backend::Similar(threshold) => Seen::Similar(Similar::new(threshold)),
+        }
+    }
+
+    /// Remember the hash, returning whether it was not seen before;
+    /// all but the set of wide hashes take just the lower half
+    fn insert(&mut self, hash: u128) -> io::Result<bool> {
+        let narrow = hash as u64;
+        let full = |_| io::Error::other("cuckoo filter is full; pass a larger --expected-lines");
+        match self {
+            Seen::Exact(set) => Ok(set.insert(narrow)),
+            Seen::Wide(set) => Ok(set.insert(hash)),
+            Seen::Bloom(bloom) => Ok(bloom.insert(narrow)),
+            Seen::Cuckoo(cuckoo) => cuckoo.insert(narrow).map_err(full),
+            Seen::Window(lru) => Ok(lru.insert(narrow)),
+            Seen::Last(recent) => Ok(recent.insert(narrow)),
+            Seen::Expire(expiring) => expiring.insert(narrow).map_err(full),
+            Seen::ExpireCuckoo(expiring) => expiring.insert(narrow).map_err(full),
+            Seen::Similar(similar) => Ok(similar.insert(narrow)),
+            Seen::Remote(client) => client.insert(narrow),
+            Seen::Store(store) => store.insert(narrow),
+        }
+    }
+}
+
+/// The records seen when counting distinct records
+enum Distinct {
+    Exact(HashSet<u64, BuildHasherDefault<IdentityHasher>>),
+    Estimate(HyperLogLog),
+}
+
+impl Distinct {
+    fn insert(&mut self, hash: u64) {
+        match self {
+            Distinct::Exact(set) => {
+                set.insert(hash);
+            }
+            Distinct::Estimate(hll) => hll.insert(hash),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            Distinct::Exact(set) => set.len() as u64,
+            Distinct::Estimate(hll) => hll.estimate().round() as u64,
+        }
     }
 }
 
-fn try_main() -> Result<()> {
-    let argspec = Command::new("huniq")
+/// Print the distinct records combined from the inputs as given.
+///
+/// An intersection is printed in the order of the last input, as each
+/// record is known to be in all inputs once it is read from that one.
+fn set_cmd(
+    out: &mut dyn Write,
+    inputs: &[OsString],
+    opts: &Opts,
+    op: SetOp,
+    stats: &mut Stats,
+) -> Result<()> {
+    let input = opts.input;
+    let hasher = opts.hasher();
+    let (mut records, mut unique) = (0, 0);
+    match op {
+        SetOp::Intersect => {
+            // The number of inputs each record was found in, as long
+            // as it was found in all previous inputs
+            let mut found: HashMap<u64, usize, BuildHasherDefault<IdentityHasher>> =
+                HashMap::with_capacity_and_hasher(opts.capacity, Default::default());
+            for (idx, path) in inputs.iter().enumerate() {
+                opts.for_each_record(path, input, |line, key| {
+                    records += 1;
+                    let h = match key {
+                        Some(key) => opts.hash(&hasher, &key),
+                        None => return Ok(true),
+                    };
+                    let inputs_found = match idx {
+                        0 => found.entry(h).or_insert(0),
+                        _ => match found.get_mut(&h) {
+                            Some(n) => n,
+                            None => return Ok(true),
+                        },
+                    };
+                    if *inputs_found != idx {
+                        return Ok(true);
+                    }
+                    *inputs_found += 1;
+                    if idx + 1 == inputs.len() {
+                        unique += 1;
+                        opts.write_record(out, line)?;
+                    }
+                    Ok(true)
+                })?;
+            }
+            mem::forget(found); // app can now exit, so we don't need to wait for this memory to be freed piecemeal
+        }
+        SetOp::Difference => {
+            let mut seen: HashSet<u64, BuildHasherDefault<IdentityHasher>> =
+                HashSet::with_capacity_and_hasher(opts.capacity, Default::default());
+            for path in inputs.iter().skip(1) {
+                opts.for_each_record(path, input, |_, key| {
+                    records += 1;
+                    if let Some(key) = key {
+                        seen.insert(opts.hash(&hasher, &key));
+                    }
+                    Ok(true)
+                })?;
+            }
+            if let Some(path) = inputs.first() {
+                opts.for_each_record(path, input, |line, key| {
+                    records += 1;
+                    let first = match key {
+                        Some(key) => seen.insert(opts.hash(&hasher, &key)),
+                        None => false,
+                    };
+                    if first {
+                        unique += 1;
+                        opts.write_record(out, line)?;
+                    }
+                    Ok(true)
+                })?;
+            }
+            mem::forget(seen); // app can now exit, so we don't need to wait for this memory to be freed piecemeal
+        }
+        SetOp::Membership => {
+            // One bit per input for each record, in the order of the
+            // records in set
+            let words = inputs.len().div_ceil(64);
+            let mut set = Counts::with_capacity(opts.capacity);
+            let mut present = Vec::with_capacity(opts.capacity * words);
+            for (idx, path) in inputs.iter().enumerate() {
+                opts.for_each_record(path, input, |_, key| {
+                    records += 1;
+                    let pos = match key {
+                        Some(key) => set.add(&key),
+                        None => return Ok(true),
+                    };
+                    if pos * words == present.len() {
+                        present.resize(present.len() + words, 0u64);
+                    }
+                    present[pos * words + idx / 64] |= 1 << (idx % 64);
+                    Ok(true)
+                })?;
+            }
+            for (record, bits) in set.iter().zip(present.chunks(words)) {
+                let mut names = inputs
+                    .iter()
+                    .enumerate()
+                    .filter(|(idx, _)| bits[idx / 64] & (1 << (idx % 64)) != 0);
+                if let Some((_, name)) = names.next() {
+                    out.write_all(name.as_bytes())?;
+                }
+                for (_, name) in names {
+                    out.write_all(b",")?;
+                    out.write_all(name.as_bytes())?;
+                }
+                out.write_all(b"\t")?;
+                opts.write_record(out, record.0)?;
+            }
+            unique = set.len() as u64;
+            mem::forget(set); // app can now exit, so we don't need to wait for this memory to be freed piecemeal
+        }
+    }
+
+    stats.records += records;
+    stats.unique += unique;
+    Ok(())
+}
+
+/// Print how the counts in the inputs changed since the baseline,
+/// reading both like [Opts::merge]; records with the same count in
+/// both are left out.
+///
+/// Records no longer present follow those in the inputs.
+fn subtract_cmd(
+    out: &mut dyn Write,
+    inputs: &[OsString],
+    baseline: &OsStr,
+    opts: &Opts,
+    report: &Report,
+    (sep, count_last): (&str, bool),
+    stats: &mut Stats,
+) -> Result<()> {
+    let current = count_records(inputs, opts, stats)?;
+    let before = count_records(&[OsString::from(baseline)], opts, stats)?;
+    let mut remaining: HashMap<&[u8], u64, ARandomState> = before.iter().collect();
+
+    let mut deltas = Vec::new();
+    for (line, count) in current.iter() {
+        let prior = remaining.remove(line).unwrap_or(0);
+        if count != prior {
+            deltas.push((count as i128 - prior as i128, line));
+        }
+    }
+    for (line, prior) in before.iter() {
+        if remaining.contains_key(line) {
+            deltas.push((-(prior as i128), line));
+        }
+    }
+    match report.sort {
+        Some(Sort::Ascending) => deltas.sort_by_key(|&(delta, _)| delta),
+        Some(Sort::Descending) => deltas.sort_by_key(|&(delta, _)| -delta),
+        None => {}
+    }
+
+    for (delta, line) in deltas {
+        match count_last {
+            true => {
+                out.write_all(line)?;
+                write!(out, "{}{:+}", sep, delta)?;
+            }
+            false => {
+                write!(out, "{:+}{}", delta, sep)?;
+                out.write_all(line)?;
+            }
+        }
+        out.write_all(opts.out_delim())?;
+    }
+
+    stats.records += current.iter().map(|(_, count)| count).sum::<u64>();
+    stats.unique += current.len() as u64;
+    mem::forget(remaining);
+    mem::forget((current, before)); // app can now exit, so we don't need to wait for this memory to be freed piecemeal
+
+    Ok(())
+}
+
+/// Print for each record whether it was seen by the run that saved
+/// the state, without changing the state.
+///
+/// Returns whether all records were seen.
+fn query_cmd(
+    out: &mut dyn Write,
+    inputs: &[OsString],
+    opts: &Opts,
+    path: &OsStr,
+    stats: &mut Stats,
+) -> Result<bool> {
+    let input = opts.input;
+    let mut seen: HashSet<u64, BuildHasherDefault<IdentityHasher>> = HashSet::default();
+    let seeds = state::read_seen(path, |hash| {
+        seen.insert(hash);
+    })?
+    .ok_or_else(|| anyhow!("no state {}", path.to_string_lossy()))?;
+    opts.check_seeds(seeds)?;
+    let hasher = opts.algorithm.hasher(seeds);
+
+    let (mut records, mut all_seen) = (0, true);
+    for path in inputs {
+        opts.for_each_record(path, input, |line, key| {
+            records += 1;
+            let found = match key {
+                Some(key) => seen.contains(&opts.hash(&hasher, &key)),
+                None => return Ok(true),
+            };
+            all_seen &= found;
+            out.write_all(if found { b"seen\t" } else { b"new\t" })?;
+            opts.write_record(out, line)?;
+            if input.follow {
+                out.flush()?;
+            }
+            Ok(true)
+        })?;
+    }
+
+    stats.records += records;
+    mem::forget(seen); // app can now exit, so we don't need to wait for this memory to be freed piecemeal
+
+    Ok(all_seen)
+}
+
+/// Print just the number of distinct records in the inputs, which
+/// is only estimated with a [HyperLogLog] if `estimate` is given
+fn distinct_cmd(
+    out: &mut dyn Write,
+    inputs: &[OsString],
+    opts: &Opts,
+    estimate: bool,
+    stats: &mut Stats,
+) -> Result<()> {
+    let Opts {
+        ref framing,
+        input,
+        threads,
+        capacity,
+        ..
+    } = *opts;
+    let hasher = opts.hasher();
+    let mut set = match estimate {
+        true => Distinct::Estimate(HyperLogLog::new()),
+        false => Distinct::Exact(HashSet::with_capacity_and_hasher(
+            capacity,
+            Default::default(),
+        )),
+    };
+    let mut records = 0;
+
+    if threads > 1 {
+        let hash_record = |line: &[u8]| opts.key(line).map(|key| opts.hash(&hasher, &key));
+        parallel::for_each_hashed_record(inputs, input, framing, threads, hash_record, |_, h| {
+            records += 1;
+            if let Some(h) = h {
+                set.insert(h);
+            }
+            Ok(())
+        })?;
+    } else {
+        for path in inputs {
+            opts.for_each_record(path, input, |_, key| {
+                records += 1;
+                if let Some(key) = key {
+                    set.insert(opts.hash(&hasher, &key));
+                }
+                if signal::stats_requested() {
+                    stats.print_progress(records, set.len(), &mut io::stderr())?;
+                }
+                Ok(true)
+            })?;
+        }
+    }
+    writeln!(out, "{}", set.len())?;
+
+    stats.records += records;
+    stats.unique += set.len();
+    mem::forget(set); // app can now exit, so we don't need to wait for this memory to be freed piecemeal
+
+    Ok(())
+}
+
+/// Flags that select count mode
+const COUNT_FLAGS: [&str; 20] = [
+    "count",
+    "sort",
+    "sort-descending",
+    "repeated",
+    "unique-only",
+    "all-repeated",
+    "group",
+    "min-count",
+    "max-count",
+    "top",
+    "bottom",
+    "format",
+    "uniq-compat",
+    "cumulative",
+    "json",
+    "csv",
+    "tsv",
+    "merge",
+    "subtract",
+    "per-file",
+];
+
+/// Flags that count the distinct records instead of printing them
+const DISTINCT_FLAGS: [&str; 2] = ["count-distinct", "estimate"];
+
+/// Ways of remembering the records seen other than a hash set of their
+/// hashes
+const BACKEND_FLAGS: [&str; 7] = [
+    "bloom",
+    "cuckoo",
+    "window",
+    "last",
+    "expire",
+    "spill-after",
+    "max-memory",
+];
+
+/// Options saving, loading or sharing the hashes of the records seen
+const SHARED_FLAGS: [&str; 6] = [
+    "load-state",
+    "dump-state",
+    "store",
+    "connect",
+    "serve",
+    "query",
+];
+
+/// Ways of splitting the inputs into records; each conflicts with
+/// those before it
+const FRAMING_FLAGS: [&str; 6] = [
+    "delimiter",
+    "null",
+    "crlf",
+    "paragraph",
+    "record-size",
+    "framing",
+];
+
+/// Ways of selecting the key of records; the later ones conflict with
+/// those before them
+const KEY_FLAGS: [&str; 9] = [
+    "field",
+    "skip-fields",
+    "skip-chars",
+    "check-chars",
+    "csv-key",
+    "key-bytes",
+    "key-regex",
+    "key-script",
+    "key-command",
+];
+
+/// Formats of the counts; json, csv and tsv conflict with those before
+/// them
+const FORMAT_FLAGS: [&str; 8] = [
+    "format",
+    "uniq-compat",
+    "count-tab",
+    "count-last",
+    "cumulative",
+    "json",
+    "csv",
+    "tsv",
+];
+
+/// Modes that don't keep an exact set of the records seen, or their counts
+const STATE_CONFLICTS: [&str; 13] = [
+    "group",
+    "all-repeated",
+    "approximate-counts",
+    "count-distinct",
+    "estimate",
+    "bloom",
+    "cuckoo",
+    "window",
+    "last",
+    "expire",
+    "spill-after",
+    "max-memory",
+    "in-place",
+];
+
+/// Options of removing duplicates that don't apply to set operations
+const SET_CONFLICTS: [&str; 29] = [
+    "count-distinct",
+    "estimate",
+    "bloom",
+    "cuckoo",
+    "window",
+    "last",
+    "expire",
+    "spill-after",
+    "max-memory",
+    "reset-every",
+    "reset-interval",
+    "follow",
+    "in-place",
+    "dupes-out",
+    "load-state",
+    "dump-state",
+    "store",
+    "connect",
+    "serve",
+    "query",
+    "filter-file",
+    "dump-lines",
+    "threads",
+    "fuzzy",
+    "max-edit-distance",
+    "print-hash",
+    "exact",
+    "hash-bits",
+    "detect-collisions",
+];
+
+/// The command line arguments
+fn argspec() -> Command<'static> {
+    let command = Command::new("huniq")
         .version(env!("CARGO_PKG_VERSION"))
         .about("Remove duplicates from stdin, using a hash table")
-        .author("Karolin Varner <karo@cupdev.net)")
+        .author("Karolin Varner <karo@cupdev.net)");
+    let command = count_args(command);
+    let command = record_args(command);
+    let command = output_args(command);
+    let command = input_args(command);
+    let command = uniq_args(command);
+    set_args(command)
+}
+
+/// Options of counting the records
+fn count_args(command: Command<'static>) -> Command<'static> {
+    let command = command
         .arg(
             Arg::new("count")
                 .help("Output the amount of times a line was encountered")
@@ -157,44 +1660,1430 @@ fn try_main() -> Result<()> {
                 .short('S'),
         )
         .arg(
-            Arg::new("delimiter")
-                .help("Which delimiter between elements to use. By default `\n` is used")
-                .long("delimiter")
-                .long("delim")
-                .short('d')
-                .takes_value(true)
-                .default_value("\n")
-                .validator(|v| match v.len() {
-                    1 => Ok(()),
-                    _ => Err(String::from(
-                        "\
-Only ascii characters are supported as delimiters. \
-Use sed to turn your delimiter into zero bytes?
-
-    $ echo -n \"1λ1λ2λ3\" | sed 's@λ@\x00@g' | huniq -0 | sed 's@\x00@λ@g'
-    1λ2λ3λ",
-                    )),
-                }),
+            Arg::new("repeated")
+                .help("Only output records occurring more than once, like `uniq -d`")
+                .long("repeated"),
         )
         .arg(
-            Arg::new("null")
-                .help("Use the \\0 character as the record delimiter.")
+            Arg::new("unique-only")
+                .help("Only output records occurring exactly once, like `uniq -u`")
+                .long("unique-only")
+                .conflicts_with("repeated"),
+        )
+        .arg(
+            Arg::new("top")
+                .help(
+                    "Output only the N most frequent records with their counts, \
+most frequent first",
+                )
+                .long("top")
+                .takes_value(true)
+                .value_name("N")
+                .conflicts_with_all(&["sort", "sort-descending"]),
+        )
+        .arg(
+            Arg::new("approximate-counts")
+                .help(
+                    "Estimate the counts for --top with a count-min sketch of a fixed size, \
+for inputs with more distinct records than fit in memory; counts may be too high",
+                )
+                .long("approximate-counts"),
+        )
+        .arg(
+            Arg::new("bottom")
+                .help(
+                    "Output only the N least frequent records with their counts, \
+least frequent first",
+                )
+                .long("bottom")
+                .takes_value(true)
+                .value_name("N")
+                .conflicts_with_all(&["sort", "sort-descending", "top"]),
+        )
+        .arg(
+            Arg::new("format")
+                .help(
+                    "Output each record in count mode according to the template, \
+e.g. `{count}\\t{line}`. The placeholders are {count}, {percent}, {cumulative} \
+(percentages of all records), {rank} and {line}",
+                )
+                .long("format")
+                .takes_value(true)
+                .allow_invalid_utf8(true)
+                .conflicts_with("cumulative"),
+        )
+        .arg(
+            Arg::new("uniq-compat")
+                .help("Output counts right-aligned like `sort | uniq -c` does")
+                .long("uniq-compat")
+                .conflicts_with_all(&["format", "cumulative"]),
+        )
+        .arg(
+            Arg::new("count-tab")
+                .help("Separate the count from the record with a tab instead of a space")
+                .long("count-tab")
+                .conflicts_with_all(&["format", "uniq-compat"]),
+        )
+        .arg(
+            Arg::new("count-last")
+                .help("Output the count after the record instead of before it")
+                .long("count-last")
+                .conflicts_with_all(&["format", "uniq-compat"]),
+        )
+        .arg(
+            Arg::new("json")
+                .help(
+                    "Output each record and its count as a JSON object per line; \
+records that are not valid UTF-8 are written with replacement \
+characters (lossy) or as an array of bytes (bytes)",
+                )
+                .long("json")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(["lossy", "bytes"])
+                .conflicts_with_all(&FORMAT_FLAGS[..5]),
+        )
+        .arg(
+            Arg::new("csv")
+                .help("Output the counts and records as CSV, quoting records as needed")
+                .long("csv")
+                .conflicts_with_all(&FORMAT_FLAGS[..6]),
+        )
+        .arg(
+            Arg::new("tsv")
+                .help(
+                    "Output the counts and records as TSV; tabs, \
+line breaks and backslashes are escaped like `\\t`",
+                )
+                .long("tsv")
+                .conflicts_with_all(&FORMAT_FLAGS[..7]),
+        )
+        .arg(
+            Arg::new("per-file")
+                .help(
+                    "Output the counts of each record in each input, in the order the \
+inputs are given, followed by the record; implies -c",
+                )
+                .long("per-file")
+                .conflicts_with_all(&[
+                    "sort",
+                    "sort-descending",
+                    "all-repeated",
+                    "group",
+                    "top",
+                    "bottom",
+                    "format",
+                    "uniq-compat",
+                    "count-last",
+                    "cumulative",
+                    "json",
+                    "merge",
+                    "subtract",
+                    "in-place",
+                    "load-state",
+                    "dump-state",
+                    "approximate-counts",
+                    "count-distinct",
+                    "estimate",
+                ]),
+        )
+        .arg(
+            Arg::new("cumulative")
+                .help("Output the cumulative percentage of all records after the count")
+                .long("cumulative"),
+        )
+        .arg(
+            Arg::new("merge")
+                .help(
+                    "Read the inputs as output of `huniq -c`, each record preceded by \
+its count and a space or tab, and add up the counts of each record; implies -c",
+                )
+                .long("merge")
+                .conflicts_with_all(&["group", "all-repeated", "approximate-counts"]),
+        )
+        .arg(
+            Arg::new("subtract")
+                .help(
+                    "Read the inputs and BASELINE as output of `huniq -c` and print \
+how much the count of each record changed since the baseline, like `+3 foo`; \
+records whose count is the same are left out",
+                )
+                .long("subtract")
+                .takes_value(true)
+                .value_name("BASELINE")
+                .allow_invalid_utf8(true)
+                .conflicts_with_all(&[
+                    "group",
+                    "all-repeated",
+                    "approximate-counts",
+                    "repeated",
+                    "unique-only",
+                    "min-count",
+                    "max-count",
+                    "top",
+                    "bottom",
+                    "format",
+                    "uniq-compat",
+                    "cumulative",
+                    "json",
+                    "csv",
+                    "tsv",
+                    "load-state",
+                    "dump-state",
+                    "count-distinct",
+                    "estimate",
+                ]),
+        )
+        .arg(
+            Arg::new("count-distinct")
+                .help("Output only the number of distinct records, like `sort -u | wc -l`")
+                .long("count-distinct")
+                .short('n')
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&["dupes-out", "follow", "in-place"]),
+        )
+        .arg(
+            Arg::new("estimate")
+                .help(
+                    "Output only an estimate of the number of distinct records, \
+accurate to about 1%, using a HyperLogLog sketch of a few kilobytes",
+                )
+                .long("estimate")
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&["dupes-out", "follow", "in-place"]),
+        )
+        .arg(
+            Arg::new("stats")
+                .help(
+                    "Print statistics to stderr at the end: the number of records read, \
+unique records and duplicates, bytes read and written, elapsed time and throughput; \
+the statistics so far are printed on SIGUSR1 even without this",
+                )
+                .long("stats")
+                .conflicts_with("follow"),
+        )
+        .arg(
+            Arg::new("min-count")
+                .help("Only output records occurring at least N times")
+                .long("min-count")
+                .takes_value(true)
+                .value_name("N"),
+        )
+        .arg(
+            Arg::new("max-count")
+                .help("Only output records occurring at most N times")
+                .long("max-count")
+                .takes_value(true)
+                .value_name("N"),
+        )
+        .arg(
+            Arg::new("all-repeated")
+                .help(
+                    "Output every occurrence of the records occurring more than once, \
+like `uniq -D`, grouped by record; use `--all-repeated=separate` \
+to separate the groups by an empty line",
+                )
+                .long("all-repeated")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(["none", "separate"])
+                .conflicts_with_all(&[
+                    "count",
+                    "sort",
+                    "sort-descending",
+                    "repeated",
+                    "unique-only",
+                ]),
+        )
+        .arg(
+            Arg::new("group")
+                .help(
+                    "Output all records grouped by record, like `uniq --group`; \
+the groups are separated by an empty line, or preceded (prepend), \
+followed (append) or both",
+                )
+                .long("group")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(["separate", "prepend", "append", "both"])
+                .conflicts_with_all(&["count", "sort", "sort-descending", "all-repeated"]),
+        );
+    #[cfg(feature = "sqlite")]
+    let command = command.arg(
+        Arg::new("sqlite")
+            .help(
+                "Insert the records and their counts into a new table \
+`counts (line BLOB, count INTEGER)` of the given SQLite database instead of printing them",
+            )
+            .long("sqlite")
+            .takes_value(true)
+            .value_name("DB")
+            .allow_invalid_utf8(true)
+            .conflicts_with_all(&[
+                "all-repeated",
+                "group",
+                "dupes-out",
+                "in-place",
+                "output",
+                "follow",
+                "top",
+                "bottom",
+                "format",
+                "json",
+                "csv",
+                "tsv",
+                "uniq-compat",
+                "cumulative",
+                "count-distinct",
+                "estimate",
+                "subtract",
+                "per-file",
+            ]),
+    );
+    command
+}
+
+/// Options of how records are told apart and compared
+fn record_args(command: Command<'static>) -> Command<'static> {
+    command
+        .arg(
+            Arg::new("delimiter")
+                .help(
+                    "Which delimiter between elements to use. By default `\n` is used. \
+May be any string, e.g. `||`, `§` or `\\r\\n`; `\\0`, `\\t`, `\\n`, `\\r`, `\\xHH` \
+and `\\\\` are interpreted as escapes",
+                )
+                .long("delimiter")
+                .long("delim")
+                .short('d')
+                .takes_value(true)
+                .allow_invalid_utf8(true)
+                .default_value("\n")
+                .validator_os(|v| match v.is_empty() {
+                    false => Ok(()),
+                    true => Err("the delimiter must not be empty"),
+                }),
+        )
+        .arg(
+            Arg::new("field")
+                .help(
+                    "Compare just the given fields of each record, like `2` or `2,4-6`, \
+but print the whole record; when counting, the fields are counted instead",
+                )
+                .long("field")
+                .short('k')
+                .takes_value(true)
+                .value_name("LIST"),
+        )
+        .arg(
+            Arg::new("skip-fields")
+                .help(
+                    "Ignore the first N fields of each record when comparing, like \
+`uniq -f`; fields are separated by blanks",
+                )
+                .long("skip-fields")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|v| v.parse::<usize>().map(|_| ()))
+                .conflicts_with("field"),
+        )
+        .arg(
+            Arg::new("skip-chars")
+                .help(
+                    "Ignore the first N characters of each record when comparing, \
+after any fields skipped, like `uniq -s`",
+                )
+                .long("skip-chars")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|v| v.parse::<usize>().map(|_| ())),
+        )
+        .arg(
+            Arg::new("check-chars")
+                .help(
+                    "Compare no more than the first N characters of each record, \
+after any skipped, like `uniq -w`",
+                )
+                .long("check-chars")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|v| v.parse::<usize>().map(|_| ())),
+        )
+        .arg(
+            Arg::new("csv-key")
+                .help(
+                    "Compare just the given columns of CSV records, like `3` or `2,4`, \
+respecting quotes; quoted fields may span lines. When counting, a single column \
+is printed unquoted, several ones as CSV",
+                )
+                .long("csv-key")
+                .takes_value(true)
+                .value_name("LIST")
+                .conflicts_with_all(&KEY_FLAGS[..4])
+                .conflicts_with_all(&FRAMING_FLAGS),
+        )
+        .arg(
+            Arg::new("key-bytes")
+                .help(
+                    "Compare just the given bytes of each record, like `5-20`, \
+counting from one; e.g. for records of fixed columns",
+                )
+                .long("key-bytes")
+                .takes_value(true)
+                .value_name("RANGE")
+                .conflicts_with_all(&KEY_FLAGS[..5]),
+        )
+        .arg(
+            Arg::new("key-regex")
+                .help(
+                    "Compare just what the regex matches in each record, or its first \
+group, like `user=(\\w+)`; see --unmatched for records it doesn't match",
+                )
+                .long("key-regex")
+                .takes_value(true)
+                .value_name("REGEX")
+                .conflicts_with_all(&KEY_FLAGS[..6]),
+        )
+        .arg(
+            Arg::new("key-script")
+                .help(
+                    "Compare what the expression makes of each record, like \
+`line.to_lowercase().split('?')[0]`; strings have the methods to_lowercase, \
+to_uppercase, trim, trim_start, trim_end, replace, sub_string and split, \
+lists can be indexed or joined",
+                )
+                .long("key-script")
+                .takes_value(true)
+                .value_name("SCRIPT")
+                .conflicts_with_all(&KEY_FLAGS[..7])
+                .conflicts_with("key-command"),
+        )
+        .arg(
+            Arg::new("key-command")
+                .help(
+                    "Compare the keys a shell command prints for the records, one per \
+line, or NUL-terminated with -0; a single process is fed all records, like \
+`tr A-Z a-z`. Slow, but allows for any normalization",
+                )
+                .long("key-command")
+                .takes_value(true)
+                .allow_invalid_utf8(true)
+                .value_name("CMD")
+                .conflicts_with_all(&KEY_FLAGS[..7])
+                .conflicts_with_all(&["follow", "merge", "subtract"]),
+        )
+        .arg(
+            Arg::new("json-records")
+                .help(
+                    "Compare records as JSON, ignoring the order of object members, \
+whitespace and how strings and numbers are written; see --unmatched for records \
+that are not JSON",
+                )
+                .long("json-records")
+                .conflicts_with_all(&KEY_FLAGS),
+        )
+        .arg(
+            Arg::new("as-paths")
+                .help(
+                    "Treat records as paths and compare the contents of the files \
+instead, so only the first of identical files is printed; see --unmatched for files \
+that can't be read",
+                )
+                .long("as-paths")
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with("key-command"),
+        )
+        .arg(
+            Arg::new("unmatched")
+                .help(
+                    "What to do with records --key-regex doesn't match, that are \
+not JSON with --json-records, or that are paths of files that can't be read with \
+--as-paths: compare the whole line, drop them, or pass them through without comparing",
+                )
+                .long("unmatched")
+                .takes_value(true)
+                .possible_values(["line", "drop", "pass"])
+                .default_value("line"),
+        )
+        .arg(
+            Arg::new("strip-regex")
+                .help(
+                    "Leave out whatever the regex matches in each record when \
+comparing, like timestamps or request ids, but print the records as they are; \
+may be given multiple times",
+                )
+                .long("strip-regex")
+                .takes_value(true)
+                .value_name("REGEX")
+                .multiple_occurrences(true),
+        )
+        .arg(
+            Arg::new("ignore-timestamps")
+                .help(
+                    "Leave out timestamps at the start of each record when comparing, \
+like those of syslog, ISO 8601, apache or dmesg, but print the records as they are",
+                )
+                .long("ignore-timestamps"),
+        )
+        .arg(
+            Arg::new("normalize")
+                .help(
+                    "Put records into the Unicode normal form before comparing, so \
+composed and decomposed characters like `é` are the same; nfkc also unifies \
+compatibility characters like `ﬁ` and `fi`",
+                )
+                .long("normalize")
+                .takes_value(true)
+                .value_name("FORM")
+                .possible_values(["nfc", "nfkc"]),
+        )
+        .arg(
+            Arg::new("trim")
+                .help(
+                    "Ignore whitespace at the start and end of each record when \
+comparing, but print the records as they are",
+                )
+                .long("trim"),
+        )
+        .arg(
+            Arg::new("squeeze-space")
+                .help(
+                    "Compare each run of whitespace in the records like a single \
+space, but print the records as they are",
+                )
+                .long("squeeze-space"),
+        )
+        .arg(
+            Arg::new("numeric-key")
+                .help(
+                    "Compare records, or the parts of them compared, that are numbers \
+by their value, so `1`, `1.0`, `01` and `1e0` are the same",
+                )
+                .long("numeric-key"),
+        )
+        .arg(
+            Arg::new("key-ip")
+                .help(
+                    "Compare records, or the parts of them compared, that are IP \
+addresses in their canonical form, so `2001:DB8:0::1` and `2001:db8::1`, or \
+`::ffff:10.0.0.1` and `10.0.0.1`, are the same; when counting, the addresses \
+are printed in that form",
+                )
+                .long("key-ip"),
+        )
+        .arg(
+            Arg::new("prefix")
+                .help(
+                    "With --key-ip, compare addresses by the network of this size they \
+are in, like /24, or /24,/64 for IPv4 and IPv6 addresses respectively",
+                )
+                .long("prefix")
+                .takes_value(true)
+                .value_name("LENGTH")
+                .requires("key-ip"),
+        )
+        .arg(
+            Arg::new("hash-input")
+                .help(
+                    "Take records, or the parts of them compared, that are hashes in \
+hex, or decimal with `--hash-input=decimal`, as the hashes to compare instead of \
+hashing them again, like those computed upstream; they should be well distributed, \
+like those of any common hash function. Other records are hashed as usual; when \
+counting, the hashes are printed in a uniform way",
+                )
+                .long("hash-input")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(["hex", "decimal"])
+                .conflicts_with_all(&["fuzzy", "max-edit-distance"]),
+        )
+        .arg(
+            Arg::new("fold-case")
+                .help(
+                    "Ignore differences in case when comparing, of ASCII letters only \
+or, with `--fold-case=unicode`, of all characters, so `STRASSE` and `straße` \
+are the same; when counting, the records are printed in lowercase",
+                )
+                .long("fold-case")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(["ascii", "unicode"]),
+        )
+        .arg(
+            Arg::new("field-delimiter")
+                .help("Fields are separated by this string instead of runs of blanks")
+                .long("field-delimiter")
+                .visible_alias("field-delim")
+                .takes_value(true)
+                .allow_invalid_utf8(true)
+                .requires("field"),
+        )
+        .arg(
+            Arg::new("out-delimiter")
+                .help("Delimiter to write after each record; by default the input's is kept")
+                .long("out-delimiter")
+                .short('D')
+                .takes_value(true)
+                .allow_invalid_utf8(true),
+        )
+        .arg(
+            Arg::new("null")
+                .help("Use the \\0 character as the record delimiter.")
                 .long("null")
                 .short('0')
-                .conflicts_with("delimiter"),
+                .conflicts_with_all(&FRAMING_FLAGS[..1]),
+        )
+        .arg(
+            Arg::new("crlf")
+                .help(
+                    "Split records on `\\n`, ignoring a `\\r` before it when comparing records. \
+The `\\r` is kept in the output",
+                )
+                .long("crlf")
+                .conflicts_with_all(&FRAMING_FLAGS[..2]),
+        )
+        .arg(
+            Arg::new("paragraph")
+                .help("Use blocks of lines separated by one or more blank lines as records")
+                .long("paragraph")
+                .short('p')
+                .conflicts_with_all(&FRAMING_FLAGS[..3]),
+        )
+        .arg(
+            Arg::new("record-size")
+                .help("Treat the input as binary records of SIZE bytes each, without delimiters")
+                .long("record-size")
+                .takes_value(true)
+                .value_name("SIZE")
+                .conflicts_with_all(&FRAMING_FLAGS[..4]),
+        )
+        .arg(
+            Arg::new("framing")
+                .help("Treat the input as binary records, each preceded by its length")
+                .long("framing")
+                .takes_value(true)
+                .possible_values(["varint", "u32le"])
+                .conflicts_with_all(&FRAMING_FLAGS[..5]),
         )
         .arg(
             Arg::new("no-trailing-delimiter")
                 .help("Prevent adding a delimiter to the last record if missing")
                 .long("no-trailing-delimiter")
                 .short('t'),
+        )
+}
+
+/// Options of where the output goes
+fn output_args(command: Command<'static>) -> Command<'static> {
+    command
+        .arg(
+            Arg::new("output")
+                .help("Write the output to the given file instead of stdout")
+                .long("output")
+                .short('o')
+                .takes_value(true)
+                .allow_invalid_utf8(true),
+        )
+        .arg(
+            Arg::new("compress")
+                .help("Compress the data written to the output file")
+                .long("compress")
+                .takes_value(true)
+                .possible_values(["gzip", "zstd", "xz", "bzip2"])
+                .requires("output"),
+        )
+        .arg(
+            Arg::new("in-place")
+                .help(
+                    "Deduplicate the given files in place. If a suffix is given \
+(e.g. `--in-place=.bak`), the original is preserved with that suffix",
+                )
+                .long("in-place")
+                .short('i')
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .allow_invalid_utf8(true)
+                .conflicts_with("output"),
+        )
+        .arg(
+            Arg::new("dupes-out")
+                .help("Write records suppressed as duplicates to the given file")
+                .long("dupes-out")
+                .takes_value(true)
+                .allow_invalid_utf8(true)
+                .conflicts_with_all(&[
+                    "count",
+                    "sort",
+                    "sort-descending",
+                    "repeated",
+                    "unique-only",
+                    "all-repeated",
+                    "group",
+                    "min-count",
+                    "max-count",
+                    "top",
+                    "bottom",
+                    "format",
+                    "uniq-compat",
+                    "json",
+                    "csv",
+                    "tsv",
+                    "merge",
+                    "subtract",
+                ]),
+        )
+        .arg(
+            Arg::new("write-buffer")
+                .help("Size of the output buffer, e.g. 64K or 1M")
+                .long("write-buffer")
+                .takes_value(true)
+                .value_name("SIZE")
+                .validator(|v| parse_size(v).map(|_| ())),
+        )
+}
+
+/// Options of reading the inputs
+fn input_args(command: Command<'static>) -> Command<'static> {
+    let command = command
+        .arg(
+            Arg::new("follow")
+                .help(
+                    "Keep reading the last input after reaching its end, waiting \
+for new records to arrive, like `tail -f`; never exits",
+                )
+                .long("follow")
+                .short('f')
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with("in-place"),
+        )
+        .arg(
+            Arg::new("decompress")
+                .help(
+                    "Decompress stdin if it is compressed with gzip, zstd, xz or bzip2. \
+Compressed input files are always decompressed",
+                )
+                .long("decompress")
+                .short('z'),
+        )
+        .arg(
+            Arg::new("no-mmap")
+                .help("Read input files using regular reads instead of memory-mapping them")
+                .long("no-mmap"),
+        )
+        .arg(
+            Arg::new("threads")
+                .help(
+                    "Number of threads to hash records on; 0 uses one thread per CPU. \
+When counting, records are distributed among that many shards. \
+With --per-file-reset, that many inputs are processed at once. \
+By default, everything happens on a single thread",
+                )
+                .long("threads")
+                .short('j')
+                .takes_value(true)
+                .value_name("N")
+                .validator(|v| v.parse::<usize>().map(|_| ())),
+        )
+        .arg(
+            Arg::new("files-from")
+                .help(
+                    "Also read the inputs listed in the given file, one per line or \
+terminated by \\0 with -0; `-` designates stdin",
+                )
+                .long("files-from")
+                .takes_value(true)
+                .value_name("FILE")
+                .allow_invalid_utf8(true),
+        )
+        .arg(
+            Arg::new("recursive")
+                .help("Read all files below the directories given as inputs")
+                .long("recursive")
+                .short('r'),
+        )
+        .arg(
+            Arg::new("file-order")
+                .help(
+                    "The order to read the files below a directory or matching a \
+pattern in: sorted by name, least recently modified first, or as listed",
+                )
+                .long("file-order")
+                .takes_value(true)
+                .possible_values(["name", "mtime", "none"])
+                .default_value("name"),
+        )
+        .arg(
+            Arg::new("input")
+                .help(
+                    "Files to read records from; `-` designates stdin. By default stdin \
+is used. Patterns like `'*.log'` are expanded unless a file of that name exists",
+                )
+                .multiple_occurrences(true)
+                .allow_invalid_utf8(true)
+                .default_value("-"),
+        );
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    let command = command.arg(
+        Arg::new("io-uring")
+            .help("Read inputs using io_uring, overlapping reads with processing")
+            .long("io-uring")
+            .conflicts_with_all(&["follow", "decompress", "no-mmap"]),
+    );
+    command
+}
+
+/// Options of remembering the records seen when removing duplicates
+fn uniq_args(command: Command<'static>) -> Command<'static> {
+    command
+        .arg(
+            Arg::new("bloom")
+                .help(
+                    "Remember the records seen in a Bloom filter of a fixed size instead \
+of a hash set; a few unique records are wrongly dropped as duplicates, see --fpr. \
+Sized for --expected-lines records, or a hundred million",
+                )
+                .long("bloom")
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS),
+        )
+        .arg(
+            Arg::new("cuckoo")
+                .help(
+                    "Remember the records seen in a cuckoo filter of a fixed size instead \
+of a hash set; about one in 10000 unique records is wrongly dropped as a duplicate. \
+Sized for --expected-lines records, or a hundred million",
+                )
+                .long("cuckoo")
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with("bloom"),
+        )
+        .arg(
+            Arg::new("window")
+                .help(
+                    "Only remember the N most recently seen distinct records, so memory \
+stays bounded on endless streams; records forgotten are printed again",
+                )
+                .long("window")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|v| v.parse::<usize>().map(|_| ()))
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with_all(&["bloom", "cuckoo"]),
+        )
+        .arg(
+            Arg::new("last")
+                .help(
+                    "Only drop records occurring within the last N records, so memory \
+stays bounded; like uniq, for N=1",
+                )
+                .long("last")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|v| v.parse::<usize>().map(|_| ()))
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with_all(&["bloom", "cuckoo", "window"]),
+        )
+        .arg(
+            Arg::new("expire")
+                .help(
+                    "Forget records once the given duration, e.g. 10m, has passed since \
+they were printed, so they are printed again; mostly useful with --follow. With \
+--cuckoo, they are removed from the filter, making room for others",
+                )
+                .long("expire")
+                .takes_value(true)
+                .value_name("DURATION")
+                .validator(|v| parse_duration(v).map(|_| ()))
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with_all(&["bloom", "window", "last"]),
+        )
+        .arg(
+            Arg::new("fuzzy")
+                .help(
+                    "Also drop records similar to one printed before, like the same \
+error with different details; records are similar if enough of the bits of the \
+SimHash fingerprints of their words are equal, see --threshold",
+                )
+                .long("fuzzy")
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with_all(&BACKEND_FLAGS)
+                .conflicts_with_all(&SHARED_FLAGS),
+        )
+        .arg(
+            Arg::new("threshold")
+                .help(
+                    "With --fuzzy, the fraction of the fingerprint bits of records that \
+must be equal for them to be similar, above zero and at most one; by default 0.8, at \
+which records of eight words or more differing in one word are mostly similar",
+                )
+                .long("threshold")
+                .takes_value(true)
+                .value_name("FRACTION")
+                .validator(|v| match v.parse::<f64>() {
+                    Ok(t) if t > 0.0 && t <= 1.0 => Ok(()),
+                    _ => Err("expected a fraction above zero and at most one"),
+                })
+                .requires("fuzzy"),
+        )
+        .arg(
+            Arg::new("max-edit-distance")
+                .help(
+                    "Also drop records that at most N characters have to be inserted, \
+removed or replaced in to get a record printed before, like names with typos; \
+far slower than comparing hashes, so meant for short records",
+                )
+                .long("max-edit-distance")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|v| v.parse::<usize>().map(|_| ()))
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with_all(&SHARED_FLAGS)
+                .conflicts_with("fuzzy"),
+        )
+        .arg(
+            Arg::new("exact")
+                .help(
+                    "Remember the records seen, instead of just their hashes, so a \
+unique record is never dropped for having the same hash as another; takes as much \
+memory as the distinct records",
+                )
+                .long("exact")
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with_all(&BACKEND_FLAGS)
+                .conflicts_with_all(&SHARED_FLAGS)
+                .conflicts_with_all(&["fuzzy", "hash-input", "print-hash"]),
+        )
+        .arg(
+            Arg::new("hasher")
+                .help(
+                    "The hash function to compare records by: aHash, the fastest, \
+wyhash, about as fast, or SipHash, which is slower, but a keyed pseudorandom \
+function, so records colliding can't be crafted without the random keys",
+                )
+                .long("hasher")
+                .takes_value(true)
+                .value_name("HASHER")
+                .possible_values(["ahash", "siphash", "wyhash"])
+                .default_value("ahash")
+                .conflicts_with_all(&["load-state", "dump-state", "store", "connect", "query"]),
+        )
+        .arg(
+            Arg::new("seed")
+                .help(
+                    "Seed the hash function with up to 64 hex digits instead of \
+randomly, so records hash the same in every run, like for --print-hash; states \
+saved with --dump-state can only be loaded with the same seed. aHash only hashes \
+the same with the same build of huniq; SipHash and wyhash everywhere",
+                )
+                .long("seed")
+                .takes_value(true)
+                .value_name("HEX")
+                .validator(|v| parse_seed(v).map(|_| ()))
+                .conflicts_with_all(&["secure", "store", "connect"]),
+        )
+        .arg(
+            Arg::new("secure")
+                .help(
+                    "Compare records by SipHash keyed from the operating system's \
+random number generator, failing if it can't be read; for untrusted input, so \
+records colliding, which would be dropped as duplicates, or slowing down the hash \
+set, can't be crafted. Slower than the default",
+                )
+                .long("secure")
+                .conflicts_with_all(&[
+                    "hasher",
+                    "load-state",
+                    "dump-state",
+                    "store",
+                    "connect",
+                    "query",
+                    "hash-input",
+                    "fuzzy",
+                ]),
+        )
+        .arg(
+            Arg::new("hash-bits")
+                .help(
+                    "Compare records by hashes of this many bits; with 128, records \
+are about as unlikely to share a hash among tens of billions as among a few, without \
+remembering the records like --exact, but the set of seen records takes about twice \
+the memory",
+                )
+                .long("hash-bits")
+                .takes_value(true)
+                .possible_values(["64", "128"])
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with_all(&BACKEND_FLAGS)
+                .conflicts_with_all(&SHARED_FLAGS)
+                .conflicts_with_all(&["fuzzy", "hash-input", "print-hash", "exact"]),
+        )
+        .arg(
+            Arg::new("detect-collisions")
+                .help(
+                    "Warn when a record is dropped for having the same hash as a \
+different record seen in this run, telling them apart by a second hash; takes about \
+twice the memory of the hashes alone",
+                )
+                .long("detect-collisions")
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with_all(&[
+                    "serve",
+                    "query",
+                    "fuzzy",
+                    "hash-input",
+                    "exact",
+                    "hash-bits",
+                ]),
+        )
+        .arg(
+            Arg::new("print-hash")
+                .help(
+                    "Print the hash each record is compared by in hex, followed by a \
+tab, before each record printed, or after it with `--print-hash=suffix`; records \
+passed through by --unmatched have none. Hashes differ between runs, unless \
+continuing from a state",
+                )
+                .long("print-hash")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(["prefix", "suffix"])
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with_all(&["query", "subtract", "serve", "framing", "record-size"]),
+        )
+        .arg(
+            Arg::new("filter-file")
+                .help(
+                    "Treat the records in FILE as seen before reading the inputs, so \
+they are never printed; may be given multiple times",
+                )
+                .long("filter-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .multiple_occurrences(true)
+                .allow_invalid_utf8(true)
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with_all(&["reset-every", "reset-interval"]),
+        )
+        .arg(
+            Arg::new("dump-lines")
+                .help(
+                    "Write every distinct record, including those of --filter-file, to \
+FILE at the end; a plain state that can be loaded again with --filter-file",
+                )
+                .long("dump-lines")
+                .takes_value(true)
+                .value_name("FILE")
+                .allow_invalid_utf8(true)
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with_all(&[
+                    "follow",
+                    "in-place",
+                    "load-state",
+                    "window",
+                    "last",
+                    "expire",
+                    "reset-every",
+                    "reset-interval",
+                ]),
+        )
+        .arg(
+            Arg::new("serve")
+                .help(
+                    "Serve a set of seen records on the Unix socket at PATH, shared by \
+all huniq processes given --connect PATH, until killed; SIGUSR2 forgets them",
+                )
+                .long("serve")
+                .takes_value(true)
+                .value_name("PATH")
+                .allow_invalid_utf8(true)
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with_all(&["connect", "stats"]),
+        )
+        .arg(
+            Arg::new("connect")
+                .help(
+                    "Remove duplicates with the set of seen records of the server \
+started with --serve PATH, so records printed by any process connected are dropped; \
+each record takes a round trip to the server, which makes this a lot slower",
+                )
+                .long("connect")
+                .takes_value(true)
+                .value_name("PATH")
+                .allow_invalid_utf8(true)
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&STATE_CONFLICTS)
+                .conflicts_with_all(&["load-state", "dump-state", "reset-every", "reset-interval"]),
+        )
+        .arg(
+            Arg::new("query")
+                .help(
+                    "Print each record prefixed by `seen` or `new`, telling whether it \
+was seen by the run that saved STATE with --dump-state, without changing it; the status \
+is zero only if all records were seen",
+                )
+                .long("query")
+                .takes_value(true)
+                .value_name("STATE")
+                .allow_invalid_utf8(true)
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&STATE_CONFLICTS)
+                .conflicts_with_all(&[
+                    "load-state",
+                    "dump-state",
+                    "dupes-out",
+                    "filter-file",
+                    "dump-lines",
+                    "serve",
+                    "connect",
+                ]),
+        )
+        .arg(
+            Arg::new("store")
+                .help(
+                    "Remember the records seen in the file at PATH, created if missing, \
+instead of in memory; the most recently seen are cached in memory. Records in the store \
+from previous runs are dropped as duplicates, and several processes may share the store",
+                )
+                .long("store")
+                .takes_value(true)
+                .value_name("PATH")
+                .allow_invalid_utf8(true)
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&STATE_CONFLICTS)
+                .conflicts_with_all(&[
+                    "load-state",
+                    "dump-state",
+                    "reset-every",
+                    "reset-interval",
+                    "connect",
+                    "serve",
+                    "query",
+                ]),
+        )
+        .arg(
+            Arg::new("per-file-reset")
+                .help(
+                    "Remove duplicates within each input on its own, instead of across \
+all inputs; likewise for counts",
+                )
+                .long("per-file-reset")
+                .conflicts_with_all(&[
+                    "in-place",
+                    "intersect",
+                    "difference",
+                    "membership",
+                    "per-file",
+                    "subtract",
+                    "dump-state",
+                    "dump-lines",
+                    "connect",
+                    "store",
+                ]),
+        )
+        .arg(
+            Arg::new("load-state")
+                .help(
+                    "Continue from the state saved by --dump-state in a previous run: \
+records seen then are dropped as duplicates, and their counts are added to when counting. \
+A missing file is no state at all",
+                )
+                .long("load-state")
+                .takes_value(true)
+                .value_name("FILE")
+                .allow_invalid_utf8(true)
+                .conflicts_with_all(&STATE_CONFLICTS),
+        )
+        .arg(
+            Arg::new("dump-state")
+                .help(
+                    "Save the records seen, or their counts when counting, to FILE at \
+the end, for --load-state. The state can only be loaded by the same build of huniq",
+                )
+                .long("dump-state")
+                .takes_value(true)
+                .value_name("FILE")
+                .allow_invalid_utf8(true)
+                .conflicts_with_all(&STATE_CONFLICTS)
+                .conflicts_with("follow"),
+        )
+        .arg(
+            Arg::new("reset-every")
+                .help(
+                    "Forget all records seen after every N records, so memory stays \
+bounded and records are printed again; mostly useful with --follow",
+                )
+                .long("reset-every")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|v| match v.parse::<u64>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err("must be a positive number"),
+                })
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with("spill-after"),
+        )
+        .arg(
+            Arg::new("reset-interval")
+                .help(
+                    "Forget all records seen whenever the given duration, e.g. 1h, has \
+passed; like --reset-every, but by time. Sending SIGUSR2 forgets them right away",
+                )
+                .long("reset-interval")
+                .takes_value(true)
+                .value_name("DURATION")
+                .validator(|v| parse_duration(v).map(|_| ()))
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with("spill-after"),
+        )
+        .arg(
+            Arg::new("spill-after")
+                .help(
+                    "Once N distinct records are held in memory, remove the remaining \
+duplicates on disk, in the directory given by TMPDIR; records are printed at the end then",
+                )
+                .long("spill-after")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|v| v.parse::<usize>().map(|_| ()))
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with_all(&["follow", "bloom", "cuckoo"]),
+        )
+        .arg(
+            Arg::new("max-memory")
+                .help(
+                    "Limit the memory taken by the records seen, e.g. 4G; what happens \
+when the limit is reached is given by --on-memory-limit",
+                )
+                .long("max-memory")
+                .takes_value(true)
+                .value_name("SIZE")
+                .validator(|v| parse_size(v).map(|_| ()))
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&DISTINCT_FLAGS)
+                .conflicts_with_all(&["bloom", "cuckoo", "spill-after"]),
+        )
+        .arg(
+            Arg::new("on-memory-limit")
+                .help(
+                    "What to do when --max-memory is reached: fail (abort, the default), \
+remove the remaining duplicates on disk like --spill-after (spill), or continue \
+with a Bloom filter of that size (approximate)",
+                )
+                .long("on-memory-limit")
+                .takes_value(true)
+                .possible_values(["abort", "spill", "approximate"])
+                .requires("max-memory"),
+        )
+        .arg(
+            Arg::new("fpr")
+                .help(
+                    "False positive rate of --bloom, i.e. the share of unique records dropped; \
+0.001 by default",
+                )
+                .long("fpr")
+                .takes_value(true)
+                .value_name("RATE")
+                .requires("bloom")
+                .validator(|v| match v.parse::<f64>() {
+                    Ok(fpr) if fpr > 0.0 && fpr < 1.0 => Ok(()),
+                    _ => Err("must be a number between 0 and 1"),
+                }),
+        )
+        .arg(
+            Arg::new("expected-lines")
+                .help(
+                    "Expected number of distinct records; used to allocate \
+sufficient memory up front instead of growing repeatedly",
+                )
+                .long("expected-lines")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|v| v.parse::<usize>().map(|_| ())),
+        )
+}
+
+/// Set operations on the inputs
+fn set_args(command: Command<'static>) -> Command<'static> {
+    command
+        .arg(
+            Arg::new("union")
+                .help("Print the records present in any input once; the default")
+                .long("union")
+                .conflicts_with_all(&["intersect", "difference", "membership"]),
+        )
+        .arg(
+            Arg::new("intersect")
+                .help(
+                    "Print the records present in all inputs, in the order of the last \
+input, instead of those present in any",
+                )
+                .long("intersect")
+                .conflicts_with_all(&SET_CONFLICTS)
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with("difference"),
+        )
+        .arg(
+            Arg::new("difference")
+                .help(
+                    "Print the records of the first input not present in any other, \
+instead of those present in any input",
+                )
+                .long("difference")
+                .conflicts_with_all(&SET_CONFLICTS)
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with("membership"),
+        )
+        .arg(
+            Arg::new("membership")
+                .help(
+                    "Print each record once, preceded by the comma separated inputs \
+containing it and a tab",
+                )
+                .long("membership")
+                .conflicts_with_all(&SET_CONFLICTS)
+                .conflicts_with_all(&COUNT_FLAGS)
+                .conflicts_with_all(&["intersect", "union"]),
+        )
+}
+
+/// How the inputs are split into records
+fn framing(args: &ArgMatches) -> Result<Framing> {
+    Ok(if args.is_present("null") {
+        Framing::Terminated(vec![b'\0'])
+    } else if args.is_present("crlf") {
+        Framing::Crlf
+    } else if args.is_present("paragraph") {
+        Framing::Paragraph
+    } else if args.is_present("csv-key") {
+        Framing::Csv
+    } else if let Some(prefix) = args.value_of("framing") {
+        Framing::Prefixed(Prefix::from_name(prefix).unwrap())
+    } else if let Some(size) = args.value_of("record-size") {
+        match parse_size(size)? {
+            0 => return Err(anyhow!("the record size must not be zero")),
+            size => Framing::Fixed(size),
+        }
+    } else {
+        Framing::Terminated(parse_escaped(
+            args.value_of_os("delimiter").unwrap(),
+            "delimiter",
+        )?)
+    })
+}
+
+/// How the keys of records are selected and normalized
+fn selector(args: &ArgMatches) -> Result<Selector> {
+    let mut key = Selector::default();
+    if let Some(fields) = args.value_of("field") {
+        let delim = match args.value_of_os("field-delimiter") {
+            Some(delim) => Some(parse_escaped(delim, "field delimiter")?),
+            None => None,
+        };
+        key = key.fields(fields, delim)?;
+    }
+    if let Some(columns) = args.value_of("csv-key") {
+        key = key.csv_columns(columns)?;
+    }
+    if let Some(n) = args.value_of("skip-fields") {
+        key = key.skip_fields(n.parse()?);
+    }
+    if let Some(n) = args.value_of("skip-chars") {
+        key = key.skip_chars(n.parse()?);
+    }
+    if let Some(n) = args.value_of("check-chars") {
+        key = key.check_chars(n.parse()?);
+    }
+    if let Some(range) = args.value_of("key-bytes") {
+        key = key.bytes(range)?;
+    }
+    if let Some(script) = args.value_of("key-script") {
+        key = key.script(script::Script::new(script)?);
+    }
+    if args.is_present("ignore-timestamps") {
+        key = key.ignore_timestamps();
+    }
+    if let Some(patterns) = args.values_of("strip-regex") {
+        for pattern in patterns {
+            key = key.strip(regex::Regex::new(pattern)?);
+        }
+    }
+    if let Some(form) = args.value_of("normalize") {
+        key = key.normalize_unicode(normalize::Form::from_name(form).unwrap());
+    }
+    if args.is_present("trim") {
+        key = key.trim();
+    }
+    if args.is_present("squeeze-space") {
+        key = key.squeeze_space();
+    }
+    if args.is_present("numeric-key") {
+        key = key.numeric();
+    }
+    if args.is_present("key-ip") {
+        let prefix = match args.value_of("prefix") {
+            Some(prefix) => IpPrefix::parse(prefix)?,
+            None => IpPrefix::default(),
+        };
+        key = key.ip(prefix);
+    }
+    if args.is_present("fold-case") {
+        let fold = args.value_of("fold-case").unwrap_or("ascii");
+        key = key.fold_case(Fold::from_name(fold).unwrap());
+    }
+    if args.is_present("hash-input") {
+        let format = args.value_of("hash-input").unwrap_or("hex");
+        key = key.hashes(HashFormat::from_name(format).unwrap());
+    }
+    let unmatched = args.value_of("unmatched").unwrap();
+    if args.is_present("json-records") {
+        key = key.json(Unmatched::from_name(unmatched).unwrap());
+    }
+    if args.is_present("as-paths") {
+        key = key.paths(Unmatched::from_name(unmatched).unwrap());
+    }
+    if let Some(pattern) = args.value_of("key-regex") {
+        key = key.regex(
+            regex::Regex::new(pattern)?,
+            Unmatched::from_name(unmatched).unwrap(),
         );
+    }
+    Ok(key)
+}
+
+fn try_main() -> Result<()> {
+    let args = argspec().get_matches();
 
-    let args = argspec.get_matches();
+    let framing = framing(&args)?;
 
-    let delim = match args.is_present("null") {
-        true => b'\0',
-        false => args.value_of("delimiter").unwrap().as_bytes()[0],
+    let out_delim = match args.value_of_os("out-delimiter") {
+        Some(delim) => Some(parse_escaped(delim, "delimiter")?),
+        None if args.is_present("json") || args.is_present("csv") || args.is_present("tsv") => {
+            Some(b"\n".to_vec())
+        }
+        None => None,
     };
 
     let sort = match (args.is_present("sort"), args.is_present("sort-descending")) {
@@ -204,14 +3093,390 @@ Use sed to turn your delimiter into zero bytes?
         (false, false) => None,
     };
 
-    match args.is_present("count") || sort.is_some() {
-        true => count_cmd(delim, sort),
-        false => uniq_cmd(delim, !args.is_present("no-trailing-delimiter")),
+    let mut inputs: Vec<OsString> = match args.occurrences_of("input") {
+        // Just the inputs listed, unless given as well
+        0 if args.is_present("files-from") => Vec::new(),
+        _ => args
+            .values_of_os("input")
+            .unwrap()
+            .map(OsString::from)
+            .collect(),
+    };
+
+    let mut min_count = match args.is_present("repeated") || args.is_present("all-repeated") {
+        true => 2,
+        false => 1,
+    };
+    if let Some(n) = args.value_of("min-count") {
+        min_count = min_count.max(n.parse()?);
+    }
+    let mut max_count = match args.is_present("unique-only") {
+        true => 1,
+        false => u64::MAX,
+    };
+    if let Some(n) = args.value_of("max-count") {
+        max_count = max_count.min(n.parse()?);
     }
+
+    let sep = match args.is_present("count-tab") {
+        true => "\t",
+        false => " ",
+    };
+    let template = if let Some(format) = args.value_of_os("format") {
+        Some(Template::parse(&parse_escaped(format, "format")?)?)
+    } else if args.is_present("json") {
+        let non_utf8 = args.value_of("json").unwrap_or("lossy");
+        Some(Template::json(NonUtf8::from_name(non_utf8).unwrap()))
+    } else if args.is_present("csv") {
+        Some(Template::csv())
+    } else if args.is_present("tsv") {
+        Some(Template::tsv())
+    } else if args.is_present("uniq-compat") {
+        Some(Template::parse(b"{count:7} {line}")?)
+    } else if args.is_present("cumulative") {
+        let format = match args.is_present("count-last") {
+            true => format!("{{line}}{0}{{count}}{0}{{cumulative}}%", sep),
+            false => format!("{{count}}{0}{{cumulative}}%{0}{{line}}", sep),
+        };
+        Some(Template::parse(format.as_bytes())?)
+    } else if args.is_present("count")
+        || args.is_present("merge")
+        || sort.is_some()
+        || args.is_present("top")
+        || args.is_present("bottom")
+    {
+        let format = match args.is_present("count-last") {
+            true => format!("{{line}}{}{{count}}", sep),
+            false => format!("{{count}}{}{{line}}", sep),
+        };
+        Some(Template::parse(format.as_bytes())?)
+    } else {
+        None
+    };
+
+    let report = Report {
+        sort,
+        groups: if args.is_present("all-repeated") {
+            Some(Grouping::from_name(args.value_of("all-repeated").unwrap_or("none")).unwrap())
+        } else if args.is_present("group") {
+            Some(Grouping::from_name(args.value_of("group").unwrap_or("separate")).unwrap())
+        } else {
+            None
+        },
+        template,
+        occurrences: min_count..=max_count,
+        top: match (args.value_of("top"), args.value_of("bottom")) {
+            (Some(n), _) => Some((n.parse()?, Sort::Descending)),
+            (_, Some(n)) => Some((n.parse()?, Sort::Ascending)),
+            (None, None) => None,
+        },
+        approximate: args.is_present("approximate-counts"),
+        #[cfg(feature = "sqlite")]
+        sqlite: args.value_of_os("sqlite").map(OsString::from),
+    };
+    if report.approximate && !args.is_present("top") {
+        return Err(anyhow!("--approximate-counts requires --top"));
+    }
+    if args.is_present("cumulative") && report.sort.is_none() && report.top.is_none() {
+        return Err(anyhow!(
+            "--cumulative requires sorted output; use --sort-descending or --top"
+        ));
+    }
+    // Filtering by the number of occurrences requires counting them
+    let count = report.template.is_some() || report.occurrences != (1..=u64::MAX);
+    let count = count || args.is_present("merge") || args.is_present("per-file");
+    #[cfg(feature = "sqlite")]
+    let count = count || report.sqlite.is_some();
+    let key = selector(&args)?;
+    let mut opts = Opts {
+        framing,
+        key,
+        key_command: args.value_of_os("key-command").map(OsString::from),
+        out_delim,
+        include_trailing: !args.is_present("no-trailing-delimiter"),
+        input: InputOpts {
+            follow: args.is_present("follow"),
+            decompress_stdin: args.is_present("decompress"),
+            mmap: !args.is_present("no-mmap"),
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            io_uring: args.is_present("io-uring"),
+        },
+        threads: match args.value_of("threads") {
+            Some("0") => thread::available_parallelism().map_or(1, |n| n.get()),
+            Some(n) => n.parse()?,
+            None => 1,
+        },
+        capacity: match args.value_of("expected-lines") {
+            Some(n) => n.parse()?,
+            None => 0,
+        },
+        algorithm: match args.is_present("secure") {
+            true => Algorithm::Siphash,
+            false => Algorithm::from_name(args.value_of("hasher").unwrap()).unwrap(),
+        },
+        seeds: match args.value_of("seed") {
+            Some(seed) => Some(parse_seed(seed)?),
+            None if args.is_present("secure") => Some(
+                hasher::secure_seeds()
+                    .map_err(|e| anyhow!("could not read random seeds: {}", e))?,
+            ),
+            None => None,
+        },
+        backend: match (args.is_present("bloom"), args.value_of("fpr")) {
+            (true, Some(fpr)) => Backend::Bloom(fpr.parse()?),
+            (true, None) => Backend::Bloom(0.001),
+            _ if args.is_present("cuckoo") => match args.value_of("expire") {
+                Some(ttl) => Backend::ExpireCuckoo(parse_duration(ttl)?),
+                None => Backend::Cuckoo,
+            },
+            _ if args.is_present("window") => {
+                Backend::Window(args.value_of("window").unwrap().parse()?)
+            }
+            _ if args.is_present("last") => Backend::Last(args.value_of("last").unwrap().parse()?),
+            _ if args.is_present("expire") => {
+                Backend::Expire(parse_duration(args.value_of("expire").unwrap())?)
+            }
+            _ if args.is_present("fuzzy") => {
+                Backend::Similar(args.value_of("threshold").unwrap_or("0.8").parse()?)
+            }
+            _ if args.value_of("hash-bits") == Some("128") => Backend::Wide,
+            _ => Backend::HashSet,
+        },
+        exact: args.is_present("exact"),
+        detect_collisions: args.is_present("detect-collisions"),
+        max_edit_distance: match args.value_of("max-edit-distance") {
+            Some(n) => Some(n.parse()?),
+            None => None,
+        },
+        spill: match args.value_of("spill-after") {
+            Some(n) => Some(n.parse()?),
+            None => None,
+        },
+        max_memory: match args.value_of("max-memory") {
+            Some(size) => {
+                let overflow = args.value_of("on-memory-limit").unwrap_or("abort");
+                Some((parse_size(size)?, Overflow::from_name(overflow).unwrap()))
+            }
+            None => None,
+        },
+        reset_every: match args.value_of("reset-every") {
+            Some(n) => Some(n.parse()?),
+            None => None,
+        },
+        reset_interval: match args.value_of("reset-interval") {
+            Some(ttl) => Some(parse_duration(ttl)?),
+            None => None,
+        },
+        load_state: args.value_of_os("load-state").map(OsString::from),
+        dump_state: args.value_of_os("dump-state").map(OsString::from),
+        filter_files: match args.values_of_os("filter-file") {
+            Some(paths) => paths.map(OsString::from).collect(),
+            None => Vec::new(),
+        },
+        dump_lines: args.value_of_os("dump-lines").map(OsString::from),
+        connect: args.value_of_os("connect").map(OsString::from),
+        store: args.value_of_os("store").map(OsString::from),
+        merge: args.is_present("merge") || args.is_present("subtract"),
+        print_hash: match args.is_present("print-hash") {
+            true => {
+                let position = args.value_of("print-hash").unwrap_or("prefix");
+                Some(HashPosition::from_name(position).unwrap())
+            }
+            false => None,
+        },
+    };
+    if let (Some((_, Overflow::Spill)), true) = (opts.max_memory, opts.input.follow) {
+        return Err(anyhow!("cannot spill to disk when following"));
+    }
+    let write_buffer = match args.value_of("write-buffer") {
+        Some(v) => parse_size(v)?,
+        None => DEFAULT_BUFFER_SIZE,
+    };
+    let mut dupes = match args.value_of_os("dupes-out") {
+        Some(path) => Some(Output::open(Some(path), None, write_buffer)?),
+        None => None,
+    };
+    if let Some(list) = args.value_of_os("files-from") {
+        let delim = match args.is_present("null") {
+            true => b'\0',
+            false => b'\n',
+        };
+        let framing = Framing::Terminated(vec![delim]);
+        let input = InputOpts {
+            follow: false,
+            ..opts.input
+        };
+        open_input(list, input)?.for_each_record(&framing, |name| {
+            let name = framing.key(name);
+            if !name.is_empty() {
+                inputs.push(OsStr::from_bytes(name).to_os_string());
+            }
+            Ok(true)
+        })?;
+    }
+    let recursive = args.is_present("recursive");
+    let order = files::Order::from_name(args.value_of("file-order").unwrap()).unwrap();
+    let inputs = files::expand(inputs, recursive, order)?;
+    // With each input on its own, the inputs are spread across the
+    // threads instead of the records of each
+    let independent = args.is_present("per-file-reset") && inputs.len() > 1;
+    let file_threads = match independent && dupes.is_none() && !opts.input.follow {
+        true => mem::replace(&mut opts.threads, 1),
+        false => 1,
+    };
+    // Each key comes from the command in the order of the records, and
+    // which of close records is kept depends on that order; exact keys
+    // are numbered afresh on a reset, while records numbered ahead on
+    // other threads would keep their numbers from before
+    if opts.key_command.is_some() || opts.max_edit_distance.is_some() || opts.exact {
+        opts.threads = 1;
+    }
+    let mut stats = Stats::new();
+    let estimate = args.is_present("estimate");
+    let distinct = args.is_present("count-distinct") || estimate;
+    let query = args.value_of_os("query");
+    let subtract = args.value_of_os("subtract");
+    let set_op = if args.is_present("intersect") {
+        Some(SetOp::Intersect)
+    } else if args.is_present("difference") {
+        Some(SetOp::Difference)
+    } else if args.is_present("membership") {
+        Some(SetOp::Membership)
+    } else {
+        None
+    };
+    let layout = (sep, args.is_present("count-last"));
+    let per_file: Option<(&[u8], RecordWriter)> = if !args.is_present("per-file") {
+        None
+    } else if args.is_present("csv") {
+        Some((b",", write_csv))
+    } else if args.is_present("tsv") {
+        Some((b"\t", write_tsv))
+    } else {
+        Some((sep.as_bytes(), |out, line| out.write_all(line)))
+    };
+    let all_seen = AtomicBool::new(true);
+    let cmd = |out: &mut dyn Write,
+               dupes: Option<&mut dyn Write>,
+               inputs: &[OsString],
+               stats: &mut Stats| match (report.groups, count) {
+        _ if query.is_some() => {
+            if !query_cmd(out, inputs, &opts, query.unwrap(), stats)? {
+                all_seen.store(false, atomic::Ordering::Relaxed);
+            }
+            Ok(())
+        }
+        _ if set_op.is_some() => set_cmd(out, inputs, &opts, set_op.unwrap(), stats),
+        _ if subtract.is_some() => {
+            let baseline = subtract.unwrap();
+            subtract_cmd(out, inputs, baseline, &opts, &report, layout, stats)
+        }
+        _ if distinct => distinct_cmd(out, inputs, &opts, estimate, stats),
+        (Some(grouping), _) => group_cmd(out, inputs, &opts, &report, grouping, stats),
+        _ if per_file.is_some() => {
+            per_file_cmd(out, inputs, &opts, &report, per_file.unwrap(), stats)
+        }
+        (None, true) => count_cmd(out, inputs, &opts, &report, stats),
+        (None, false) => uniq_cmd(out, dupes, inputs, &opts, stats),
+    };
+    signal::install()?;
+    if let Some(path) = args.value_of_os("serve") {
+        return server::serve::<BuildHasherDefault<IdentityHasher>>(path, opts.capacity);
+    }
+    // The counts so far are printed when interrupted; but not in place,
+    // so the inputs aren't replaced by partial counts
+    let interruptible =
+        count && report.groups.is_none() && !distinct && !args.is_present("in-place");
+    if interruptible {
+        signal::install_interrupt()?;
+    }
+    let show_stats = args.is_present("stats");
+    let mut run = |out: &mut Output, inputs: &[OsString]| {
+        if !show_stats {
+            return cmd(out, dupes.as_mut().map(|d| d as _), inputs, &mut stats);
+        }
+        // Only counted when needed, as every write goes through it
+        let mut out = Counted::new(out);
+        let result = cmd(&mut out, dupes.as_mut().map(|d| d as _), inputs, &mut stats);
+        stats.bytes_out += out.bytes;
+        result
+    };
+
+    if args.is_present("in-place") {
+        let suffix = args.value_of_os("in-place");
+        for path in &inputs {
+            if path == "-" {
+                return Err(anyhow!("cannot edit stdin in place"));
+            }
+            let mut out = Output::in_place(path, suffix, write_buffer)?;
+            run(&mut out, slice::from_ref(path))?;
+            out.commit()?;
+        }
+    } else {
+        let compression = args
+            .value_of("compress")
+            .map(|c| Compression::from_name(c).unwrap());
+        let mut out = Output::open(args.value_of_os("output"), compression, write_buffer)?;
+        match args.is_present("per-file-reset") {
+            true if file_threads > 1 => parallel::map_inputs(
+                &inputs,
+                file_threads,
+                |path| {
+                    let (mut buf, mut file_stats) = (Vec::new(), Stats::new());
+                    cmd(&mut buf, None, slice::from_ref(path), &mut file_stats)?;
+                    Ok((buf, file_stats))
+                },
+                |(buf, file_stats)| {
+                    out.write_all(&buf)?;
+                    stats.records += file_stats.records;
+                    stats.unique += file_stats.unique;
+                    stats.bytes_out += buf.len() as u64;
+                    Ok(())
+                },
+            )?,
+            true => {
+                for path in &inputs {
+                    run(&mut out, slice::from_ref(path))?;
+                }
+            }
+            false => run(&mut out, &inputs)?,
+        }
+        out.commit()?;
+    }
+
+    if let Some(dupes) = dupes {
+        dupes.commit()?;
+    }
+    if show_stats {
+        stats.print(&mut io::stderr())?;
+    }
+    if interruptible && signal::interrupted() {
+        return Err(anyhow!("interrupted; the counts printed are partial"));
+    }
+    if !all_seen.into_inner() {
+        // Like grep, the status tells whether everything was found
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Whether the error is caused by the output being closed, like
+/// by `head` once it has read enough
+fn is_broken_pipe(er: &anyhow::Error) -> bool {
+    er.chain()
+        .any(|cause| match cause.downcast_ref::<io::Error>() {
+            Some(e) => e.kind() == io::ErrorKind::BrokenPipe,
+            None => false,
+        })
 }
 
 fn main() {
     if let Err(er) = try_main() {
-        println!("{}", er);
+        if is_broken_pipe(&er) {
+            // Quietly, with the status of being killed by SIGPIPE
+            process::exit(128 + libc::SIGPIPE);
+        }
+        eprintln!("huniq: {:#}", er);
+        process::exit(1);
     }
 }
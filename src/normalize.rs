@@ -0,0 +1,182 @@
+//! Unicode normalization, so text composed of different but equivalent
+//! characters compares equal, like `é` written as one character or as
+//! `e` followed by a combining accent.
+//!
+//! The characters are decomposed, put into the canonical order and
+//! composed again, as described by Unicode Standard Annex #15; the
+//! tables in [crate::unicode_tables] are generated from the Unicode
+//! database.
+
+use crate::unicode_tables::{CANONICAL, COMBINING_CLASSES, COMPATIBILITY, COMPOSITIONS};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+/// Normalization forms
+#[derive(Clone, Copy)]
+pub enum Form {
+    /// Canonical composition
+    Nfc,
+    /// Compatibility composition, which also unifies characters like
+    /// `ﬁ` and `fi`, or `²` and `2`
+    Nfkc,
+}
+
+impl Form {
+    pub fn from_name(name: &str) -> Option<Form> {
+        match name {
+            "nfc" => Some(Form::Nfc),
+            "nfkc" => Some(Form::Nfkc),
+            _ => None,
+        }
+    }
+}
+
+/// The text in the given normal form; bytes that are not valid UTF-8
+/// are kept as they are
+pub fn normalize(text: Cow<[u8]>, form: Form) -> Cow<[u8]> {
+    if text.is_ascii() {
+        return text;
+    }
+    let compat = matches!(form, Form::Nfkc);
+    let mut normalized = Vec::with_capacity(text.len());
+    let mut chars = Vec::new();
+    for chunk in text.utf8_chunks() {
+        chars.clear();
+        for c in chunk.valid().chars() {
+            decompose(c, compat, &mut chars);
+        }
+        reorder(&mut chars);
+        compose(&mut chars);
+        for c in &chars {
+            let mut buf = [0; 4];
+            normalized.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+        }
+        normalized.extend_from_slice(chunk.invalid());
+    }
+    match normalized == *text {
+        true => text,
+        false => Cow::Owned(normalized),
+    }
+}
+
+const HANGUL_BASE: u32 = 0xac00;
+const HANGUL_L: u32 = 0x1100;
+const HANGUL_V: u32 = 0x1161;
+const HANGUL_T: u32 = 0x11a7;
+const HANGUL_L_COUNT: u32 = 19;
+const HANGUL_V_COUNT: u32 = 21;
+const HANGUL_T_COUNT: u32 = 28;
+const HANGUL_COUNT: u32 = HANGUL_L_COUNT * HANGUL_V_COUNT * HANGUL_T_COUNT;
+
+fn combining_class(c: char) -> u8 {
+    let found = COMBINING_CLASSES.binary_search_by(|&(lo, hi, _)| {
+        if hi < c {
+            Ordering::Less
+        } else if lo > c {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    });
+    match found {
+        Ok(idx) => COMBINING_CLASSES[idx].2,
+        Err(_) => 0,
+    }
+}
+
+/// Add the full decomposition of the character
+fn decompose(c: char, compat: bool, chars: &mut Vec<char>) {
+    let s = (c as u32).wrapping_sub(HANGUL_BASE);
+    if s < HANGUL_COUNT {
+        let t = s % HANGUL_T_COUNT;
+        let jamo = |cp| char::from_u32(cp).unwrap();
+        chars.push(jamo(HANGUL_L + s / (HANGUL_V_COUNT * HANGUL_T_COUNT)));
+        chars.push(jamo(
+            HANGUL_V + s % (HANGUL_V_COUNT * HANGUL_T_COUNT) / HANGUL_T_COUNT,
+        ));
+        if t > 0 {
+            chars.push(jamo(HANGUL_T + t));
+        }
+        return;
+    }
+    let lookup = |table: &[(char, &'static [char])]| {
+        table
+            .binary_search_by_key(&c, |&(c, _)| c)
+            .ok()
+            .map(|idx| table[idx].1)
+    };
+    let parts = match compat {
+        true => lookup(COMPATIBILITY).or_else(|| lookup(CANONICAL)),
+        false => lookup(CANONICAL),
+    };
+    match parts {
+        Some(parts) => {
+            for &part in parts {
+                decompose(part, compat, chars);
+            }
+        }
+        None => chars.push(c),
+    }
+}
+
+/// Sort each run of combining characters by their combining class
+fn reorder(chars: &mut [char]) {
+    let mut start = 0;
+    while start < chars.len() {
+        let len = chars[start..]
+            .iter()
+            .take_while(|&&c| combining_class(c) != 0)
+            .count();
+        // Stable, so characters of the same class keep their order
+        chars[start..start + len].sort_by_key(|&c| combining_class(c));
+        start += len.max(1);
+    }
+}
+
+/// Compose the decomposed, reordered characters in place
+fn compose(chars: &mut Vec<char>) {
+    let mut starter: Option<usize> = None;
+    let mut len = 0;
+    for idx in 0..chars.len() {
+        let c = chars[idx];
+        let class = combining_class(c);
+        if let Some(start) = starter {
+            // Blocked by a character in between of the same or higher
+            // class, or by a starter
+            let last = combining_class(chars[len - 1]);
+            let blocked = len > start + 1 && (last == 0 || last >= class);
+            if let (false, Some(composed)) = (blocked, composition(chars[start], c)) {
+                chars[start] = composed;
+                continue;
+            }
+        }
+        if class == 0 {
+            starter = Some(len);
+        }
+        chars[len] = c;
+        len += 1;
+    }
+    chars.truncate(len);
+}
+
+/// The character the pair composes to, if any
+fn composition(first: char, second: char) -> Option<char> {
+    let (l, v) = (first as u32, second as u32);
+    if (HANGUL_L..HANGUL_L + HANGUL_L_COUNT).contains(&l)
+        && (HANGUL_V..HANGUL_V + HANGUL_V_COUNT).contains(&v)
+    {
+        let s = ((l - HANGUL_L) * HANGUL_V_COUNT + v - HANGUL_V) * HANGUL_T_COUNT;
+        return char::from_u32(HANGUL_BASE + s);
+    }
+    let s = l.wrapping_sub(HANGUL_BASE);
+    if s < HANGUL_COUNT
+        && s % HANGUL_T_COUNT == 0
+        && (HANGUL_T + 1..HANGUL_T + HANGUL_T_COUNT).contains(&v)
+    {
+        return char::from_u32(l + v - HANGUL_T);
+    }
+    COMPOSITIONS
+        .binary_search_by_key(&(first, second), |&(pair, _)| pair)
+        .ok()
+        .map(|idx| COMPOSITIONS[idx].1)
+}
@@ -0,0 +1,227 @@
+//! Destinations the results are written to.
+
+use crate::compress::Compression;
+use anyhow::{anyhow, Context, Result};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, stdout, BufWriter, StdoutLock, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, ChildStdin, Stdio};
+
+/// Default size of the output buffer
+pub const DEFAULT_BUFFER_SIZE: usize = 128 * 1024;
+
+/// Where the output goes.
+///
+/// All output is buffered, using a buffer of the given size.
+///
+/// When writing to a file, the data is written to a temporary file
+/// in the same directory first, which is then renamed into place by
+/// [Output::commit]. This way a crash never leaves a truncated result
+/// behind; if the output is dropped without being committed, the
+/// temporary file is removed.
+pub enum Output {
+    Stdout(BufWriter<StdoutLock<'static>>),
+    File {
+        sink: BufWriter<Sink>,
+        /// The temporary file; the sink writes to it, possibly indirectly
+        file: File,
+        tmp: Option<PathBuf>,
+        dst: PathBuf,
+        backup: Option<PathBuf>,
+    },
+}
+
+impl Output {
+    /// Output to the given file or stdout, compressing data written
+    /// to a file if a compression format is given.
+    pub fn open(
+        path: Option<&OsStr>,
+        compression: Option<Compression>,
+        buffer: usize,
+    ) -> Result<Output> {
+        match path {
+            Some(p) if p != "-" => Output::file(PathBuf::from(p), None, compression, buffer),
+            _ => Ok(Output::Stdout(BufWriter::with_capacity(
+                buffer,
+                stdout().lock(),
+            ))),
+        }
+    }
+
+    /// Output replacing the given file; the previous contents are
+    /// kept in a file with the backup suffix appended if one is given.
+    pub fn in_place(path: &OsStr, backup_suffix: Option<&OsStr>, buffer: usize) -> Result<Output> {
+        let backup = backup_suffix.map(|suffix| {
+            let mut name = OsString::from(path);
+            name.push(suffix);
+            PathBuf::from(name)
+        });
+        let out = Output::file(PathBuf::from(path), backup, None, buffer)?;
+        if let Output::File { file, dst, .. } = &out {
+            let perms = fs::metadata(dst)
+                .with_context(|| format!("could not open {}", dst.display()))?
+                .permissions();
+            file.set_permissions(perms)?;
+        }
+        Ok(out)
+    }
+
+    fn file(
+        dst: PathBuf,
+        backup: Option<PathBuf>,
+        compression: Option<Compression>,
+        buffer: usize,
+    ) -> Result<Output> {
+        let mut name = OsString::from(".");
+        name.push(dst.file_name().unwrap_or_else(|| OsStr::new("huniq")));
+        name.push(format!(".huniq-{}.tmp", process::id()));
+        let tmp = dst.with_file_name(name);
+
+        let file =
+            File::create(&tmp).with_context(|| format!("could not create {}", tmp.display()))?;
+        // Construct the output right away, so the temporary file is
+        // removed again should spawning the compressor fail
+        let mut out = Output::File {
+            sink: BufWriter::with_capacity(buffer, Sink::Plain(file.try_clone()?)),
+            file,
+            tmp: Some(tmp),
+            dst,
+            backup,
+        };
+        if let (Some(compression), Output::File { sink, file, .. }) = (compression, &mut out) {
+            let mut child = compression.spawn_compressor(Stdio::from(file.try_clone()?))?;
+            *sink = BufWriter::with_capacity(
+                buffer,
+                Sink::Compressed {
+                    stdin: child.stdin.take(),
+                    child,
+                    tool: compression.tool(),
+                },
+            );
+        }
+        Ok(out)
+    }
+
+    /// Flush all output and move it to its final destination
+    pub fn commit(mut self) -> Result<()> {
+        self.flush()?;
+        if let Output::File {
+            sink,
+            file,
+            tmp,
+            dst,
+            backup,
+        } = &mut self
+        {
+            sink.get_mut().finish()?;
+            file.sync_all()?;
+            if let Some(backup) = backup {
+                make_backup(dst, backup)
+                    .with_context(|| format!("could not create backup {}", backup.display()))?;
+            }
+            fs::rename(tmp.as_ref().unwrap(), &dst)
+                .with_context(|| format!("could not write {}", dst.display()))?;
+            *tmp = None;
+        }
+        Ok(())
+    }
+}
+
+/// Preserve the current contents of `path` under the name `backup`;
+/// hard links are used where possible, so this does not copy any data.
+fn make_backup(path: &Path, backup: &Path) -> io::Result<()> {
+    match fs::remove_file(backup) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs::hard_link(path, backup).or_else(|_| fs::copy(path, backup).map(|_| ()))
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout(out) => out.write(buf),
+            Output::File { sink, .. } => sink.write(buf),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Output::Stdout(out) => out.write_all(buf),
+            Output::File { sink, .. } => sink.write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout(out) => out.flush(),
+            Output::File { sink, .. } => sink.flush(),
+        }
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if let Output::File { tmp: Some(tmp), .. } = self {
+            let _ = fs::remove_file(tmp);
+        }
+    }
+}
+
+/// Writes to the temporary file of an [Output], either directly or
+/// through a compression tool running as a child process.
+pub enum Sink {
+    Plain(File),
+    Compressed {
+        /// Dropped once all data is written, signaling EOF to the child
+        stdin: Option<ChildStdin>,
+        child: Child,
+        tool: &'static str,
+    },
+}
+
+impl Sink {
+    /// Wait until all data has reached the file
+    fn finish(&mut self) -> Result<()> {
+        if let Sink::Compressed { stdin, child, tool } = self {
+            drop(stdin.take());
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(anyhow!("{} failed: {}", tool, status));
+            }
+        }
+        Ok(())
+    }
+
+    fn pipe(stdin: &mut Option<ChildStdin>) -> io::Result<&mut ChildStdin> {
+        stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(file) => file.write(buf),
+            Sink::Compressed { stdin, .. } => Sink::pipe(stdin)?.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(file) => file.flush(),
+            Sink::Compressed { stdin, .. } => Sink::pipe(stdin)?.flush(),
+        }
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        if let Sink::Compressed { child, .. } = self {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
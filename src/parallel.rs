@@ -0,0 +1,315 @@
+//! Multithreaded record processing.
+//!
+//! In both modes, a reader thread splits the inputs into chunks which
+//! are processed by a pool of worker threads.
+//!
+//! When removing duplicates, the workers hash the records in each chunk
+//! and the calling thread receives the hashed records in input order.
+//! This way hashing, which is the bulk of the work, is spread across
+//! cores, while inserting into the set of seen records and writing the
+//! output stays sequential.
+//!
+//! When counting, the workers distribute the records by their hash among
+//! a number of shard threads, each counting its records in its own map.
+//! As every record ends up in exactly one shard, the maps can simply be
+//! merged at the end.
+//!
+//! Inputs processed independently, each on its own, are instead spread
+//! across the threads as a whole; see [map_inputs].
+
+use crate::counts::Counts;
+use crate::framing::Framing;
+use crate::input::{open_input, InputOpts};
+use crate::signal;
+use ahash::RandomState as ARandomState;
+use anyhow::Result;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread::{self, Scope, ScopedJoinHandle};
+
+/// Approximate size of the chunks handed to the worker threads
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Number of chunks that may be queued per worker thread
+const QUEUE_DEPTH: usize = 4;
+
+/// A chunk of input along with the end of each record and its hash
+struct Hashed<T> {
+    data: Vec<u8>,
+    records: Vec<(usize, T)>,
+}
+
+/// Invoke `f` with every record in the inputs (including its
+/// terminator) and the result `hash` gives for it, in input order,
+/// computing the hashes on `threads` worker threads.
+pub fn for_each_hashed_record<T, H, F>(
+    inputs: &[OsString],
+    opts: InputOpts,
+    framing: &Framing,
+    threads: usize,
+    hash: H,
+    mut f: F,
+) -> Result<()>
+where
+    T: Copy + Send,
+    H: Fn(&[u8]) -> T + Sync,
+    F: FnMut(&[u8], T) -> Result<()>,
+{
+    let (chunk_tx, chunk_rx) = sync_channel::<(usize, Vec<u8>)>(threads * QUEUE_DEPTH);
+    let (hashed_tx, hashed_rx) = sync_channel::<(usize, Hashed<T>)>(threads * QUEUE_DEPTH);
+    let chunk_rx = Chunks::new(chunk_rx);
+
+    thread::scope(|scope| {
+        let reader = spawn_reader(scope, inputs, opts, framing, chunk_tx);
+
+        for _ in 0..threads {
+            let hashed_tx = hashed_tx.clone();
+            let (chunk_rx, hash) = (&chunk_rx, &hash);
+            scope.spawn(move || {
+                while let Some((seq, data)) = chunk_rx.next() {
+                    let records = hash_records(&data, framing, hash);
+                    if hashed_tx.send((seq, Hashed { data, records })).is_err() {
+                        chunk_rx.close();
+                        break;
+                    }
+                }
+            });
+        }
+        drop(hashed_tx);
+
+        // Chunks arrive out of order; hold them back until it is their turn
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        let result = hashed_rx.iter().try_for_each(|(seq, hashed)| {
+            pending.insert(seq, hashed);
+            while let Some(hashed) = pending.remove(&next) {
+                let mut start = 0;
+                for &(end, hash) in &hashed.records {
+                    f(&hashed.data[start..end], hash)?;
+                    start = end;
+                }
+                next += 1;
+            }
+            Ok(())
+        });
+        // Stop the other threads in case of an error
+        drop(hashed_rx);
+        chunk_rx.close();
+
+        let read_result = reader.join().unwrap();
+        result.and(read_result)
+    })
+}
+
+/// Count the occurrences of the `key` of each record in the inputs,
+/// distributing the records among `threads` shards. The capacity is
+/// the total number of distinct keys expected.
+pub fn count_sharded<K>(
+    inputs: &[OsString],
+    opts: InputOpts,
+    framing: &Framing,
+    threads: usize,
+    capacity: usize,
+    key: K,
+) -> Result<Counts>
+where
+    K: Fn(&[u8]) -> Option<Cow<[u8]>> + Sync,
+{
+    let (chunk_tx, chunk_rx) = sync_channel::<(usize, Vec<u8>)>(threads * QUEUE_DEPTH);
+    let chunk_rx = Chunks::new(chunk_rx);
+    let (shard_txs, shard_rxs): (Vec<_>, Vec<_>) = (0..threads)
+        .map(|_| sync_channel::<Batch>(QUEUE_DEPTH))
+        .unzip();
+    // Independent of the hasher used in the maps, so the records
+    // within each shard are still evenly distributed in its map
+    let router = ARandomState::new();
+
+    thread::scope(|scope| {
+        let reader = spawn_reader(scope, inputs, opts, framing, chunk_tx);
+
+        let shards: Vec<_> = shard_rxs
+            .into_iter()
+            .map(|rx| {
+                scope.spawn(move || {
+                    let mut counts = Counts::with_capacity(capacity / threads);
+                    for batch in rx {
+                        let mut start = 0;
+                        for &end in &batch.ends {
+                            counts.add(&batch.data[start..end]);
+                            start = end;
+                        }
+                    }
+                    counts
+                })
+            })
+            .collect();
+
+        for _ in 0..threads {
+            let shard_txs = shard_txs.clone();
+            let (chunk_rx, key, router) = (&chunk_rx, &key, &router);
+            scope.spawn(move || {
+                while let Some((_, data)) = chunk_rx.next() {
+                    let mut batches: Vec<Batch> = (0..threads).map(|_| Batch::default()).collect();
+                    for_each_record(&data, framing, |record| {
+                        let key = match key(record) {
+                            Some(key) => key,
+                            None => return,
+                        };
+                        let shard = (router.hash_one(&key) % threads as u64) as usize;
+                        let batch = &mut batches[shard];
+                        batch.data.extend_from_slice(&key);
+                        batch.ends.push(batch.data.len());
+                    });
+                    for (tx, batch) in shard_txs.iter().zip(batches) {
+                        if !batch.ends.is_empty() && tx.send(batch).is_err() {
+                            chunk_rx.close();
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+        drop(shard_txs);
+
+        let read_result = reader.join().unwrap();
+        let mut maps: Vec<Counts> = shards.into_iter().map(|s| s.join().unwrap()).collect();
+        read_result?;
+
+        maps.sort_unstable_by_key(|m| Reverse(m.len()));
+        let mut merged = maps.remove(0);
+        for map in maps {
+            merged.merge_disjoint(map);
+        }
+        Ok(merged)
+    })
+}
+
+/// Invoke `f` with each input on `threads` worker threads, passing
+/// the results to `g` in input order.
+///
+/// The result for an input is held back until the results for all
+/// inputs before it have been passed on.
+pub fn map_inputs<T, F, G>(inputs: &[OsString], threads: usize, f: F, mut g: G) -> Result<()>
+where
+    T: Send,
+    F: Fn(&OsString) -> Result<T> + Sync,
+    G: FnMut(T) -> Result<()>,
+{
+    let (tx, rx) = sync_channel::<(usize, Result<T>)>(threads * QUEUE_DEPTH);
+    let next_input = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let tx = tx.clone();
+            let (next_input, f) = (&next_input, &f);
+            scope.spawn(move || loop {
+                let idx = next_input.fetch_add(1, Ordering::Relaxed);
+                if idx >= inputs.len() || signal::interrupted() {
+                    break;
+                }
+                if tx.send((idx, f(&inputs[idx]))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        let result = rx.iter().try_for_each(|(idx, result)| {
+            pending.insert(idx, result);
+            while let Some(result) = pending.remove(&next) {
+                g(result?)?;
+                next += 1;
+            }
+            Ok(())
+        });
+        // Stop the other threads in case of an error
+        drop(rx);
+        result
+    })
+}
+
+/// Records (without terminators) sent to a shard
+#[derive(Default)]
+struct Batch {
+    data: Vec<u8>,
+    ends: Vec<usize>,
+}
+
+/// Spawn the thread sending the chunks of all inputs to `tx`
+fn spawn_reader<'scope>(
+    scope: &'scope Scope<'scope, '_>,
+    inputs: &'scope [OsString],
+    opts: InputOpts,
+    framing: &'scope Framing,
+    tx: SyncSender<(usize, Vec<u8>)>,
+) -> ScopedJoinHandle<'scope, Result<()>> {
+    scope.spawn(move || {
+        let mut seq = 0;
+        for path in inputs {
+            let mut open = true;
+            open_input(path, opts)?.for_each_chunk(framing, CHUNK_SIZE, |chunk| {
+                open = tx.send((seq, chunk)).is_ok() && !signal::interrupted();
+                seq += 1;
+                open
+            })?;
+            if !open {
+                break; // the receiving end stopped early, or interrupted
+            }
+        }
+        Ok(())
+    })
+}
+
+/// A chunk of input and its position among the chunks
+type Chunk = (usize, Vec<u8>);
+
+/// The receiving end of the chunks, shared by the workers
+struct Chunks(Mutex<Option<Receiver<Chunk>>>);
+
+impl Chunks {
+    fn new(rx: Receiver<Chunk>) -> Chunks {
+        Chunks(Mutex::new(Some(rx)))
+    }
+
+    /// The next chunk; none once all were received or it is closed
+    fn next(&self) -> Option<Chunk> {
+        self.0.lock().unwrap().as_ref()?.recv().ok()
+    }
+
+    /// Stop receiving chunks, so the reader stops instead of waiting
+    /// for room in the queue forever
+    fn close(&self) {
+        self.0.lock().unwrap().take();
+    }
+}
+
+fn hash_records<T, H: Fn(&[u8]) -> T>(data: &[u8], framing: &Framing, hash: &H) -> Vec<(usize, T)> {
+    let mut records = Vec::new();
+    let mut end = 0;
+    for_each_record(data, framing, |record| {
+        end += record.len();
+        records.push((end, hash(record)));
+    });
+    records
+}
+
+/// Invoke `f` for every record in the chunk, including its terminator
+fn for_each_record<F: FnMut(&[u8])>(data: &[u8], framing: &Framing, mut f: F) {
+    let mut start = 0;
+    while start < data.len() {
+        let end = match framing.record_end(&data[start..], true) {
+            Some(len) => start + len,
+            None => data.len(),
+        };
+        f(&data[start..end]);
+        start = end;
+    }
+}
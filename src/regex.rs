@@ -0,0 +1,529 @@
+//! A small regular expression engine, for extracting keys from records.
+//!
+//! The pattern is compiled into a program for a Pike VM, which runs all
+//! alternatives in lockstep; matching takes time linear in the length
+//! of the record, whatever the pattern. Like most engines, the match
+//! found is the leftmost one, preferring earlier alternatives and
+//! greedy repetitions.
+//!
+//! Supported are literals, `.`, classes like `[a-z_]` or `[^,]`, the
+//! escapes `\d`, `\w`, `\s` (ASCII only) and their negations, anchors
+//! `^` and `$`, groups `(…)` and `(?:…)`, alternation `|` and the
+//! repetitions `*`, `+`, `?` and `{n,m}`, each optionally lazy. Patterns
+//! match bytes, so a `.` or class matches a single byte of UTF-8.
+
+use anyhow::{anyhow, Result};
+
+/// Largest number of repetitions `{n,m}` may ask for
+const MAX_REPEAT: u32 = 1000;
+
+pub struct Regex {
+    program: Vec<Inst>,
+    /// Number of capture groups, not counting the whole match
+    groups: usize,
+}
+
+enum Inst {
+    Byte(Class),
+    /// Continue at both, preferring the first
+    Split(usize, usize),
+    Jump(usize),
+    /// Record the position in the given slot
+    Save(usize),
+    Start,
+    End,
+    Match,
+}
+
+/// A set of bytes
+#[derive(Clone)]
+struct Class([u64; 4]);
+
+impl Class {
+    fn empty() -> Class {
+        Class([0; 4])
+    }
+
+    fn byte(b: u8) -> Class {
+        let mut class = Class::empty();
+        class.add(b, b);
+        class
+    }
+
+    fn add(&mut self, from: u8, to: u8) {
+        for b in from..=to {
+            self.0[b as usize / 64] |= 1 << (b % 64);
+        }
+    }
+
+    fn union(&mut self, other: &Class) {
+        for (word, other) in self.0.iter_mut().zip(other.0) {
+            *word |= other;
+        }
+    }
+
+    fn negate(mut self) -> Class {
+        for word in &mut self.0 {
+            *word = !*word;
+        }
+        self
+    }
+
+    fn contains(&self, b: u8) -> bool {
+        self.0[b as usize / 64] & (1 << (b % 64)) != 0
+    }
+}
+
+enum Node {
+    Class(Class),
+    Start,
+    End,
+    /// A group, capturing into the given group unless none
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+        greedy: bool,
+    },
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex> {
+        let mut parser = Parser {
+            pattern: pattern.as_bytes(),
+            pos: 0,
+            groups: 0,
+        };
+        let node = parser.alternate()?;
+        if parser.pos < parser.pattern.len() {
+            return Err(parser.error("unmatched `)`"));
+        }
+        let mut program = vec![Inst::Save(0)];
+        compile(&node, &mut program);
+        program.push(Inst::Save(1));
+        program.push(Inst::Match);
+        Ok(Regex {
+            program,
+            groups: parser.groups,
+        })
+    }
+
+    /// The part of the text matched by the first group, or by the
+    /// whole pattern if it has no groups; none if there is no match,
+    /// or the group did not participate in it
+    pub fn extract<'a>(&self, text: &'a [u8]) -> Option<&'a [u8]> {
+        let slots = self.find(text)?;
+        let group = if self.groups > 0 { 1 } else { 0 };
+        match (slots[group * 2], slots[group * 2 + 1]) {
+            (Some(start), Some(end)) => Some(&text[start..end]),
+            _ => None,
+        }
+    }
+
+    /// The start and end of each match, not overlapping; an empty
+    /// match right after another is skipped
+    pub fn matches(&self, text: &[u8]) -> Vec<(usize, usize)> {
+        let mut matches: Vec<(usize, usize)> = Vec::new();
+        let mut pos = 0;
+        while pos <= text.len() {
+            let (start, end) = match self.find_at(text, pos) {
+                Some(slots) => (slots[0].unwrap(), slots[1].unwrap()),
+                None => break,
+            };
+            let after_last = matches.last().is_some_and(|&(_, last)| last == start);
+            if start < end || !after_last {
+                matches.push((start, end));
+            }
+            pos = match start == end {
+                true => end + 1,
+                false => end,
+            };
+        }
+        matches
+    }
+
+    fn find(&self, text: &[u8]) -> Option<Vec<Option<usize>>> {
+        self.find_at(text, 0)
+    }
+
+    /// The start and end of the whole match and each group, as slots,
+    /// for the first match starting at `start` or later
+    fn find_at(&self, text: &[u8], start: usize) -> Option<Vec<Option<usize>>> {
+        let slots = (self.groups + 1) * 2;
+        let mut current = Threads::new(self.program.len());
+        let mut next = Threads::new(self.program.len());
+        let mut matched = None;
+        for pos in start..=text.len() {
+            // Start another attempt, of lower priority than those
+            // started earlier, until a match is found
+            if matched.is_none() {
+                self.add(&mut current, 0, pos, text, vec![None; slots]);
+            }
+            if current.list.is_empty() {
+                break;
+            }
+            for (pc, caps) in current.list.drain(..) {
+                match &self.program[pc] {
+                    Inst::Match => {
+                        // Threads after this one have lower priority
+                        matched = Some(caps);
+                        break;
+                    }
+                    Inst::Byte(class) if pos < text.len() && class.contains(text[pos]) => {
+                        self.add(&mut next, pc + 1, pos + 1, text, caps);
+                    }
+                    _ => {}
+                }
+            }
+            current.clear();
+            std::mem::swap(&mut current, &mut next);
+        }
+        matched
+    }
+
+    /// Add the thread along with all threads reachable from it without
+    /// consuming a byte, in order of priority
+    fn add(
+        &self,
+        threads: &mut Threads,
+        pc: usize,
+        pos: usize,
+        text: &[u8],
+        mut caps: Vec<Option<usize>>,
+    ) {
+        if threads.visited[pc] {
+            return;
+        }
+        threads.visited[pc] = true;
+        match self.program[pc] {
+            Inst::Jump(to) => self.add(threads, to, pos, text, caps),
+            Inst::Split(first, second) => {
+                self.add(threads, first, pos, text, caps.clone());
+                self.add(threads, second, pos, text, caps);
+            }
+            Inst::Save(slot) => {
+                if slot < caps.len() {
+                    caps[slot] = Some(pos);
+                }
+                self.add(threads, pc + 1, pos, text, caps);
+            }
+            Inst::Start if pos == 0 => self.add(threads, pc + 1, pos, text, caps),
+            Inst::End if pos == text.len() => self.add(threads, pc + 1, pos, text, caps),
+            Inst::Start | Inst::End => {}
+            Inst::Byte(_) | Inst::Match => threads.list.push((pc, caps)),
+        }
+    }
+}
+
+/// The threads at one position of the text
+struct Threads {
+    list: Vec<(usize, Vec<Option<usize>>)>,
+    /// Instructions already added at this position
+    visited: Vec<bool>,
+}
+
+impl Threads {
+    fn new(len: usize) -> Threads {
+        Threads {
+            list: Vec::new(),
+            visited: vec![false; len],
+        }
+    }
+
+    fn clear(&mut self) {
+        self.list.clear();
+        self.visited.iter_mut().for_each(|v| *v = false);
+    }
+}
+
+fn compile(node: &Node, program: &mut Vec<Inst>) {
+    match node {
+        Node::Class(class) => program.push(Inst::Byte(class.clone())),
+        Node::Start => program.push(Inst::Start),
+        Node::End => program.push(Inst::End),
+        Node::Group(node, None) => compile(node, program),
+        Node::Group(node, Some(group)) => {
+            program.push(Inst::Save(group * 2));
+            compile(node, program);
+            program.push(Inst::Save(group * 2 + 1));
+        }
+        Node::Concat(nodes) => nodes.iter().for_each(|node| compile(node, program)),
+        Node::Alternate(nodes) => {
+            let mut jumps = Vec::new();
+            for (idx, node) in nodes.iter().enumerate() {
+                if idx + 1 == nodes.len() {
+                    compile(node, program);
+                    break;
+                }
+                let split = program.len();
+                program.push(Inst::Split(split + 1, 0));
+                compile(node, program);
+                jumps.push(program.len());
+                program.push(Inst::Jump(0));
+                let next = program.len();
+                program[split] = Inst::Split(split + 1, next);
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Inst::Jump(end);
+            }
+        }
+        Node::Repeat {
+            node,
+            min,
+            max,
+            greedy,
+        } => {
+            for _ in 0..*min {
+                compile(node, program);
+            }
+            let split = |program: &mut Vec<Inst>, at: usize, body: usize, exit: usize| {
+                program[at] = match greedy {
+                    true => Inst::Split(body, exit),
+                    false => Inst::Split(exit, body),
+                };
+            };
+            match max {
+                // Loop back to a split deciding whether to go on
+                None => {
+                    let at = program.len();
+                    program.push(Inst::Jump(0));
+                    compile(node, program);
+                    program.push(Inst::Jump(at));
+                    let exit = program.len();
+                    split(program, at, at + 1, exit);
+                }
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Inst::Jump(0));
+                        compile(node, program);
+                    }
+                    let exit = program.len();
+                    for at in splits {
+                        split(program, at, at + 1, exit);
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    pattern: &'a [u8],
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.pattern.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        let found = self.peek() == Some(b);
+        self.pos += found as usize;
+        found
+    }
+
+    fn next(&mut self) -> Result<u8> {
+        let b = self.peek().ok_or_else(|| self.error("unexpected end"))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn error(&self, msg: &str) -> anyhow::Error {
+        anyhow!(
+            "invalid regex `{}`: {} at offset {}",
+            String::from_utf8_lossy(self.pattern),
+            msg,
+            self.pos
+        )
+    }
+
+    fn alternate(&mut self) -> Result<Node> {
+        let mut nodes = vec![self.concat()?];
+        while self.eat(b'|') {
+            nodes.push(self.concat()?);
+        }
+        Ok(match nodes.len() {
+            1 => nodes.pop().unwrap(),
+            _ => Node::Alternate(nodes),
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node> {
+        let mut nodes = Vec::new();
+        while !matches!(self.peek(), None | Some(b'|') | Some(b')')) {
+            let atom = self.atom()?;
+            nodes.push(self.repeat(atom)?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn repeat(&mut self, mut node: Node) -> Result<Node> {
+        loop {
+            let (min, max) = match self.peek() {
+                Some(b'*') => (0, None),
+                Some(b'+') => (1, None),
+                Some(b'?') => (0, Some(1)),
+                Some(b'{') => {
+                    self.pos += 1;
+                    self.counted()?
+                }
+                _ => return Ok(node),
+            };
+            self.pos += 1;
+            if matches!(node, Node::Start | Node::End) {
+                return Err(self.error("nothing to repeat"));
+            }
+            let greedy = !self.eat(b'?');
+            node = Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+                greedy,
+            };
+        }
+    }
+
+    /// Parse the rest of `{n}`, `{n,}` or `{n,m}`
+    fn counted(&mut self) -> Result<(u32, Option<u32>)> {
+        let min = self.number()?;
+        let max = match self.eat(b',') {
+            true if self.peek() == Some(b'}') => None,
+            true => Some(self.number()?),
+            false => Some(min),
+        };
+        if self.peek() != Some(b'}') {
+            return Err(self.error("expected `}`"));
+        }
+        if max.is_some_and(|max| max < min) || max.unwrap_or(min) > MAX_REPEAT {
+            return Err(self.error("invalid repetition"));
+        }
+        Ok((min, max))
+    }
+
+    fn number(&mut self) -> Result<u32> {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.pattern[start..self.pos])
+            .unwrap()
+            .parse()
+            .map_err(|_| self.error("expected a number"))
+    }
+
+    fn atom(&mut self) -> Result<Node> {
+        Ok(match self.next()? {
+            b'(' => {
+                let group = match self.eat(b'?') {
+                    true if self.eat(b':') => None,
+                    true => return Err(self.error("unsupported group")),
+                    false => {
+                        self.groups += 1;
+                        Some(self.groups)
+                    }
+                };
+                let node = self.alternate()?;
+                if !self.eat(b')') {
+                    return Err(self.error("unmatched `(`"));
+                }
+                Node::Group(Box::new(node), group)
+            }
+            b'[' => Node::Class(self.class()?),
+            b'.' => Node::Class(Class::empty().negate()),
+            b'^' => Node::Start,
+            b'$' => Node::End,
+            b'\\' => Node::Class(self.escape()?),
+            b'*' | b'+' | b'?' => return Err(self.error("nothing to repeat")),
+            b => Node::Class(Class::byte(b)),
+        })
+    }
+
+    /// Parse the rest of an escape sequence after the backslash
+    fn escape(&mut self) -> Result<Class> {
+        let digit = || {
+            let mut class = Class::empty();
+            class.add(b'0', b'9');
+            class
+        };
+        let word = || {
+            let mut class = digit();
+            class.add(b'a', b'z');
+            class.add(b'A', b'Z');
+            class.add(b'_', b'_');
+            class
+        };
+        let space = || {
+            let mut class = Class::empty();
+            for &b in b" \t\n\r\x0b\x0c" {
+                class.add(b, b);
+            }
+            class
+        };
+        Ok(match self.next()? {
+            b'd' => digit(),
+            b'D' => digit().negate(),
+            b'w' => word(),
+            b'W' => word().negate(),
+            b's' => space(),
+            b'S' => space().negate(),
+            b't' => Class::byte(b'\t'),
+            b'n' => Class::byte(b'\n'),
+            b'r' => Class::byte(b'\r'),
+            b if b.is_ascii_alphanumeric() => return Err(self.error("unsupported escape")),
+            b => Class::byte(b),
+        })
+    }
+
+    /// Parse the rest of a class after the `[`
+    fn class(&mut self) -> Result<Class> {
+        let negated = self.eat(b'^');
+        let mut class = Class::empty();
+        let mut first = true;
+        loop {
+            let from = match self.next()? {
+                b']' if !first => break,
+                b'\\' => match self.peek() {
+                    Some(b) if b.is_ascii_alphabetic() && !b"tnr".contains(&b) => {
+                        class.union(&self.escape()?);
+                        first = false;
+                        continue;
+                    }
+                    _ => self.escape()?,
+                },
+                b => Class::byte(b),
+            };
+            first = false;
+            // A single byte, unless followed by the end of a range
+            let single = (0..=255u8).find(|&b| from.contains(b));
+            match (single, self.peek(), self.pattern.get(self.pos + 1)) {
+                (Some(start), Some(b'-'), Some(&end)) if end != b']' => {
+                    self.pos += 2;
+                    let end = match end {
+                        b'\\' => {
+                            let escaped = self.escape()?;
+                            (0..=255u8).find(|&b| escaped.contains(b)).unwrap()
+                        }
+                        end => end,
+                    };
+                    if end < start {
+                        return Err(self.error("invalid range"));
+                    }
+                    class.add(start, end);
+                }
+                _ => class.union(&from),
+            }
+        }
+        Ok(match negated {
+            true => class.negate(),
+            false => class,
+        })
+    }
+}
@@ -0,0 +1,393 @@
+//! Key scripts: small expressions transforming each record into its
+//! key in-process, like `line.to_lowercase().split('?')[0]`.
+//!
+//! A script is an expression on the record, called `line`, and string
+//! literals in single or double quotes. Strings are joined with `+` and
+//! have the methods `to_lowercase()`, `to_uppercase()`, `trim()`,
+//! `trim_start()`, `trim_end()`, `replace(from, to)`,
+//! `sub_string(start, len)` and `split(sep)`; the list `split` returns
+//! is indexed like `[0]`, or `[-1]` for the last part, or joined again
+//! with `join(sep)`. Indices past the end give an empty string.
+//!
+//! The syntax follows Rhai, but this is just a tiny subset of it; the
+//! types are checked before any record is read.
+
+use anyhow::{anyhow, Result};
+use bstr::ByteSlice;
+
+pub struct Script {
+    expr: Expr,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Type {
+    Str,
+    List,
+    Int,
+}
+
+impl Type {
+    fn name(self) -> &'static str {
+        match self {
+            Type::Str => "a string",
+            Type::List => "a list",
+            Type::Int => "a number",
+        }
+    }
+}
+
+enum Expr {
+    Line,
+    Str(Vec<u8>),
+    Int(i64),
+    Concat(Box<Expr>, Box<Expr>),
+    /// A part of a list
+    Index(Box<Expr>, i64),
+    Call(Method, Box<Expr>, Vec<Expr>),
+}
+
+#[derive(Clone, Copy)]
+enum Method {
+    Lowercase,
+    Uppercase,
+    Trim,
+    TrimStart,
+    TrimEnd,
+    Replace,
+    SubString,
+    Split,
+    Join,
+}
+
+/// The methods by name, with the types of their receiver, arguments
+/// and result
+const METHODS: [(&str, Method, Type, &[Type], Type); 9] = [
+    ("to_lowercase", Method::Lowercase, Type::Str, &[], Type::Str),
+    ("to_uppercase", Method::Uppercase, Type::Str, &[], Type::Str),
+    ("trim", Method::Trim, Type::Str, &[], Type::Str),
+    ("trim_start", Method::TrimStart, Type::Str, &[], Type::Str),
+    ("trim_end", Method::TrimEnd, Type::Str, &[], Type::Str),
+    (
+        "replace",
+        Method::Replace,
+        Type::Str,
+        &[Type::Str, Type::Str],
+        Type::Str,
+    ),
+    (
+        "sub_string",
+        Method::SubString,
+        Type::Str,
+        &[Type::Int, Type::Int],
+        Type::Str,
+    ),
+    ("split", Method::Split, Type::Str, &[Type::Str], Type::List),
+    ("join", Method::Join, Type::List, &[Type::Str], Type::Str),
+];
+
+enum Value {
+    Str(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Int(i64),
+}
+
+impl Value {
+    fn into_str(self) -> Vec<u8> {
+        match self {
+            Value::Str(s) => s,
+            _ => unreachable!("types are checked when parsing"),
+        }
+    }
+
+    fn into_list(self) -> Vec<Vec<u8>> {
+        match self {
+            Value::List(list) => list,
+            _ => unreachable!("types are checked when parsing"),
+        }
+    }
+
+    fn into_int(self) -> i64 {
+        match self {
+            Value::Int(n) => n,
+            _ => unreachable!("types are checked when parsing"),
+        }
+    }
+}
+
+impl Script {
+    pub fn new(script: &str) -> Result<Script> {
+        let mut parser = Parser {
+            script: script.as_bytes(),
+            pos: 0,
+        };
+        let (expr, ty) = parser.expr()?;
+        parser.skip_space();
+        if parser.pos < parser.script.len() {
+            return Err(parser.error("unexpected character"));
+        }
+        if ty != Type::Str {
+            return Err(parser.error(&format!("expected a string, not {}", ty.name())));
+        }
+        Ok(Script { expr })
+    }
+
+    /// The key the script makes of the record
+    pub fn key(&self, line: &[u8]) -> Vec<u8> {
+        self.expr.eval(line).into_str()
+    }
+}
+
+impl Expr {
+    fn eval(&self, line: &[u8]) -> Value {
+        match self {
+            Expr::Line => Value::Str(line.to_vec()),
+            Expr::Str(s) => Value::Str(s.clone()),
+            Expr::Int(n) => Value::Int(*n),
+            Expr::Concat(a, b) => {
+                let mut s = a.eval(line).into_str();
+                s.extend_from_slice(&b.eval(line).into_str());
+                Value::Str(s)
+            }
+            Expr::Index(list, idx) => {
+                let mut list = list.eval(line).into_list();
+                let idx = match *idx < 0 {
+                    true => list.len().checked_sub(idx.unsigned_abs() as usize),
+                    false => Some(*idx as usize),
+                };
+                match idx.filter(|&idx| idx < list.len()) {
+                    Some(idx) => Value::Str(list.swap_remove(idx)),
+                    None => Value::Str(Vec::new()),
+                }
+            }
+            Expr::Call(method, recv, args) => {
+                let recv = recv.eval(line);
+                let mut args = args.iter().map(|arg| arg.eval(line));
+                let mut arg = || args.next().unwrap();
+                match method {
+                    Method::Lowercase => Value::Str(recv.into_str().to_lowercase()),
+                    Method::Uppercase => Value::Str(recv.into_str().to_uppercase()),
+                    Method::Trim => Value::Str(recv.into_str().trim().to_vec()),
+                    Method::TrimStart => Value::Str(recv.into_str().trim_start().to_vec()),
+                    Method::TrimEnd => Value::Str(recv.into_str().trim_end().to_vec()),
+                    Method::Replace => {
+                        let (from, to) = (arg().into_str(), arg().into_str());
+                        let s = recv.into_str();
+                        Value::Str(match from.is_empty() {
+                            true => s,
+                            false => s.replace(from, to),
+                        })
+                    }
+                    Method::SubString => {
+                        let (start, len) = (arg().into_int(), arg().into_int());
+                        Value::Str(sub_string(&recv.into_str(), start, len).to_vec())
+                    }
+                    Method::Split => {
+                        let (sep, s) = (arg().into_str(), recv.into_str());
+                        Value::List(match sep.is_empty() {
+                            true => s
+                                .char_indices()
+                                .map(|(start, end, _)| s[start..end].to_vec())
+                                .collect(),
+                            false => s.split_str(&sep).map(<[u8]>::to_vec).collect(),
+                        })
+                    }
+                    Method::Join => {
+                        let sep = arg().into_str();
+                        Value::Str(recv.into_list().join(&sep[..]))
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The `len` characters starting at character `start`, counted from
+/// the end if negative
+fn sub_string(s: &[u8], start: i64, len: i64) -> &[u8] {
+    let bounds: Vec<usize> = s
+        .char_indices()
+        .map(|(start, _, _)| start)
+        .chain(Some(s.len()))
+        .collect();
+    let chars = bounds.len() as i64 - 1;
+    let start = match start < 0 {
+        true => (chars + start).max(0),
+        false => start.min(chars),
+    };
+    let end = (start + len.max(0)).min(chars);
+    &s[bounds[start as usize]..bounds[end as usize]]
+}
+
+struct Parser<'a> {
+    script: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.script.get(self.pos).copied()
+    }
+
+    fn skip_space(&mut self) {
+        while self.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skip whitespace, then the byte if it comes next
+    fn eat(&mut self, b: u8) -> bool {
+        self.skip_space();
+        let found = self.peek() == Some(b);
+        self.pos += found as usize;
+        found
+    }
+
+    fn expect(&mut self, b: u8) -> Result<()> {
+        match self.eat(b) {
+            true => Ok(()),
+            false => Err(self.error(&format!("expected `{}`", b as char))),
+        }
+    }
+
+    fn error(&self, msg: &str) -> anyhow::Error {
+        anyhow!(
+            "invalid key script `{}`: {} at offset {}",
+            String::from_utf8_lossy(self.script),
+            msg,
+            self.pos
+        )
+    }
+
+    fn expr(&mut self) -> Result<(Expr, Type)> {
+        let (mut expr, mut ty) = self.term()?;
+        while self.eat(b'+') {
+            let (next, next_ty) = self.term()?;
+            if ty != Type::Str || next_ty != Type::Str {
+                return Err(self.error("only strings can be joined with `+`"));
+            }
+            expr = Expr::Concat(Box::new(expr), Box::new(next));
+            ty = Type::Str;
+        }
+        Ok((expr, ty))
+    }
+
+    fn term(&mut self) -> Result<(Expr, Type)> {
+        let (mut expr, mut ty) = self.primary()?;
+        loop {
+            if self.eat(b'.') {
+                self.skip_space();
+                let name = self.ident();
+                let &(_, method, _, params, ret) = METHODS
+                    .iter()
+                    .find(|(n, _, recv, _, _)| *n == name && *recv == ty)
+                    .ok_or_else(|| {
+                        self.error(&format!("{} has no method `{}`", ty.name(), name))
+                    })?;
+                self.expect(b'(')?;
+                let mut args = Vec::new();
+                for (idx, param) in params.iter().enumerate() {
+                    if idx > 0 {
+                        self.expect(b',')?;
+                    }
+                    let (arg, arg_ty) = self.expr()?;
+                    if arg_ty != *param {
+                        return Err(self.error(&format!(
+                            "`{}` expects {}, not {}",
+                            name,
+                            param.name(),
+                            arg_ty.name()
+                        )));
+                    }
+                    args.push(arg);
+                }
+                self.expect(b')')?;
+                expr = Expr::Call(method, Box::new(expr), args);
+                ty = ret;
+            } else if self.eat(b'[') {
+                if ty != Type::List {
+                    return Err(self.error(&format!("{} can not be indexed", ty.name())));
+                }
+                self.skip_space();
+                let idx = self.int()?;
+                self.expect(b']')?;
+                expr = Expr::Index(Box::new(expr), idx);
+                ty = Type::Str;
+            } else {
+                return Ok((expr, ty));
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<(Expr, Type)> {
+        self.skip_space();
+        match self.peek() {
+            Some(b'(') => {
+                self.pos += 1;
+                let expr = self.expr()?;
+                self.expect(b')')?;
+                Ok(expr)
+            }
+            Some(quote @ b'\'') | Some(quote @ b'"') => {
+                self.pos += 1;
+                Ok((Expr::Str(self.string(quote)?), Type::Str))
+            }
+            Some(b) if b == b'-' || b.is_ascii_digit() => Ok((Expr::Int(self.int()?), Type::Int)),
+            _ => match self.ident() {
+                "line" => Ok((Expr::Line, Type::Str)),
+                "" => Err(self.error("expected `line`, a string or a number")),
+                name => Err(self.error(&format!("unknown variable `{}`", name))),
+            },
+        }
+    }
+
+    fn ident(&mut self) -> &'a str {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|b| b == b'_' || b.is_ascii_alphanumeric())
+        {
+            self.pos += 1;
+        }
+        // Only ASCII was consumed
+        std::str::from_utf8(&self.script[start..self.pos]).unwrap()
+    }
+
+    fn int(&mut self) -> Result<i64> {
+        let start = self.pos;
+        self.pos += (self.peek() == Some(b'-')) as usize;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.script[start..self.pos])
+            .unwrap()
+            .parse()
+            .map_err(|_| self.error("expected a number"))
+    }
+
+    /// The rest of a string literal, after the opening quote
+    fn string(&mut self, quote: u8) -> Result<Vec<u8>> {
+        let mut s = Vec::new();
+        loop {
+            let b = self
+                .peek()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match b {
+                b if b == quote => return Ok(s),
+                b'\\' => {
+                    let escaped = match self.peek() {
+                        Some(b'n') => b'\n',
+                        Some(b't') => b'\t',
+                        Some(b'r') => b'\r',
+                        Some(b'0') => b'\0',
+                        Some(b @ b'\\') | Some(b @ b'\'') | Some(b @ b'"') => b,
+                        _ => return Err(self.error("unknown escape")),
+                    };
+                    self.pos += 1;
+                    s.push(escaped);
+                }
+                b => s.push(b),
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Sharing one set of seen records between several huniq processes.
+//!
+//! The server holds the set and listens on a Unix socket. Upon
+//! connecting, a client is sent the four seeds of the hasher, so all
+//! clients hash records to the same values. Then the client sends the
+//! hash of each record, eight little endian bytes, and is answered
+//! with a single byte, one if the record was not seen before and zero
+//! otherwise.
+//!
+//! A client may send more hashes before reading the answers; the
+//! answers are flushed whenever the server runs out of hashes to read.
+//!
+//! [Client] does not: each record is only printed or dropped once its
+//! answer is in, so it sends one hash at a time and waits for the
+//! answer. That takes a round trip through the socket per record,
+//! which limits a client to a few hundred thousand records a second,
+//! a small fraction of what huniq gets through on its own.
+
+use crate::signal;
+use crate::state;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::hash::BuildHasher;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Serve a set of seen records on the socket at the given path,
+/// until killed
+pub fn serve<S>(path: &OsStr, capacity: usize) -> Result<()>
+where
+    S: BuildHasher + Default + Send + 'static,
+{
+    let listener = bind(path)?;
+    let seeds = state::random_seeds();
+    let seen = Arc::new(Mutex::new(HashSet::<u64, S>::with_capacity_and_hasher(
+        capacity,
+        S::default(),
+    )));
+    for stream in listener.incoming() {
+        let (stream, seen) = (stream?, Arc::clone(&seen));
+        thread::spawn(move || {
+            // A client going away is no reason to stop serving
+            let _ = answer(stream, seeds, &seen);
+        });
+    }
+    Ok(())
+}
+
+/// Listen on the socket, replacing a stale one left by a previous server
+fn bind(path: &OsStr) -> Result<UnixListener> {
+    let context = || format!("could not listen on {}", path.to_string_lossy());
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && UnixStream::connect(path).is_err() => {
+            fs::remove_file(path).with_context(context)?;
+            UnixListener::bind(path).with_context(context)
+        }
+        result => result.with_context(context),
+    }
+}
+
+fn answer<S: BuildHasher>(
+    stream: UnixStream,
+    seeds: [u64; 4],
+    seen: &Mutex<HashSet<u64, S>>,
+) -> io::Result<()> {
+    let mut r = BufReader::new(stream.try_clone()?);
+    let mut w = BufWriter::new(stream);
+    for seed in seeds {
+        w.write_all(&seed.to_le_bytes())?;
+    }
+    loop {
+        if r.buffer().is_empty() {
+            w.flush()?;
+            if r.fill_buf()?.is_empty() {
+                return Ok(());
+            }
+        }
+        let mut hash = [0; 8];
+        r.read_exact(&mut hash)?;
+        let mut seen = seen.lock().unwrap();
+        if signal::reset_requested() {
+            seen.clear();
+        }
+        let first = seen.insert(u64::from_le_bytes(hash));
+        drop(seen);
+        w.write_all(&[first as u8])?;
+    }
+}
+
+/// A connection to a server
+pub struct Client {
+    r: BufReader<UnixStream>,
+    w: BufWriter<UnixStream>,
+    seeds: [u64; 4],
+}
+
+impl Client {
+    pub fn connect(path: &OsStr) -> Result<Client> {
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("could not connect to {}", path.to_string_lossy()))?;
+        let mut r = BufReader::new(stream.try_clone()?);
+        let mut seeds = [0; 4];
+        for seed in &mut seeds {
+            let mut buf = [0; 8];
+            r.read_exact(&mut buf)?;
+            *seed = u64::from_le_bytes(buf);
+        }
+        let w = BufWriter::new(stream);
+        Ok(Client { r, w, seeds })
+    }
+
+    /// The seeds of the hasher to hash the records with
+    pub fn seeds(&self) -> [u64; 4] {
+        self.seeds
+    }
+
+    /// Remember the hash, returning whether it was not seen before;
+    /// waits for the answer of the server
+    pub fn insert(&mut self, hash: u64) -> io::Result<bool> {
+        self.w.write_all(&hash.to_le_bytes())?;
+        self.w.flush()?;
+        let mut first = [0];
+        self.r.read_exact(&mut first)?;
+        Ok(first[0] != 0)
+    }
+}
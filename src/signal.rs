@@ -0,0 +1,82 @@
+//! Signals sent to a running huniq by the operator.
+//!
+//! The handlers just set a flag, which is checked between records, so
+//! a signal takes effect with the next record read.
+
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by SIGUSR1: print the statistics so far
+static STATS: AtomicBool = AtomicBool::new(false);
+
+/// Set by SIGUSR2: forget the records seen so far
+static RESET: AtomicBool = AtomicBool::new(false);
+
+/// Set by SIGINT and SIGTERM, if handled: stop reading
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_usr1(_: libc::c_int) {
+    STATS.store(true, Ordering::Relaxed);
+}
+
+extern "C" fn on_usr2(_: libc::c_int) {
+    RESET.store(true, Ordering::Relaxed);
+}
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+/// Install the handlers, replacing the default of terminating
+pub fn install() -> io::Result<()> {
+    handle(libc::SIGUSR1, on_usr1, 0)?;
+    handle(libc::SIGUSR2, on_usr2, 0)
+}
+
+/// Stop reading on SIGINT and SIGTERM instead of terminating right
+/// away; a second signal terminates as usual
+pub fn install_interrupt() -> io::Result<()> {
+    handle(libc::SIGINT, on_interrupt, libc::SA_RESETHAND)?;
+    handle(libc::SIGTERM, on_interrupt, libc::SA_RESETHAND)
+}
+
+fn handle(
+    signal: libc::c_int,
+    handler: extern "C" fn(libc::c_int),
+    flags: libc::c_int,
+) -> io::Result<()> {
+    // Safety: The handler only touches an atomic; restarting keeps
+    // reads from failing with EINTR
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART | flags;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Whether the statistics were asked for since the last call
+pub fn stats_requested() -> bool {
+    take(&STATS)
+}
+
+/// Whether forgetting the records seen was asked for since the last call
+pub fn reset_requested() -> bool {
+    take(&RESET)
+}
+
+/// Whether SIGINT or SIGTERM was received
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+fn take(flag: &AtomicBool) -> bool {
+    // Loading first avoids a write to the flag for every record
+    flag.load(Ordering::Relaxed) && flag.swap(false, Ordering::Relaxed)
+}
@@ -0,0 +1,210 @@
+//! Finding records similar to ones seen before, for removing near
+//! duplicates like the same error with slightly different details, or
+//! names with typos.
+//!
+//! Longer records are reduced to a SimHash, a 64 bit fingerprint in
+//! which every bit is the majority vote of the hashes of the words of
+//! the record, so records sharing most words differ in few bits. The
+//! similarity of two records is the fraction of equal bits.
+//!
+//! Short records are compared by their edit distance instead, finding
+//! those close to a record in a [BkTree].
+
+use ahash::RandomState as ARandomState;
+use bstr::ByteSlice;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+/// The SimHash of the text, of its words made of letters and digits;
+/// bytes that aren't ASCII are treated as letters
+pub fn simhash(text: &[u8]) -> u64 {
+    // Fixed, so the fingerprints are the same in every run
+    let hasher = ARandomState::with_seeds(
+        0x243f_6a88_85a3_08d3,
+        0x1319_8a2e_0370_7344,
+        0xa409_3822_299f_31d0,
+        0x082e_fa98_ec4e_6c89,
+    );
+    let mut votes = [0i64; 64];
+    let words = text
+        .split(|b| b.is_ascii() && !b.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty());
+    for word in words {
+        let hash = hasher.hash_one(word);
+        for (bit, vote) in votes.iter_mut().enumerate() {
+            match hash >> bit & 1 {
+                1 => *vote += 1,
+                _ => *vote -= 1,
+            }
+        }
+    }
+    votes
+        .iter()
+        .enumerate()
+        .filter(|&(_, &vote)| vote > 0)
+        .fold(0, |fingerprint, (bit, _)| fingerprint | 1 << bit)
+}
+
+/// The fingerprints of the records seen that were not similar to any
+/// seen before them.
+///
+/// The fingerprints are split into one more block than the number of
+/// bits that may differ, so similar fingerprints share at least one
+/// block; each block indexes the fingerprints, so only those sharing a
+/// block are compared.
+pub struct Similar {
+    /// Fingerprints differing in at most this many bits are similar
+    max_distance: u32,
+    /// The shift and mask of each block
+    blocks: Vec<(u32, u64)>,
+    /// The fingerprints by the value of each block
+    index: Vec<HashMap<u64, Vec<u64>, ARandomState>>,
+}
+
+impl Similar {
+    /// Records are similar if this fraction of their fingerprints, more
+    /// than zero, is equal
+    pub fn new(threshold: f64) -> Similar {
+        // Rounded to the bit, ignoring the error of the subtraction
+        let max_distance = (64.0 * (1.0 - threshold) + 1e-9).floor().clamp(0.0, 63.0) as u32;
+        let n = max_distance + 1;
+        let blocks = (0..n)
+            .map(|idx| {
+                let (start, end) = (idx * 64 / n, (idx + 1) * 64 / n);
+                (start, u64::MAX >> (64 - (end - start)))
+            })
+            .collect::<Vec<_>>();
+        Similar {
+            max_distance,
+            index: blocks.iter().map(|_| HashMap::default()).collect(),
+            blocks,
+        }
+    }
+
+    /// Remember the fingerprint unless it is similar to one seen,
+    /// returning whether it was not
+    pub fn insert(&mut self, fingerprint: u64) -> bool {
+        let similar = self
+            .blocks
+            .iter()
+            .zip(&self.index)
+            .any(|(&(shift, mask), index)| {
+                index
+                    .get(&(fingerprint >> shift & mask))
+                    .is_some_and(|seen| {
+                        seen.iter()
+                            .any(|seen| (seen ^ fingerprint).count_ones() <= self.max_distance)
+                    })
+            });
+        if similar {
+            return false;
+        }
+        for (&(shift, mask), index) in self.blocks.iter().zip(&mut self.index) {
+            index
+                .entry(fingerprint >> shift & mask)
+                .or_default()
+                .push(fingerprint);
+        }
+        true
+    }
+}
+
+/// Records by their edit distance, from Burkhard and Keller's "Some
+/// approaches to best-match file searching".
+///
+/// Each child of a record is at the distance of its edge from it, so
+/// by the triangle inequality, records within the maximum distance of
+/// a query can only be below the edges within that distance of the
+/// distance of the query to the record.
+pub struct BkTree {
+    max_distance: usize,
+    /// The first is the root
+    nodes: Vec<Node>,
+}
+
+struct Node {
+    key: Vec<u8>,
+    chars: Vec<char>,
+    /// The distances and indices of the children
+    children: Vec<(usize, usize)>,
+}
+
+impl BkTree {
+    /// Keys are close if at most this many characters have to be
+    /// inserted, removed or replaced to turn one into the other
+    pub fn new(max_distance: usize) -> BkTree {
+        BkTree {
+            max_distance,
+            nodes: Vec::new(),
+        }
+    }
+
+    /// The closest key added before that is close to this one, the
+    /// first added of those equally close; otherwise it is added and
+    /// returned itself
+    pub fn find_or_insert(&mut self, key: &[u8]) -> &[u8] {
+        let chars: Vec<char> = key.chars().collect();
+        if self.nodes.is_empty() {
+            self.nodes.push(Node {
+                key: key.to_vec(),
+                chars,
+                children: Vec::new(),
+            });
+            return &self.nodes[0].key;
+        }
+        let mut closest: Option<(usize, usize)> = None;
+        let mut pending = vec![0];
+        while let Some(idx) = pending.pop() {
+            let node = &self.nodes[idx];
+            let distance = edit_distance(&chars, &node.chars);
+            if distance <= self.max_distance && closest.is_none_or(|c| (distance, idx) < c) {
+                closest = Some((distance, idx));
+            }
+            let range = distance.saturating_sub(self.max_distance)..=distance + self.max_distance;
+            pending.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| range.contains(d))
+                    .map(|&(_, child)| child),
+            );
+        }
+        if let Some((_, idx)) = closest {
+            return &self.nodes[idx].key;
+        }
+
+        // Below the root, at the first node without a child at its distance
+        let mut idx = 0;
+        let distance = loop {
+            let node = &self.nodes[idx];
+            let distance = edit_distance(&chars, &node.chars);
+            match node.children.iter().find(|&&(d, _)| d == distance) {
+                Some(&(_, child)) => idx = child,
+                None => break distance,
+            }
+        };
+        let new = self.nodes.len();
+        self.nodes[idx].children.push((distance, new));
+        self.nodes.push(Node {
+            key: key.to_vec(),
+            chars,
+            children: Vec::new(),
+        });
+        &self.nodes[new].key
+    }
+}
+
+/// The Levenshtein distance of the texts, in characters
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    // The distances of the prefixes of a to the current prefix of b
+    let mut row: Vec<usize> = (0..=a.len()).collect();
+    for (j, cb) in b.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = j + 1;
+        for (i, ca) in a.iter().enumerate() {
+            let replaced = diagonal + (ca != cb) as usize;
+            diagonal = row[i + 1];
+            row[i + 1] = replaced.min(row[i] + 1).min(diagonal + 1);
+        }
+    }
+    row[a.len()]
+}
@@ -0,0 +1,358 @@
+//! Probabilistic data structures, trading exactness for a small,
+//! fixed amount of memory.
+
+use ahash::RandomState as ARandomState;
+use std::collections::HashMap;
+
+/// Number of bits of the hash selecting the register of a HyperLogLog
+const HLL_PRECISION: u32 = 14;
+
+/// Estimates the number of distinct hashes added to it.
+///
+/// Uses 2^14 one byte registers, for a standard error of about 0.8%.
+/// The cardinality is estimated with the improved estimator from Otmar
+/// Ertl's "New cardinality estimation algorithms for HyperLogLog
+/// sketches", which is accurate for small and large cardinalities alike,
+/// without the empirical bias correction of HyperLogLog++.
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; 1 << HLL_PRECISION],
+        }
+    }
+
+    /// Add a (well distributed) hash
+    pub fn insert(&mut self, hash: u64) {
+        let idx = (hash >> (64 - HLL_PRECISION)) as usize;
+        let rest = hash << HLL_PRECISION;
+        let rank = (rest.leading_zeros() + 1).min(64 - HLL_PRECISION + 1) as u8;
+        let register = &mut self.registers[idx];
+        *register = (*register).max(rank);
+    }
+
+    /// The estimated number of distinct hashes added
+    pub fn estimate(&self) -> f64 {
+        let q = 64 - HLL_PRECISION as usize;
+        let m = self.registers.len() as f64;
+        let mut histogram = [0u32; 64 + 2];
+        for &r in &self.registers {
+            histogram[r as usize] += 1;
+        }
+
+        let mut z = m * tau(1.0 - f64::from(histogram[q + 1]) / m);
+        for &count in histogram[1..=q].iter().rev() {
+            z = 0.5 * (z + f64::from(count));
+        }
+        z += m * sigma(f64::from(histogram[0]) / m);
+        m * m / (2.0 * std::f64::consts::LN_2 * z)
+    }
+}
+
+/// Number of counters in each row of a count-min sketch
+const CM_WIDTH: usize = 1 << 18;
+
+/// Number of rows of a count-min sketch
+const CM_DEPTH: usize = 4;
+
+/// Estimates the number of occurrences of each hash added to it.
+///
+/// The estimates are never too low; they are too high by at most
+/// e / 2^18 of all occurrences with a probability of 1 - e^-4.
+/// Counters are only incremented if they are the smallest for the
+/// hash (conservative update), which reduces the overestimation.
+pub struct CountMin {
+    counters: Vec<u64>,
+}
+
+impl CountMin {
+    pub fn new() -> CountMin {
+        CountMin {
+            counters: vec![0; CM_WIDTH * CM_DEPTH],
+        }
+    }
+
+    /// Count one occurrence of the (well distributed) hash,
+    /// returning its estimated number of occurrences
+    pub fn add(&mut self, hash: u64) -> u64 {
+        // One counter per row, derived from the two halves of the hash
+        let (h1, h2) = (hash as u32 as usize, (hash >> 32) as u32 as usize | 1);
+        let mut idx = [0; CM_DEPTH];
+        for (row, idx) in idx.iter_mut().enumerate() {
+            *idx = row * CM_WIDTH + (h1.wrapping_add(row.wrapping_mul(h2)) % CM_WIDTH);
+        }
+        let count = idx.iter().map(|&i| self.counters[i]).min().unwrap() + 1;
+        for &i in &idx {
+            self.counters[i] = self.counters[i].max(count);
+        }
+        count
+    }
+}
+
+/// The records with the highest counts offered to it
+pub struct TopK {
+    k: usize,
+    entries: HashMap<Vec<u8>, u64, ARandomState>,
+    /// At most the smallest count in entries, once full
+    min: u64,
+}
+
+impl TopK {
+    pub fn new(k: usize) -> TopK {
+        TopK {
+            k,
+            entries: HashMap::with_capacity_and_hasher(k + 1, ARandomState::new()),
+            min: 0,
+        }
+    }
+
+    /// Offer the current count of a record, which never decreases
+    pub fn offer(&mut self, record: &[u8], count: u64) {
+        if let Some(c) = self.entries.get_mut(record) {
+            *c = count;
+            return;
+        }
+        if self.entries.len() >= self.k {
+            if count <= self.min {
+                return;
+            }
+            // The counts may have grown since; find the actual smallest
+            let (smallest, &min) = match self.entries.iter().min_by_key(|&(_, &c)| c) {
+                Some(entry) => entry,
+                None => return, // k is zero
+            };
+            self.min = min;
+            if count <= min {
+                return;
+            }
+            let smallest = smallest.clone();
+            self.entries.remove(&smallest);
+        }
+        self.entries.insert(record.to_vec(), count);
+        if self.entries.len() == self.k {
+            self.min = self.entries.values().copied().min().unwrap_or(0);
+        }
+    }
+
+    /// The records and their counts, highest count first
+    pub fn into_sorted_vec(self) -> Vec<(Vec<u8>, u64)> {
+        let mut entries: Vec<_> = self.entries.into_iter().collect();
+        entries.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        entries
+    }
+}
+
+/// Records seen, with a fixed rate of records wrongly considered seen
+pub struct Bloom {
+    bits: Vec<u64>,
+    /// Number of bits
+    len: u64,
+    /// Number of bits set per hash
+    k: u64,
+}
+
+impl Bloom {
+    /// A filter for `n` hashes with the given false positive rate
+    pub fn new(n: usize, fpr: f64) -> Bloom {
+        let ln2 = std::f64::consts::LN_2;
+        let len = (-(n.max(1) as f64) * fpr.ln() / (ln2 * ln2))
+            .ceil()
+            .max(64.0) as u64;
+        let k = ((len as f64 / n.max(1) as f64) * ln2).round().max(1.0) as u64;
+        Bloom {
+            // Zeroed memory is mapped lazily, so this is cheap for small inputs
+            bits: vec![0; len.div_ceil(64) as usize],
+            len,
+            k,
+        }
+    }
+
+    /// A filter taking up the given number of bytes, containing the hashes.
+    ///
+    /// Sets 7 bits per hash, which is optimal for about ten bits per hash,
+    /// or a false positive rate of 1% once that many hashes are added.
+    pub fn with_memory<I: IntoIterator<Item = u64>>(bytes: usize, hashes: I) -> Bloom {
+        let words = (bytes / 8).max(1);
+        let mut bloom = Bloom {
+            bits: vec![0; words],
+            len: words as u64 * 64,
+            k: 7,
+        };
+        for hash in hashes {
+            bloom.insert(hash);
+        }
+        bloom
+    }
+
+    /// Add a (well distributed) hash, returning whether it was not seen before
+    pub fn insert(&mut self, hash: u64) -> bool {
+        let step = hash.rotate_left(32) | 1;
+        let mut new = false;
+        for i in 0..self.k {
+            let h = hash.wrapping_add(i.wrapping_mul(step));
+            // Maps the hash to 0..len without a division
+            let bit = ((u128::from(h) * u128::from(self.len)) >> 64) as usize;
+            let (word, mask) = (bit / 64, 1 << (bit % 64));
+            new |= self.bits[word] & mask == 0;
+            self.bits[word] |= mask;
+        }
+        new
+    }
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let (mut y, mut z) = (1.0, x);
+    loop {
+        x *= x;
+        let prev = z;
+        z += x * y;
+        y += y;
+        if z == prev {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let (mut y, mut z) = (1.0, 1.0 - x);
+    loop {
+        x = x.sqrt();
+        let prev = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z == prev {
+            return z / 3.0;
+        }
+    }
+}
+
+/// Number of fingerprints per bucket of a cuckoo filter
+const CUCKOO_SLOTS: usize = 4;
+
+/// Number of times fingerprints are moved to make room for a new one
+/// before the filter is considered full
+const CUCKOO_MAX_KICKS: usize = 500;
+
+/// The cuckoo filter can not take any more fingerprints
+#[derive(Debug)]
+pub struct Full;
+
+/// Records seen, like [Bloom], but supporting the removal of records.
+///
+/// Stores a 16 bit fingerprint of each hash in one of two buckets, both
+/// derived from the hash, for a false positive rate of about 0.01%.
+pub struct Cuckoo {
+    /// Zero marks an empty slot
+    buckets: Vec<[u16; CUCKOO_SLOTS]>,
+    mask: usize,
+    /// A fingerprint that could not be placed in its buckets
+    victim: Option<(usize, u16)>,
+}
+
+impl Cuckoo {
+    /// A filter with room for at least `n` hashes
+    pub fn new(n: usize) -> Cuckoo {
+        let buckets = (n.max(1) * 21 / 20)
+            .div_ceil(CUCKOO_SLOTS)
+            .next_power_of_two();
+        Cuckoo {
+            buckets: vec![[0; CUCKOO_SLOTS]; buckets],
+            mask: buckets - 1,
+            victim: None,
+        }
+    }
+
+    /// The fingerprint and first bucket of a hash
+    fn locate(&self, hash: u64) -> (u16, usize) {
+        let fingerprint = ((hash >> 48) as u16).max(1);
+        (fingerprint, hash as usize & self.mask)
+    }
+
+    /// The other bucket the fingerprint may be stored in
+    fn alternate(&self, bucket: usize, fingerprint: u16) -> usize {
+        let mixed = u64::from(fingerprint).wrapping_mul(0x5bd1_e995_5bd1_e995);
+        (bucket ^ (mixed >> 32) as usize) & self.mask
+    }
+
+    pub fn contains(&self, hash: u64) -> bool {
+        let (fingerprint, first) = self.locate(hash);
+        let second = self.alternate(first, fingerprint);
+        self.buckets[first].contains(&fingerprint)
+            || self.buckets[second].contains(&fingerprint)
+            || matches!(self.victim, Some((b, f)) if f == fingerprint && (b == first || b == second))
+    }
+
+    /// Add a hash, returning whether it was not seen before
+    pub fn insert(&mut self, hash: u64) -> Result<bool, Full> {
+        if self.contains(hash) {
+            return Ok(false);
+        }
+        if self.victim.is_some() {
+            return Err(Full);
+        }
+        let (fingerprint, first) = self.locate(hash);
+        let second = self.alternate(first, fingerprint);
+        if self.place(first, fingerprint) || self.place(second, fingerprint) {
+            return Ok(true);
+        }
+
+        // Move fingerprints to their other bucket until one fits
+        let (mut bucket, mut fingerprint) = (first, fingerprint);
+        for kick in 0..CUCKOO_MAX_KICKS {
+            let slot = (usize::from(fingerprint) + kick) % CUCKOO_SLOTS;
+            std::mem::swap(&mut fingerprint, &mut self.buckets[bucket][slot]);
+            bucket = self.alternate(bucket, fingerprint);
+            if self.place(bucket, fingerprint) {
+                return Ok(true);
+            }
+        }
+        // Nothing is lost yet; the filter refuses further records instead
+        self.victim = Some((bucket, fingerprint));
+        Ok(true)
+    }
+
+    /// Forget a hash added before, returning whether it was present
+    pub fn remove(&mut self, hash: u64) -> bool {
+        let (fingerprint, first) = self.locate(hash);
+        let second = self.alternate(first, fingerprint);
+        if let Some((b, f)) = self.victim {
+            if f == fingerprint && (b == first || b == second) {
+                self.victim = None;
+                return true;
+            }
+        }
+        for bucket in [first, second] {
+            if let Some(slot) = self.buckets[bucket].iter().position(|&f| f == fingerprint) {
+                self.buckets[bucket][slot] = 0;
+                // Make room for the victim, if any
+                if let Some((b, f)) = self.victim.take() {
+                    if !self.place(b, f) && !self.place(self.alternate(b, f), f) {
+                        self.victim = Some((b, f));
+                    }
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Store the fingerprint in an empty slot of the bucket, if any
+    fn place(&mut self, bucket: usize, fingerprint: u16) -> bool {
+        match self.buckets[bucket].iter_mut().find(|f| **f == 0) {
+            Some(slot) => {
+                *slot = fingerprint;
+                true
+            }
+            None => false,
+        }
+    }
+}
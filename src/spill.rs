@@ -0,0 +1,171 @@
+//! Removing duplicates with more distinct records than fit in memory.
+//!
+//! Once spilling starts, the records are no longer printed right away.
+//! Instead, each record is appended to a spool file and its hash along
+//! with its position is collected in a buffer. Whenever the buffer is
+//! full, it is sorted by hash, keeping only the first position of each
+//! hash, and written to disk as a run. In the end, the runs are merged;
+//! every hash's first position is marked in a bitmap of all spooled
+//! records, which is a fraction of the size of a set of their hashes.
+//! Finally the spool is read back, printing just the marked records.
+//!
+//! The hashes seen before spilling go into a run of their own; their
+//! records have been printed already, so they are never marked.
+//!
+//! The temporary files are created in the directory given by `TMPDIR`
+//! and unlinked right away, so they disappear once closed.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::convert::TryInto;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Position of the records seen before spilling
+const PRIOR: u64 = 0;
+
+pub struct Spill {
+    /// Each record, preceded by its length and its hash as eight little
+    /// endian bytes each
+    spool: BufWriter<File>,
+    /// Number of records spooled
+    spooled: u64,
+    /// Hashes and positions (starting at one) not written to a run yet
+    buffer: Vec<(u64, u64)>,
+    /// Number of entries the buffer may hold
+    limit: usize,
+    runs: Vec<File>,
+}
+
+impl Spill {
+    /// Start spilling, given the hashes seen so far
+    pub fn new<I: IntoIterator<Item = u64>>(prior: I, limit: usize) -> io::Result<Spill> {
+        let mut spill = Spill {
+            spool: BufWriter::new(temp_file()?),
+            spooled: 0,
+            buffer: Vec::with_capacity(limit),
+            limit: limit.max(1),
+            runs: Vec::new(),
+        };
+        spill
+            .buffer
+            .extend(prior.into_iter().map(|hash| (hash, PRIOR)));
+        spill.write_run()?;
+        Ok(spill)
+    }
+
+    pub fn add(&mut self, record: &[u8], hash: u64) -> io::Result<()> {
+        self.spool.write_all(&(record.len() as u64).to_le_bytes())?;
+        self.spool.write_all(&hash.to_le_bytes())?;
+        self.spool.write_all(record)?;
+        self.spooled += 1;
+        self.buffer.push((hash, self.spooled));
+        if self.buffer.len() >= self.limit {
+            self.write_run()?;
+        }
+        Ok(())
+    }
+
+    fn write_run(&mut self) -> io::Result<()> {
+        self.buffer.sort_unstable();
+        self.buffer.dedup_by_key(|&mut (hash, _)| hash);
+        let mut run = BufWriter::new(temp_file()?);
+        for &(hash, pos) in &self.buffer {
+            run.write_all(&hash.to_le_bytes())?;
+            run.write_all(&pos.to_le_bytes())?;
+        }
+        let mut run = run.into_inner().map_err(|e| e.into_error())?;
+        run.seek(SeekFrom::Start(0))?;
+        self.runs.push(run);
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Invoke `f` with every spooled record, in input order, along with
+    /// its hash and whether it is the first occurrence of the record
+    pub fn finish<F>(mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8], u64, bool) -> io::Result<()>,
+    {
+        if !self.buffer.is_empty() {
+            self.write_run()?;
+        }
+
+        let mut first = vec![0u64; self.spooled.div_ceil(64) as usize];
+        let mut runs: Vec<_> = self.runs.drain(..).map(BufReader::new).collect();
+        let mut heap = BinaryHeap::with_capacity(runs.len());
+        for (idx, run) in runs.iter_mut().enumerate() {
+            if let Some(entry) = read_entry(run)? {
+                heap.push(Reverse((entry, idx)));
+            }
+        }
+        // Entries come out ordered by hash, then position
+        let mut last = None;
+        while let Some(Reverse(((hash, pos), idx))) = heap.pop() {
+            if last != Some(hash) {
+                last = Some(hash);
+                if pos != PRIOR {
+                    let bit = pos - 1;
+                    first[(bit / 64) as usize] |= 1 << (bit % 64);
+                }
+            }
+            if let Some(entry) = read_entry(&mut runs[idx])? {
+                heap.push(Reverse((entry, idx)));
+            }
+        }
+        drop(runs);
+
+        let mut spool = self.spool.into_inner().map_err(|e| e.into_error())?;
+        spool.seek(SeekFrom::Start(0))?;
+        let mut spool = BufReader::new(spool);
+        let mut record = Vec::new();
+        for bit in 0..self.spooled {
+            let (mut len, mut hash) = ([0; 8], [0; 8]);
+            spool.read_exact(&mut len)?;
+            spool.read_exact(&mut hash)?;
+            record.clear();
+            (&mut spool)
+                .take(u64::from_le_bytes(len))
+                .read_to_end(&mut record)?;
+            let first = first[(bit / 64) as usize] & (1 << (bit % 64)) != 0;
+            f(&record, u64::from_le_bytes(hash), first)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the next hash and position from a run
+fn read_entry(run: &mut BufReader<File>) -> io::Result<Option<(u64, u64)>> {
+    let mut entry = [0; 16];
+    match run.read_exact(&mut entry) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let (hash, pos) = entry.split_at(8);
+    Ok(Some((
+        u64::from_le_bytes(hash.try_into().unwrap()),
+        u64::from_le_bytes(pos.try_into().unwrap()),
+    )))
+}
+
+/// Create an anonymous temporary file
+fn temp_file() -> io::Result<File> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = format!(
+        ".huniq-{}-{}.spill",
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let path = env::temp_dir().join(name);
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    Ok(file)
+}
@@ -0,0 +1,171 @@
+//! Writing the counts into an SQLite database.
+//!
+//! The system's libsqlite3 is used directly through its C interface;
+//! only the handful of functions needed for a bulk insert are declared.
+
+use anyhow::{anyhow, Context, Result};
+use std::ffi::{CStr, CString, OsStr};
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::ptr;
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_DONE: c_int = 101;
+const SQLITE_OPEN_READWRITE: c_int = 0x2;
+const SQLITE_OPEN_CREATE: c_int = 0x4;
+
+#[repr(C)]
+struct Sqlite3 {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct Stmt {
+    _private: [u8; 0],
+}
+
+/// Called by sqlite to free bound data; `None` means the data is static
+type Destructor = Option<unsafe extern "C" fn(*mut c_void)>;
+
+#[link(name = "sqlite3")]
+extern "C" {
+    fn sqlite3_open_v2(
+        filename: *const c_char,
+        db: *mut *mut Sqlite3,
+        flags: c_int,
+        vfs: *const c_char,
+    ) -> c_int;
+    fn sqlite3_close(db: *mut Sqlite3) -> c_int;
+    fn sqlite3_errmsg(db: *mut Sqlite3) -> *const c_char;
+    fn sqlite3_exec(
+        db: *mut Sqlite3,
+        sql: *const c_char,
+        callback: *const c_void,
+        arg: *mut c_void,
+        errmsg: *mut *mut c_char,
+    ) -> c_int;
+    fn sqlite3_prepare_v2(
+        db: *mut Sqlite3,
+        sql: *const c_char,
+        len: c_int,
+        stmt: *mut *mut Stmt,
+        tail: *mut *const c_char,
+    ) -> c_int;
+    fn sqlite3_bind_blob64(
+        stmt: *mut Stmt,
+        idx: c_int,
+        data: *const c_void,
+        len: u64,
+        destructor: Destructor,
+    ) -> c_int;
+    fn sqlite3_bind_int64(stmt: *mut Stmt, idx: c_int, value: i64) -> c_int;
+    fn sqlite3_step(stmt: *mut Stmt) -> c_int;
+    fn sqlite3_reset(stmt: *mut Stmt) -> c_int;
+    fn sqlite3_finalize(stmt: *mut Stmt) -> c_int;
+}
+
+/// An open database connection
+pub struct Database {
+    db: *mut Sqlite3,
+}
+
+impl Database {
+    /// Open the database at the given path, creating it if necessary
+    pub fn open(path: &OsStr) -> Result<Database> {
+        let name = CString::new(path.as_bytes()).context("invalid database path")?;
+        let mut db = ptr::null_mut();
+        let flags = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE;
+        // Safety: The handle is closed by Drop, even if opening failed
+        let rc = unsafe { sqlite3_open_v2(name.as_ptr(), &mut db, flags, ptr::null()) };
+        let db = Database { db };
+        match rc {
+            SQLITE_OK => Ok(db),
+            _ => Err(db.error())
+                .with_context(|| format!("could not open database {}", path.to_string_lossy())),
+        }
+    }
+
+    /// The error of the last failed call
+    fn error(&self) -> anyhow::Error {
+        if self.db.is_null() {
+            return anyhow!("out of memory");
+        }
+        // Safety: The message is valid until the next call on the connection
+        let msg = unsafe { CStr::from_ptr(sqlite3_errmsg(self.db)) };
+        anyhow!("{}", msg.to_string_lossy())
+    }
+
+    fn exec(&self, sql: &str) -> Result<()> {
+        let sql = CString::new(sql).unwrap();
+        let rc = unsafe {
+            sqlite3_exec(
+                self.db,
+                sql.as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        match rc {
+            SQLITE_OK => Ok(()),
+            _ => Err(self.error()),
+        }
+    }
+
+    /// Create the table `counts (line BLOB, count INTEGER)` and
+    /// insert the records and their counts in a single transaction
+    pub fn insert_counts<'a, I>(&self, rows: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (&'a [u8], u64)>,
+    {
+        self.exec("CREATE TABLE counts (line BLOB, count INTEGER)")?;
+        self.exec("BEGIN")?;
+        let sql = b"INSERT INTO counts (line, count) VALUES (?1, ?2)";
+        let mut stmt = ptr::null_mut();
+        let rc = unsafe {
+            sqlite3_prepare_v2(
+                self.db,
+                sql.as_ptr() as *const c_char,
+                sql.len() as c_int,
+                &mut stmt,
+                ptr::null_mut(),
+            )
+        };
+        if rc != SQLITE_OK {
+            return Err(self.error());
+        }
+
+        let result = (|| {
+            for (line, count) in rows {
+                // Safety: The line outlives the step; the binding is
+                // replaced before the next one
+                let ok = unsafe {
+                    sqlite3_bind_blob64(
+                        stmt,
+                        1,
+                        line.as_ptr() as *const c_void,
+                        line.len() as u64,
+                        None,
+                    ) == SQLITE_OK
+                        && sqlite3_bind_int64(stmt, 2, count as i64) == SQLITE_OK
+                        && sqlite3_step(stmt) == SQLITE_DONE
+                        && sqlite3_reset(stmt) == SQLITE_OK
+                };
+                if !ok {
+                    return Err(self.error());
+                }
+            }
+            Ok(())
+        })();
+        unsafe { sqlite3_finalize(stmt) };
+        result?;
+
+        self.exec("COMMIT")
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        unsafe { sqlite3_close(self.db) };
+    }
+}
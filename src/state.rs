@@ -0,0 +1,150 @@
+//! Saving what was seen in one run, to continue from it in the next.
+//!
+//! A state file starts with a magic number and the kind of state:
+//! Either the hashes of the records seen when removing duplicates,
+//! preceded by the seeds of the hasher, so later runs hash the same
+//! records to the same values; or the records along with their counts.
+//! All numbers are eight little endian bytes.
+//!
+//! The hashes only match when the state is loaded by the same build
+//! of huniq, as the hash function may change between versions.
+
+use crate::output::{Output, DEFAULT_BUFFER_SIZE};
+use ahash::RandomState as ARandomState;
+use anyhow::{anyhow, Context, Result};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::io::{self, BufReader, Read, Write};
+
+const MAGIC: &[u8; 8] = b"huniqst1";
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Seen = 1,
+    Counts = 2,
+}
+
+/// Seeds for a hasher not known in advance
+pub fn random_seeds() -> [u64; 4] {
+    let random = ARandomState::new();
+    [0u64, 1, 2, 3].map(|i| random.hash_one(i))
+}
+
+/// Invoke `f` with each hash in the state file, returning the seeds
+/// they were hashed with; none if there is no such file yet
+pub fn read_seen<F: FnMut(u64)>(path: &OsStr, mut f: F) -> Result<Option<[u64; 4]>> {
+    let mut state = match open(path, Kind::Seen)? {
+        Some(state) => state,
+        None => return Ok(None),
+    };
+    let seeds = (|| -> io::Result<[u64; 4]> {
+        let mut seeds = [0; 4];
+        for seed in &mut seeds {
+            *seed = read_u64(&mut state)?;
+        }
+        for _ in 0..read_u64(&mut state)? {
+            f(read_u64(&mut state)?);
+        }
+        Ok(seeds)
+    })()
+    .with_context(|| corrupt(path))?;
+    Ok(Some(seeds))
+}
+
+pub fn write_seen<I>(path: &OsStr, seeds: [u64; 4], len: usize, hashes: I) -> Result<()>
+where
+    I: IntoIterator<Item = u64>,
+{
+    let mut out = create(path, Kind::Seen)?;
+    for seed in seeds {
+        out.write_all(&seed.to_le_bytes())?;
+    }
+    out.write_all(&(len as u64).to_le_bytes())?;
+    for hash in hashes {
+        out.write_all(&hash.to_le_bytes())?;
+    }
+    out.commit()
+}
+
+/// Invoke `f` with each record in the state file and its count; not
+/// at all if there is no such file yet
+pub fn read_counts<F: FnMut(&[u8], u64)>(path: &OsStr, mut f: F) -> Result<()> {
+    let mut state = match open(path, Kind::Counts)? {
+        Some(state) => state,
+        None => return Ok(()),
+    };
+    let mut record = Vec::new();
+    (|| -> io::Result<()> {
+        for _ in 0..read_u64(&mut state)? {
+            let count = read_u64(&mut state)?;
+            let len = read_u64(&mut state)?;
+            record.clear();
+            (&mut state).take(len).read_to_end(&mut record)?;
+            if record.len() as u64 != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            f(&record, count);
+        }
+        Ok(())
+    })()
+    .with_context(|| corrupt(path))
+}
+
+pub fn write_counts<'a, I>(path: &OsStr, len: usize, rows: I) -> Result<()>
+where
+    I: IntoIterator<Item = (&'a [u8], u64)>,
+{
+    let mut out = create(path, Kind::Counts)?;
+    out.write_all(&(len as u64).to_le_bytes())?;
+    for (record, count) in rows {
+        out.write_all(&count.to_le_bytes())?;
+        out.write_all(&(record.len() as u64).to_le_bytes())?;
+        out.write_all(record)?;
+    }
+    out.commit()
+}
+
+fn open(path: &OsStr, kind: Kind) -> Result<Option<BufReader<File>>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("could not open state {}", path.to_string_lossy()))
+        }
+    };
+    let mut state = BufReader::new(file);
+    let mut header = [0; 9];
+    state
+        .read_exact(&mut header)
+        .with_context(|| corrupt(path))?;
+    if &header[..8] != MAGIC {
+        return Err(anyhow!("{} is not a state file", path.to_string_lossy()));
+    }
+    if header[8] != kind as u8 {
+        let held = match kind {
+            Kind::Seen => "counts, not the records seen",
+            Kind::Counts => "the records seen, not counts",
+        };
+        return Err(anyhow!("state {} holds {}", path.to_string_lossy(), held));
+    }
+    Ok(Some(state))
+}
+
+fn create(path: &OsStr, kind: Kind) -> Result<Output> {
+    let mut out = Output::open(Some(path), None, DEFAULT_BUFFER_SIZE)?;
+    out.write_all(MAGIC)?;
+    out.write_all(&[kind as u8])?;
+    Ok(out)
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn corrupt(path: &OsStr) -> String {
+    format!("could not read state {}", path.to_string_lossy())
+}
@@ -0,0 +1,116 @@
+//! Statistics about a run, printed with `--stats`, or on SIGUSR1 while
+//! still reading.
+
+use std::fs;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Bytes read from all inputs, after decompression; updated by the
+/// inputs once they have been read, from whatever thread reads them
+static BYTES_READ: AtomicU64 = AtomicU64::new(0);
+
+pub fn add_bytes_read(n: u64) {
+    BYTES_READ.fetch_add(n, Ordering::Relaxed);
+}
+
+pub struct Stats {
+    start: Instant,
+    /// Records read from all inputs
+    pub records: u64,
+    /// Distinct records among them
+    pub unique: u64,
+    /// Bytes written to the output
+    pub bytes_out: u64,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            start: Instant::now(),
+            records: 0,
+            unique: 0,
+            bytes_out: 0,
+        }
+    }
+
+    pub fn print(&self, out: &mut dyn Write) -> io::Result<()> {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let bytes_in = BYTES_READ.load(Ordering::Relaxed);
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+        writeln!(out, "records read:          {}", self.records)?;
+        writeln!(out, "unique records:        {}", self.unique)?;
+        writeln!(
+            out,
+            "duplicates suppressed: {}",
+            self.records.saturating_sub(self.unique)
+        )?;
+        writeln!(out, "bytes in:              {}", bytes_in)?;
+        writeln!(out, "bytes out:             {}", self.bytes_out)?;
+        writeln!(out, "elapsed:               {:.3}s", elapsed)?;
+        writeln!(
+            out,
+            "throughput:            {:.1} MiB/s, {:.0} records/s",
+            mib(bytes_in) / elapsed,
+            self.records as f64 / elapsed
+        )
+    }
+
+    /// Print the statistics so far, given the records read and the
+    /// distinct records among them by the running command
+    pub fn print_progress(&self, records: u64, unique: u64, out: &mut dyn Write) -> io::Result<()> {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let bytes_in = BYTES_READ.load(Ordering::Relaxed);
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+        writeln!(out, "records read:          {}", self.records + records)?;
+        writeln!(out, "unique records:        {}", self.unique + unique)?;
+        if let Some(resident) = resident_memory() {
+            writeln!(out, "memory:                {:.1} MiB", mib(resident))?;
+        }
+        writeln!(out, "elapsed:               {:.3}s", elapsed)?;
+        writeln!(
+            out,
+            "throughput:            {:.1} MiB/s, {:.0} records/s",
+            mib(bytes_in) / elapsed,
+            (self.records + records) as f64 / elapsed
+        )
+    }
+}
+
+/// Bytes of memory resident, as far as the system tells
+fn resident_memory() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page_size as u64)
+}
+
+/// Counts the bytes written through it
+pub struct Counted<'a> {
+    inner: &'a mut dyn Write,
+    pub bytes: u64,
+}
+
+impl<'a> Counted<'a> {
+    pub fn new(inner: &'a mut dyn Write) -> Counted<'a> {
+        Counted { inner, bytes: 0 }
+    }
+}
+
+impl Write for Counted<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.bytes += buf.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,279 @@
+//! Keeping the set of seen records in a file, for sets too big for
+//! memory or to be kept across runs.
+//!
+//! The file holds an open addressing hash table of the hashes, mapped
+//! into memory; the operating system pages in the parts of it needed
+//! and writes the changes back. The most recently seen hashes are kept
+//! in an [Lru] in front of it, so frequent records don't touch the file.
+//!
+//! The file starts with a header of eight byte little endian numbers:
+//! A magic number, the four seeds of the hasher, the number of hashes
+//! stored and the number of slots. The slots follow, each holding a
+//! hash or zero if empty; a hash of zero is stored as one.
+//!
+//! Several processes may use the same store at once; each insertion
+//! happens with the file locked. A process growing the store writes a
+//! new file, moves it into place and marks the old one as moved, so
+//! the other processes open the new file once they get the lock.
+//!
+//! Like the state files, a store only works with the build of huniq
+//! that created it.
+
+use crate::state;
+use crate::window::Lru;
+use anyhow::{anyhow, Context, Result};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::hash::BuildHasher;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::slice;
+
+const MAGIC: u64 = u64::from_le_bytes(*b"huniqdb1");
+/// Replaces the magic number of a store replaced by a bigger one
+const MOVED: u64 = u64::from_le_bytes(*b"huniqmv1");
+/// Words of the header, and positions of its fields
+const HEADER: usize = 7;
+const SEEDS: usize = 1;
+const LEN: usize = 5;
+const SLOTS: usize = 6;
+/// Slots of a new store; eight MiB
+const INITIAL_SLOTS: usize = 1 << 20;
+/// Number of the most recently seen hashes kept in memory
+const CACHED: usize = 1 << 18;
+
+pub struct Store<S> {
+    path: PathBuf,
+    file: File,
+    map: Map,
+    cache: Lru<S>,
+}
+
+impl<S: BuildHasher + Default> Store<S> {
+    /// Open the store at the given path, creating it if necessary
+    pub fn open(path: &OsStr) -> Result<Store<S>> {
+        let path = PathBuf::from(path);
+        let context = || format!("could not open store {}", path.display());
+        let file = match open_file(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let tmp = tmp_path(&path);
+                let (file, _) =
+                    create(&tmp, INITIAL_SLOTS, state::random_seeds()).with_context(context)?;
+                // Linking fails if another process created the store meanwhile
+                let linked = fs::hard_link(&tmp, &path);
+                fs::remove_file(&tmp).with_context(context)?;
+                match linked {
+                    Ok(()) => file,
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                        open_file(&path).with_context(context)?
+                    }
+                    Err(e) => return Err(e).with_context(context),
+                }
+            }
+            file => file.with_context(context)?,
+        };
+        let map = Map::new(&file).with_context(context)?;
+        let words = map.words();
+        let valid = words.len() >= HEADER
+            && matches!(u64::from_le(words[0]), MAGIC | MOVED)
+            && (words.len() - HEADER) as u64 == u64::from_le(words[SLOTS]);
+        if !valid {
+            return Err(anyhow!("{} is not a store", path.display()));
+        }
+        Ok(Store {
+            path,
+            file,
+            map,
+            cache: Lru::new(CACHED),
+        })
+    }
+
+    /// The seeds of the hasher to hash the records with
+    pub fn seeds(&self) -> [u64; 4] {
+        let words = self.map.words();
+        let mut seeds = [0; 4];
+        for (seed, word) in seeds.iter_mut().zip(&words[SEEDS..SEEDS + 4]) {
+            *seed = u64::from_le(*word);
+        }
+        seeds
+    }
+
+    /// Remember the hash, returning whether it was not seen before
+    pub fn insert(&mut self, hash: u64) -> io::Result<bool> {
+        let hash = hash.max(1);
+        // Cached hashes were inserted into the store by this process
+        if self.cache.touch(hash) {
+            return Ok(false);
+        }
+        self.lock()?;
+        let first = self.insert_locked(hash);
+        flock(&self.file, libc::LOCK_UN)?;
+        // Only cached once in the store, so a failed insert is retried
+        let first = first?;
+        self.cache.insert(hash);
+        Ok(first)
+    }
+
+    fn insert_locked(&mut self, hash: u64) -> io::Result<bool> {
+        let words = self.map.words();
+        let len = u64::from_le(words[LEN]);
+        if (len + 1) * 2 > (words.len() - HEADER) as u64 {
+            self.grow()?;
+        }
+        let words = self.map.words_mut();
+        let first = insert_slot(&mut words[HEADER..], hash);
+        if first {
+            // Updated right away, so the store stays usable when killed
+            words[LEN] = (u64::from_le(words[LEN]) + 1).to_le();
+        }
+        Ok(first)
+    }
+
+    /// Lock the store, switching to the current file if it was moved
+    fn lock(&mut self) -> io::Result<()> {
+        loop {
+            flock(&self.file, libc::LOCK_EX)?;
+            if u64::from_le(self.map.words()[0]) != MOVED {
+                return Ok(());
+            }
+            let file = open_file(&self.path)?;
+            self.map = Map::new(&file)?;
+            // Releases the lock of the moved file
+            self.file = file;
+        }
+    }
+
+    /// Move the hashes into a store of twice the slots, locked
+    fn grow(&mut self) -> io::Result<()> {
+        let tmp = tmp_path(&self.path);
+        let words = self.map.words();
+        let (file, mut map) = create(&tmp, (words.len() - HEADER) * 2, self.seeds())?;
+        let new = map.words_mut();
+        for &hash in &words[HEADER..] {
+            if hash != 0 {
+                insert_slot(&mut new[HEADER..], u64::from_le(hash));
+            }
+        }
+        new[LEN] = words[LEN];
+        flock(&file, libc::LOCK_EX)?;
+        fs::rename(&tmp, &self.path)?;
+        self.map.words_mut()[0] = MOVED.to_le();
+        self.map = map;
+        self.file = file;
+        Ok(())
+    }
+}
+
+fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).open(path)
+}
+
+/// A temporary file next to the store
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_else(|| OsStr::new("huniq")));
+    name.push(format!(".huniq-{}.tmp", process::id()));
+    path.with_file_name(name)
+}
+
+fn flock(file: &File, op: libc::c_int) -> io::Result<()> {
+    match unsafe { libc::flock(file.as_raw_fd(), op) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Insert the hash into the slots, returning whether it was not there
+/// yet; there must be an empty slot
+fn insert_slot(slots: &mut [u64], hash: u64) -> bool {
+    let mut idx = ((hash as u128 * slots.len() as u128) >> 64) as usize;
+    loop {
+        match u64::from_le(slots[idx]) {
+            0 => {
+                slots[idx] = hash.to_le();
+                return true;
+            }
+            h if h == hash => return false,
+            _ => idx = (idx + 1) % slots.len(),
+        }
+    }
+}
+
+/// Create an empty store with the given number of slots
+fn create(path: &Path, slots: usize, seeds: [u64; 4]) -> io::Result<(File, Map)> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    file.set_len(((HEADER + slots) * 8) as u64)?;
+    let mut map = Map::new(&file)?;
+    let words = map.words_mut();
+    words[0] = MAGIC.to_le();
+    for (word, seed) in words[SEEDS..SEEDS + 4].iter_mut().zip(seeds) {
+        *word = seed.to_le();
+    }
+    words[SLOTS] = (slots as u64).to_le();
+    Ok((file, map))
+}
+
+/// A shared, writable memory mapping of an entire file
+struct Map {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Map {
+    fn new(file: &File) -> io::Result<Map> {
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Ok(Map {
+                ptr: ptr::null_mut(),
+                len,
+            });
+        }
+        // Safety: The mapping is only exposed through slices tied to
+        // the lifetime of Map. Like every program using mmap, we can
+        // not protect against another process changing the file.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Map { ptr, len })
+    }
+
+    fn words(&self) -> &[u64] {
+        match self.ptr.is_null() {
+            true => &[],
+            false => unsafe { slice::from_raw_parts(self.ptr as *const u64, self.len / 8) },
+        }
+    }
+
+    fn words_mut(&mut self) -> &mut [u64] {
+        match self.ptr.is_null() {
+            true => &mut [],
+            false => unsafe { slice::from_raw_parts_mut(self.ptr as *mut u64, self.len / 8) },
+        }
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! Templates for the records printed in count mode, like `{count} {line}`.
+
+use anyhow::{anyhow, Result};
+use bstr::ByteSlice;
+use std::io::{self, Write};
+
+/// A part of a template
+enum Piece {
+    Literal(Vec<u8>),
+    /// A field, right-aligned to the given width
+    Field(Field, usize),
+    /// The record, escaped for the output format
+    Escaped(Escape),
+}
+
+#[derive(Clone, Copy)]
+enum Escape {
+    /// As a JSON value
+    Json(NonUtf8),
+    /// Quoted if necessary, as in RFC 4180
+    Csv,
+    /// Tabs, line breaks and backslashes escaped with backslashes
+    Tsv,
+}
+
+/// How records that are not valid UTF-8 are written as JSON
+#[derive(Clone, Copy)]
+pub enum NonUtf8 {
+    /// As a string with invalid bytes replaced by U+FFFD
+    Lossy,
+    /// As an array of the bytes
+    Bytes,
+}
+
+impl NonUtf8 {
+    pub fn from_name(name: &str) -> Option<NonUtf8> {
+        match name {
+            "lossy" => Some(NonUtf8::Lossy),
+            "bytes" => Some(NonUtf8::Bytes),
+            _ => None,
+        }
+    }
+}
+
+/// A placeholder of a template
+#[derive(Clone, Copy)]
+enum Field {
+    /// Number of occurrences of the record
+    Count,
+    /// Percentage of all records made up by this one
+    Percent,
+    /// Percentage of all records made up by the records printed so far
+    Cumulative,
+    /// Position of the record in the output, starting at one
+    Rank,
+    Line,
+}
+
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+/// The values to fill into a template for one record
+pub struct Row<'a> {
+    pub line: &'a [u8],
+    pub count: u64,
+    pub rank: u64,
+    /// Sum of the counts of this and all previous records
+    pub sum: u64,
+    /// Number of all records
+    pub total: u64,
+}
+
+impl Template {
+    /// Parse a template with the placeholders `{count}`, `{percent}`,
+    /// `{cumulative}`, `{rank}` and `{line}`; `{{` and `}}` are literal braces.
+    /// Placeholders may give a minimum width, e.g. `{count:7}`.
+    pub fn parse(v: &[u8]) -> Result<Template> {
+        let mut pieces = Vec::new();
+        let mut literal = Vec::new();
+        let mut rest = v;
+        while let Some(&b) = rest.first() {
+            if rest.starts_with(b"{{") || rest.starts_with(b"}}") {
+                literal.push(b);
+                rest = &rest[2..];
+                continue;
+            }
+            if b == b'}' {
+                return Err(anyhow!("unmatched `}}` in format `{}`", v.as_bstr()));
+            }
+            if b != b'{' {
+                literal.push(b);
+                rest = &rest[1..];
+                continue;
+            }
+
+            let end = rest
+                .find_byte(b'}')
+                .ok_or_else(|| anyhow!("unmatched `{{` in format `{}`", v.as_bstr()))?;
+            let placeholder = &rest[1..end];
+            let unknown = || anyhow!("unknown placeholder `{{{}}}`", placeholder.as_bstr());
+            let (name, width) = match placeholder.find_byte(b':') {
+                Some(idx) => {
+                    let width = placeholder[idx + 1..].to_str().ok();
+                    let width = width.and_then(|w| w.parse().ok());
+                    (&placeholder[..idx], width.ok_or_else(unknown)?)
+                }
+                None => (placeholder, 0),
+            };
+            let field = match name {
+                b"count" => Field::Count,
+                b"percent" => Field::Percent,
+                b"cumulative" => Field::Cumulative,
+                b"rank" => Field::Rank,
+                b"line" => Field::Line,
+                _ => return Err(unknown()),
+            };
+            if !literal.is_empty() {
+                pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+            }
+            pieces.push(Piece::Field(field, width));
+            rest = &rest[end + 1..];
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+        Ok(Template { pieces })
+    }
+
+    /// A JSON object with the count and the record
+    pub fn json(non_utf8: NonUtf8) -> Template {
+        let pieces = vec![
+            Piece::Literal(b"{\"count\":".to_vec()),
+            Piece::Field(Field::Count, 0),
+            Piece::Literal(b",\"line\":".to_vec()),
+            Piece::Escaped(Escape::Json(non_utf8)),
+            Piece::Literal(b"}".to_vec()),
+        ];
+        Template { pieces }
+    }
+
+    /// The count and the record, separated by a comma
+    pub fn csv() -> Template {
+        Template::separated(b",", Escape::Csv)
+    }
+
+    /// The count and the record, separated by a tab
+    pub fn tsv() -> Template {
+        Template::separated(b"\t", Escape::Tsv)
+    }
+
+    fn separated(sep: &[u8], escape: Escape) -> Template {
+        let pieces = vec![
+            Piece::Field(Field::Count, 0),
+            Piece::Literal(sep.to_vec()),
+            Piece::Escaped(escape),
+        ];
+        Template { pieces }
+    }
+
+    pub fn write(&self, out: &mut dyn Write, row: &Row) -> io::Result<()> {
+        let percent = |n: u64| n as f64 * 100.0 / row.total as f64;
+        for piece in &self.pieces {
+            match *piece {
+                Piece::Literal(ref data) => out.write_all(data)?,
+                Piece::Field(Field::Count, w) => write!(out, "{:>w$}", row.count, w = w)?,
+                Piece::Field(Field::Percent, w) => {
+                    write!(out, "{:>w$.2}", percent(row.count), w = w)?
+                }
+                Piece::Field(Field::Cumulative, w) => {
+                    write!(out, "{:>w$.2}", percent(row.sum), w = w)?
+                }
+                Piece::Field(Field::Rank, w) => write!(out, "{:>w$}", row.rank, w = w)?,
+                Piece::Field(Field::Line, w) => {
+                    write!(out, "{:w$}", "", w = w.saturating_sub(row.line.len()))?;
+                    out.write_all(row.line)?;
+                }
+                Piece::Escaped(Escape::Json(non_utf8)) => write_json(out, row.line, non_utf8)?,
+                Piece::Escaped(Escape::Csv) => write_csv(out, row.line)?,
+                Piece::Escaped(Escape::Tsv) => write_tsv(out, row.line)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write the record as a JSON string
+pub fn write_json(out: &mut dyn Write, line: &[u8], non_utf8: NonUtf8) -> io::Result<()> {
+    let line = match (line.to_str(), non_utf8) {
+        (Ok(line), _) => line.into(),
+        (Err(_), NonUtf8::Lossy) => line.to_str_lossy(),
+        (Err(_), NonUtf8::Bytes) => {
+            out.write_all(b"[")?;
+            for (idx, b) in line.iter().enumerate() {
+                match idx {
+                    0 => write!(out, "{}", b)?,
+                    _ => write!(out, ",{}", b)?,
+                }
+            }
+            return out.write_all(b"]");
+        }
+    };
+    out.write_all(b"\"")?;
+    for c in line.chars() {
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            '\n' => out.write_all(b"\\n")?,
+            '\r' => out.write_all(b"\\r")?,
+            '\t' => out.write_all(b"\\t")?,
+            c if c < ' ' => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    out.write_all(b"\"")
+}
+
+/// Write the record as a CSV field
+pub fn write_csv(out: &mut dyn Write, line: &[u8]) -> io::Result<()> {
+    if !line
+        .iter()
+        .any(|&b| matches!(b, b',' | b'"' | b'\n' | b'\r'))
+    {
+        return out.write_all(line);
+    }
+    out.write_all(b"\"")?;
+    for part in line.split_inclusive(|&b| b == b'"') {
+        out.write_all(part)?;
+        if part.ends_with(b"\"") {
+            out.write_all(b"\"")?;
+        }
+    }
+    out.write_all(b"\"")
+}
+
+/// Write the record as a TSV field
+pub fn write_tsv(out: &mut dyn Write, line: &[u8]) -> io::Result<()> {
+    for &b in line {
+        match b {
+            b'\t' => out.write_all(b"\\t")?,
+            b'\n' => out.write_all(b"\\n")?,
+            b'\r' => out.write_all(b"\\r")?,
+            b'\\' => out.write_all(b"\\\\")?,
+            b => out.write_all(&[b])?,
+        }
+    }
+    Ok(())
+}
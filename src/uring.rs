@@ -0,0 +1,455 @@
+//! Input reader based on io_uring.
+//!
+//! Reads are issued asynchronously into a ring of buffers, so the
+//! kernel fills the next buffers while the records in the current one
+//! are being hashed. For regular files, all free buffers are kept in
+//! flight at consecutive offsets; other files (pipes, terminals, …)
+//! have no offsets, so only a single read is in flight at a time.
+//!
+//! The kernel interface is used directly through the raw system calls;
+//! the structure definitions mirror `linux/io_uring.h`.
+
+use std::fs::File;
+use std::io::{self, BufRead, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Number of buffers in the ring
+const BUFFERS: usize = 8;
+
+/// Size of each buffer
+const BUFFER_SIZE: usize = 256 * 1024;
+
+const IORING_OP_READ: u8 = 22;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A memory mapped region shared with the kernel
+struct Region {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Region {
+    fn map(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Region> {
+        // Safety: Mapping fresh memory does not alias anything
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        match ptr {
+            libc::MAP_FAILED => Err(io::Error::last_os_error()),
+            ptr => Ok(Region {
+                ptr: ptr as *mut u8,
+                len,
+            }),
+        }
+    }
+
+    /// Pointer to a value at the given byte offset into the region
+    fn at<T>(&self, offset: u32) -> *mut T {
+        debug_assert!(offset as usize + std::mem::size_of::<T>() <= self.len);
+        unsafe { self.ptr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// A submission and completion queue pair
+struct Ring {
+    fd: RawFd,
+    sq: Region,
+    cq: Region,
+    sqes: Region,
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+    sq_mask: u32,
+    cq_mask: u32,
+    to_submit: u32,
+}
+
+impl Ring {
+    fn new(entries: u32) -> io::Result<Ring> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = fd as RawFd;
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+        let regions = Region::map(fd, sq_len, IORING_OFF_SQ_RING).and_then(|sq| {
+            let cq = Region::map(fd, cq_len, IORING_OFF_CQ_RING)?;
+            let sqes = Region::map(fd, sqes_len, IORING_OFF_SQES)?;
+            Ok((sq, cq, sqes))
+        });
+        let (sq, cq, sqes) = match regions {
+            Ok(r) => r,
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+        };
+
+        let sq_mask = unsafe { *sq.at::<u32>(params.sq_off.ring_mask) };
+        let cq_mask = unsafe { *cq.at::<u32>(params.cq_off.ring_mask) };
+        Ok(Ring {
+            fd,
+            sq,
+            cq,
+            sqes,
+            sq_off: params.sq_off,
+            cq_off: params.cq_off,
+            sq_mask,
+            cq_mask,
+            to_submit: 0,
+        })
+    }
+
+    fn atomic(ptr: *mut u32) -> &'static AtomicU32 {
+        unsafe { &*(ptr as *const AtomicU32) }
+    }
+
+    /// Queue a submission; the caller must never have more entries
+    /// in flight than the ring was created with.
+    fn push(&mut self, sqe: Sqe) {
+        let tail = Ring::atomic(self.sq.at(self.sq_off.tail));
+        let t = tail.load(Ordering::Relaxed);
+        let idx = t & self.sq_mask;
+        unsafe {
+            ptr::write(self.sqes.at::<Sqe>(0).add(idx as usize), sqe);
+            *self.sq.at::<u32>(self.sq_off.array).add(idx as usize) = idx;
+        }
+        tail.store(t.wrapping_add(1), Ordering::Release);
+        self.to_submit += 1;
+    }
+
+    /// Submit all queued entries and wait for at least `min_complete`
+    /// completions to be available.
+    fn enter(&mut self, min_complete: u32) -> io::Result<()> {
+        let flags = match min_complete {
+            0 => 0,
+            _ => IORING_ENTER_GETEVENTS,
+        };
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd,
+                self.to_submit,
+                min_complete,
+                flags,
+                ptr::null::<libc::sigset_t>(),
+                0usize,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.to_submit -= res as u32;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Cqe> {
+        let head = Ring::atomic(self.cq.at(self.cq_off.head));
+        let tail = Ring::atomic(self.cq.at(self.cq_off.tail));
+        let h = head.load(Ordering::Relaxed);
+        if h == tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let idx = (h & self.cq_mask) as usize;
+        let cqe = unsafe { *self.cq.at::<Cqe>(self.cq_off.cqes).add(idx) };
+        head.store(h.wrapping_add(1), Ordering::Release);
+        Some(cqe)
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Free,
+    /// A read at the given offset is in flight
+    InFlight(u64),
+    /// A read is in flight whose result will be discarded
+    Stale,
+    Ready(usize),
+}
+
+/// Reader issuing overlapping reads through io_uring
+pub struct UringReader {
+    ring: Ring,
+    /// Keeps the file open while reads are in flight
+    _file: Option<File>,
+    fd: RawFd,
+    seekable: bool,
+    bufs: Vec<Box<[u8]>>,
+    slots: [Slot; BUFFERS],
+    in_flight: usize,
+    /// Slot currently being consumed and position therein
+    cur: usize,
+    pos: usize,
+    /// Next slot to submit a read for and the offset to read from
+    next: usize,
+    next_off: u64,
+    eof: bool,
+}
+
+impl UringReader {
+    /// Read from the given file; with None reads from stdin.
+    pub fn new(file: Option<File>) -> io::Result<UringReader> {
+        let fd = file.as_ref().map(|f| f.as_raw_fd()).unwrap_or(0);
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(UringReader {
+            ring: Ring::new(BUFFERS as u32)?,
+            _file: file,
+            fd,
+            seekable: stat.st_mode & libc::S_IFMT == libc::S_IFREG,
+            bufs: (0..BUFFERS)
+                .map(|_| vec![0u8; BUFFER_SIZE].into_boxed_slice())
+                .collect(),
+            slots: [Slot::Free; BUFFERS],
+            in_flight: 0,
+            cur: 0,
+            pos: 0,
+            next: 0,
+            next_off: 0,
+            eof: false,
+        })
+    }
+
+    /// Submit reads for free slots, in ring order
+    fn submit(&mut self) -> io::Result<()> {
+        let max_in_flight = if self.seekable { BUFFERS } else { 1 };
+        while !self.eof && self.in_flight < max_in_flight && self.slots[self.next] == Slot::Free {
+            let slot = self.next;
+            let off = match self.seekable {
+                true => self.next_off,
+                false => u64::MAX, // read from the current position
+            };
+            self.ring.push(Sqe {
+                opcode: IORING_OP_READ,
+                fd: self.fd,
+                off,
+                addr: self.bufs[slot].as_mut_ptr() as u64,
+                len: BUFFER_SIZE as u32,
+                user_data: slot as u64,
+                ..Sqe::default()
+            });
+            self.slots[slot] = Slot::InFlight(self.next_off);
+            self.in_flight += 1;
+            self.next = (self.next + 1) % BUFFERS;
+            self.next_off += BUFFER_SIZE as u64;
+        }
+        match self.ring.to_submit {
+            0 => Ok(()),
+            _ => self.ring.enter(0),
+        }
+    }
+
+    /// Wait for at least one read to finish, process the results and
+    /// submit reads for the slots that became free.
+    fn complete(&mut self) -> io::Result<()> {
+        self.ring.enter(1)?;
+        while let Some(cqe) = self.ring.pop() {
+            let slot = cqe.user_data as usize;
+            self.in_flight -= 1;
+
+            if self.slots[slot] == Slot::Stale {
+                self.slots[slot] = Slot::Free;
+                continue;
+            }
+            let off = match self.slots[slot] {
+                Slot::InFlight(off) => off,
+                _ => unreachable!(),
+            };
+
+            if cqe.res < 0 {
+                let err = io::Error::from_raw_os_error(-cqe.res);
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+                // Retry the read from the same position
+                self.invalidate_after(slot, off);
+                self.slots[slot] = Slot::Free;
+                continue;
+            }
+
+            let len = cqe.res as usize;
+            self.slots[slot] = Slot::Ready(len);
+            if len == 0 {
+                self.eof = true;
+            } else if self.seekable && len < BUFFER_SIZE {
+                // The following reads assumed this one to fill the whole
+                // buffer; redo them from where this read actually ended
+                self.invalidate_after(slot, off + len as u64);
+            }
+        }
+        self.submit()
+    }
+
+    /// Discard all reads submitted after the given slot and continue
+    /// reading at the given offset.
+    fn invalidate_after(&mut self, slot: usize, off: u64) {
+        let mut idx = (slot + 1) % BUFFERS;
+        while idx != self.next {
+            self.slots[idx] = match self.slots[idx] {
+                Slot::InFlight(_) => Slot::Stale,
+                Slot::Ready(_) => Slot::Free,
+                other => other,
+            };
+            idx = (idx + 1) % BUFFERS;
+        }
+        self.next = match self.slots[slot] {
+            Slot::InFlight(_) => slot,
+            _ => (slot + 1) % BUFFERS,
+        };
+        self.next_off = off;
+    }
+}
+
+impl BufRead for UringReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        loop {
+            match self.slots[self.cur] {
+                Slot::Ready(len) if self.pos < len => {
+                    return Ok(&self.bufs[self.cur][self.pos..len]);
+                }
+                Slot::Ready(0) => return Ok(&[]),
+                Slot::Ready(_) => {
+                    self.slots[self.cur] = Slot::Free;
+                    self.cur = (self.cur + 1) % BUFFERS;
+                    self.pos = 0;
+                    self.submit()?;
+                }
+                Slot::Free if self.eof => return Ok(&[]),
+                Slot::Free => self.submit()?,
+                Slot::InFlight(_) | Slot::Stale => self.complete()?,
+            }
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+impl Read for UringReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let avail = self.fill_buf()?;
+        let len = avail.len().min(buf.len());
+        buf[..len].copy_from_slice(&avail[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl Drop for UringReader {
+    fn drop(&mut self) {
+        // The kernel may still be writing to the buffers
+        self.eof = true;
+        while self.in_flight > 0 {
+            if self.complete().is_err() && self.ring.enter(1).is_err() {
+                break;
+            }
+        }
+    }
+}
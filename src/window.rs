@@ -0,0 +1,206 @@
+//! Remembering only the recently seen records, so memory stays bounded
+//! on endless streams.
+
+use crate::sketch::{Cuckoo, Full};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant};
+
+/// Marks the absence of a node
+const NONE: usize = usize::MAX;
+
+struct Node {
+    hash: u64,
+    /// The node seen more recently
+    prev: usize,
+    /// The node seen less recently
+    next: usize,
+}
+
+/// The `n` most recently seen distinct hashes.
+///
+/// The hashes are kept in a doubly linked list ordered by when they
+/// were last seen, whose nodes are stored in a vector; when full, the
+/// node of the least recently seen hash is reused.
+pub struct Lru<S> {
+    index: HashMap<u64, usize, S>,
+    nodes: Vec<Node>,
+    /// The most recently seen node
+    head: usize,
+    /// The least recently seen node
+    tail: usize,
+    n: usize,
+}
+
+impl<S: BuildHasher + Default> Lru<S> {
+    pub fn new(n: usize) -> Lru<S> {
+        Lru {
+            index: HashMap::with_capacity_and_hasher(n, S::default()),
+            nodes: Vec::with_capacity(n),
+            head: NONE,
+            tail: NONE,
+            n: n.max(1),
+        }
+    }
+
+    /// Mark the hash as seen, returning whether it was not remembered
+    pub fn insert(&mut self, hash: u64) -> bool {
+        if self.touch(hash) {
+            return false;
+        }
+
+        let idx = if self.nodes.len() < self.n {
+            self.nodes.push(Node {
+                hash,
+                prev: NONE,
+                next: NONE,
+            });
+            self.nodes.len() - 1
+        } else {
+            // Forget the least recently seen hash, reusing its node
+            let idx = self.tail;
+            self.unlink(idx);
+            self.index.remove(&self.nodes[idx].hash);
+            self.nodes[idx].hash = hash;
+            idx
+        };
+        self.index.insert(hash, idx);
+        self.push_front(idx);
+        true
+    }
+
+    /// Mark the hash as seen if it is remembered, returning whether it is
+    pub fn touch(&mut self, hash: u64) -> bool {
+        match self.index.get(&hash) {
+            Some(&idx) => {
+                self.unlink(idx);
+                self.push_front(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn unlink(&mut self, idx: usize) {
+        let Node { prev, next, .. } = self.nodes[idx];
+        match prev {
+            NONE => self.head = next,
+            prev => self.nodes[prev].next = next,
+        }
+        match next {
+            NONE => self.tail = prev,
+            next => self.nodes[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, idx: usize) {
+        self.nodes[idx].prev = NONE;
+        self.nodes[idx].next = self.head;
+        match self.head {
+            NONE => self.tail = idx,
+            head => self.nodes[head].prev = idx,
+        }
+        self.head = idx;
+    }
+}
+
+/// The hashes among the last `n` hashes seen, duplicates included
+pub struct Recent<S> {
+    /// Number of occurrences of each hash among the last
+    counts: HashMap<u64, u32, S>,
+    last: VecDeque<u64>,
+    n: usize,
+}
+
+impl<S: BuildHasher + Default> Recent<S> {
+    pub fn new(n: usize) -> Recent<S> {
+        Recent {
+            counts: HashMap::with_capacity_and_hasher(n, S::default()),
+            last: VecDeque::with_capacity(n + 1),
+            n,
+        }
+    }
+
+    /// Mark the hash as seen, returning whether it was not among the last
+    pub fn insert(&mut self, hash: u64) -> bool {
+        let count = self.counts.entry(hash).or_insert(0);
+        let new = *count == 0;
+        *count += 1;
+        self.last.push_back(hash);
+        if self.last.len() > self.n {
+            let oldest = self.last.pop_front().unwrap();
+            let count = self.counts.get_mut(&oldest).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&oldest);
+            }
+        }
+        new
+    }
+}
+
+/// A set of hashes that [Expiring] can forget hashes from
+pub trait Forgetful {
+    /// Add the hash, returning whether it was not in the set
+    fn insert(&mut self, hash: u64) -> Result<bool, Full>;
+
+    /// Forget the hash, returning whether it was in the set
+    fn remove(&mut self, hash: u64) -> bool;
+}
+
+impl<S: BuildHasher> Forgetful for HashSet<u64, S> {
+    fn insert(&mut self, hash: u64) -> Result<bool, Full> {
+        Ok(HashSet::insert(self, hash))
+    }
+
+    fn remove(&mut self, hash: u64) -> bool {
+        HashSet::remove(self, &hash)
+    }
+}
+
+impl Forgetful for Cuckoo {
+    fn insert(&mut self, hash: u64) -> Result<bool, Full> {
+        Cuckoo::insert(self, hash)
+    }
+
+    fn remove(&mut self, hash: u64) -> bool {
+        Cuckoo::remove(self, hash)
+    }
+}
+
+/// The hashes first seen within the given duration, remembered in the
+/// given set
+pub struct Expiring<T> {
+    seen: T,
+    /// The hashes in the order they were first seen
+    order: VecDeque<(Instant, u64)>,
+    ttl: Duration,
+}
+
+impl<T: Forgetful> Expiring<T> {
+    pub fn new(seen: T, ttl: Duration) -> Expiring<T> {
+        Expiring {
+            seen,
+            order: VecDeque::new(),
+            ttl,
+        }
+    }
+
+    /// Mark the hash as seen, returning whether it was not seen within
+    /// the duration; the duration starts anew then
+    pub fn insert(&mut self, hash: u64) -> Result<bool, Full> {
+        let now = Instant::now();
+        while let Some(&(at, oldest)) = self.order.front() {
+            if now.duration_since(at) < self.ttl {
+                break;
+            }
+            self.order.pop_front();
+            self.seen.remove(oldest);
+        }
+        if !self.seen.insert(hash)? {
+            return Ok(false);
+        }
+        self.order.push_back((now, hash));
+        Ok(true)
+    }
+}
@@ -1,4 +1,7 @@
-use assert_cmd::{assert::Assert, Command};
+use assert_cmd::{assert::Assert, cargo::cargo_bin, Command};
+use std::io::{BufRead, BufReader, Write};
+use std::time::Duration;
+use std::{env, fs, process, thread};
 
 #[test]
 fn noargs() {
@@ -29,6 +32,1722 @@ fn count_sort_descending() {
         .stdout("2 a\n1 b\n");
 }
 
+#[test]
+fn input_files() {
+    let inp = fixture("input.txt");
+    assert("", &[&inp, &inp])
+        .success()
+        .stdout("hello\nfoo\nbar\n");
+}
+
+#[test]
+fn input_files_and_stdin() {
+    let inp = fixture("input.txt");
+    assert("baz\nfoo\n", &[&inp, "-"])
+        .success()
+        .stdout("hello\nfoo\nbar\nbaz\n");
+}
+
+#[test]
+fn input_missing_file() {
+    assert("", &["does/not/exist"]).failure();
+}
+
+#[test]
+fn output_file() {
+    let dst = tmp_path("output_file");
+    assert("a\nb\na\n", &["-o", &dst]).success().stdout("");
+    assert_eq!(fs::read_to_string(&dst).unwrap(), "a\nb\n");
+
+    assert("a\na\n", &["-c", "--output", &dst])
+        .success()
+        .stdout("");
+    assert_eq!(fs::read_to_string(&dst).unwrap(), "2 a\n");
+    fs::remove_file(&dst).unwrap();
+}
+
+#[test]
+fn output_file_untouched_on_error() {
+    let dst = tmp_path("output_file_untouched_on_error");
+    fs::write(&dst, "previous\n").unwrap();
+    assert("a\n", &["-o", &dst, "-", "does/not/exist"]).failure();
+    assert_eq!(fs::read_to_string(&dst).unwrap(), "previous\n");
+    fs::remove_file(&dst).unwrap();
+}
+
+#[test]
+fn in_place() {
+    let a = tmp_path("in_place_a");
+    let b = tmp_path("in_place_b");
+    fs::write(&a, "a\nb\na\n").unwrap();
+    fs::write(&b, "a\nc\nc\n").unwrap();
+    assert("", &["-i", &a, &b]).success().stdout("");
+    assert_eq!(fs::read_to_string(&a).unwrap(), "a\nb\n");
+    assert_eq!(fs::read_to_string(&b).unwrap(), "a\nc\n");
+    fs::remove_file(&a).unwrap();
+    fs::remove_file(&b).unwrap();
+}
+
+#[test]
+fn in_place_backup() {
+    let a = tmp_path("in_place_backup");
+    let bak = format!("{}.bak", a);
+    fs::write(&a, "a\nb\na\n").unwrap();
+    assert("", &["--in-place=.bak", "-c", &a]).success();
+    assert_eq!(fs::read_to_string(&bak).unwrap(), "a\nb\na\n");
+    assert_eq!(fs::read_to_string(&a).unwrap().len(), "2 a\n1 b\n".len());
+    fs::remove_file(&a).unwrap();
+    fs::remove_file(&bak).unwrap();
+}
+
+#[test]
+fn dupes_out() {
+    let dupes = tmp_path("dupes_out");
+    assert("a\nb\na\nb\nc\na", &["--dupes-out", &dupes])
+        .success()
+        .stdout("a\nb\nc\n");
+    assert_eq!(fs::read_to_string(&dupes).unwrap(), "a\nb\na\n");
+    fs::remove_file(&dupes).unwrap();
+}
+
+#[test]
+fn follow() {
+    let path = tmp_path("follow");
+    fs::write(&path, "a\na\n").unwrap();
+
+    let mut child = process::Command::new(cargo_bin("huniq"))
+        .args(["-f", &path])
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut out = BufReader::new(child.stdout.take().unwrap()).lines();
+    assert_eq!(out.next().unwrap().unwrap(), "a");
+
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"b\na\nc\n").unwrap();
+    assert_eq!(out.next().unwrap().unwrap(), "b");
+    assert_eq!(out.next().unwrap().unwrap(), "c");
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    fs::remove_file(&path).unwrap();
+
+    // Counts are printed at the end, which never comes
+    for flag in ["-c", "--repeated", "--top=1", "--json"] {
+        assert("a\n", &["-f", flag]).failure();
+    }
+}
+
+#[test]
+fn decompress_file() {
+    let inp = fixture("input.txt");
+    let gz = fixture("input.txt.gz");
+    assert("", &[&gz, &inp])
+        .success()
+        .stdout("hello\nfoo\nbar\n");
+}
+
+#[test]
+fn decompress_stdin() {
+    let gz = fs::read(fixture("input.txt.gz")).unwrap();
+    Command::cargo_bin("huniq")
+        .unwrap()
+        .args(["-z", "-c", "-s"])
+        .write_stdin(gz)
+        .assert()
+        .success()
+        .stdout("1 foo\n2 bar\n3 hello\n");
+}
+
+#[test]
+fn compress_output() {
+    let dst = tmp_path("compress_output.gz");
+    assert("a\nb\na\n", &["-o", &dst, "--compress", "gzip"])
+        .success()
+        .stdout("");
+    assert!(fs::read(&dst).unwrap().starts_with(&[0x1f, 0x8b]));
+    assert("", &[&dst]).success().stdout("a\nb\n");
+    fs::remove_file(&dst).unwrap();
+}
+
+#[test]
+fn mmap_input() {
+    let path = tmp_path("mmap_input");
+    fs::write(&path, "a\nb\na\nc\nc\na").unwrap();
+    assert("", &[&path]).success().stdout("a\nb\nc\n");
+    assert("", &["--no-mmap", &path])
+        .success()
+        .stdout("a\nb\nc\n");
+    assert("", &["-c", "-S", &path])
+        .success()
+        .stdout("3 a\n2 c\n1 b\n");
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+#[test]
+fn io_uring_input() {
+    let inp = fixture("input.txt");
+    assert("foo\nbaz", &["--io-uring", &inp, "-"])
+        .success()
+        .stdout("hello\nfoo\nbar\nbaz\n");
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite() {
+    let path = tmp_path("counts.db");
+    let _ = fs::remove_file(&path);
+    assert("a\nb\nb\n", &["--sqlite", &path])
+        .success()
+        .stdout("");
+    // The table already exists
+    assert("a\n", &["--sqlite", &path]).failure();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn write_buffer() {
+    assert(
+        "a\nbbbbbbbbbbbbbbbbbbbbbbbb\na\nc",
+        &["--write-buffer", "4"],
+    )
+    .success()
+    .stdout("a\nbbbbbbbbbbbbbbbbbbbbbbbb\nc\n");
+    assert("a\n", &["--write-buffer", "1M"])
+        .success()
+        .stdout("a\n");
+    assert("a\n", &["--write-buffer", "lots"]).failure();
+}
+
+#[test]
+fn threads() {
+    // Large enough to be split into multiple chunks
+    let input: String = (0..300_000).map(|i| format!("{}\n", i % 70_001)).collect();
+    let expected: String = (0..70_001).map(|i| format!("{}\n", i)).collect();
+    assert(&input, &["-j", "4"])
+        .success()
+        .stdout(expected.clone());
+
+    let path = tmp_path("threads");
+    fs::write(&path, &input).unwrap();
+    assert("70001\n0", &["--threads", "3", &path, "-"])
+        .success()
+        .stdout(expected + "70001\n");
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn threads_count() {
+    // Record i occurs i times
+    let input: String = (1..=600)
+        .flat_map(|i| (0..i).map(move |_| format!("{}\n", i)))
+        .collect();
+    let expected: String = (1..=600).rev().map(|i| format!("{} {}\n", i, i)).collect();
+    assert(&input, &["-c", "-S", "-j", "4"])
+        .success()
+        .stdout(expected);
+}
+
+#[test]
+fn expected_lines() {
+    assert("a\nb\na\n", &["--expected-lines", "1000"])
+        .success()
+        .stdout("a\nb\n");
+    assert("a\nb\na\n", &["-c", "-S", "--expected-lines", "1000"])
+        .success()
+        .stdout("2 a\n1 b\n");
+    assert("a\n", &["--expected-lines", "many"]).failure();
+}
+
+#[test]
+fn count_large_records() {
+    let large = "x".repeat(400 * 1024);
+    let input = format!("{0}\na\n{0}\n{0}x\na\n{0}\n", large);
+    let expected = format!("3 {0}\n2 a\n1 {0}x\n", large);
+    assert(&input, &["-c", "-S"]).success().stdout(expected);
+}
+
+#[test]
+fn count_duplicate_heavy() {
+    let input = "a\n\nb\nb\n".repeat(1000) + "\n";
+    assert(&input, &["-c", "-S"])
+        .success()
+        .stdout("2000 b\n1001 \n1000 a\n");
+}
+
+#[test]
+fn multi_byte_delimiter() {
+    assert("a||b|||a||b", &["-d", "||"])
+        .success()
+        .stdout("a||b|||a||");
+    assert("a\r\nb\na\r\na", &["-d", "\r\n", "-c", "-S"])
+        .success()
+        .stdout("2 a\r\n1 b\na\r\n");
+}
+
+#[test]
+fn unicode_delimiter() {
+    assert("1§1§2§3", &["-d", "§"]).success().stdout("1§2§3§");
+    assert("1§1§2§3", &["-d", "§", "-c", "-s"])
+        .success()
+        .stdout("1 2§1 3§2 1§");
+}
+
+#[test]
+fn crlf() {
+    assert("a\r\nb\na\nb\r\nc", &["--crlf"])
+        .success()
+        .stdout("a\r\nb\nc\r\n");
+    assert("a\r\nb\na\na\r\n", &["--crlf", "-c", "-s"])
+        .success()
+        .stdout("1 b\r\n3 a\r\n");
+}
+
+#[test]
+fn paragraph() {
+    let input = "a\nb\n\nc\n\n\n\na\nb\n\nd\n\na\nb\n\nd";
+    assert(input, &["-p"])
+        .success()
+        .stdout("a\nb\n\nc\n\n\n\nd\n\n");
+    assert(input, &["-p", "-c", "-s"])
+        .success()
+        .stdout("1 c\n\n2 d\n\n3 a\nb\n\n");
+}
+
+#[test]
+fn record_size() {
+    assert("abcabcxyzabcxy", &["--record-size", "3"])
+        .success()
+        .stdout("abcxyzxy");
+    assert("ab\ncdab\n", &["--record-size", "0"]).failure();
+}
+
+#[test]
+fn framing() {
+    assert("\x02a\n\x01\n\x02a\n\x00\x00", &["--framing", "varint"])
+        .success()
+        .stdout("\x02a\n\x01\n\x00");
+    assert("\x02\0\0\0ab\x02\0\0\0ab", &["--framing", "u32le"])
+        .success()
+        .stdout("\x02\0\0\0ab");
+    assert("\x02a", &["--framing", "varint"]).failure();
+}
+
+#[test]
+fn out_delimiter() {
+    assert("a\nb\na\nc", &["-D", "|"])
+        .success()
+        .stdout("a|b|c|");
+    assert("a\nb\na\nc", &["-D", ",", "-t"])
+        .success()
+        .stdout("a,b,c");
+    assert("a,a,b,", &["-d", ",", "-D", "\n", "-c", "-s"])
+        .success()
+        .stdout("1 b\n2 a\n");
+}
+
+#[test]
+fn delimiter_escapes() {
+    assert("a\tb\ta", &["-d", "\\t", "-D", "\\0"])
+        .success()
+        .stdout("a\0b\0");
+    assert("a\x1eb\x1ea", &["-d", "\\x1e"])
+        .success()
+        .stdout("a\x1eb\x1e");
+    assert("a\\b\\a", &["-d", "\\\\"])
+        .success()
+        .stdout("a\\b\\");
+    assert("a", &["-d", "\\q"]).failure();
+}
+
+#[test]
+fn repeated() {
+    let input = "c\na\nb\na\nd\nb\nc\nb\n";
+    assert(input, &["--repeated"]).success().stdout("c\na\nb\n");
+    assert(input, &["--repeated", "-j", "3"])
+        .success()
+        .stdout("c\na\nb\n");
+    assert("a\nb\nb\nc\nc\nc\n", &["--repeated", "-s"])
+        .success()
+        .stdout("2 b\n3 c\n");
+}
+
+#[test]
+fn unique_only() {
+    let input = "c\na\nb\na\nd\nb\ne\nb\n";
+    assert(input, &["--unique-only"])
+        .success()
+        .stdout("c\nd\ne\n");
+    assert(input, &["--unique-only", "--repeated"]).failure();
+}
+
+#[test]
+fn all_repeated() {
+    let input = "c\na\nb\na\nd\nb\nc\nb\ne";
+    assert(input, &["--all-repeated"])
+        .success()
+        .stdout("c\nc\na\na\nb\nb\nb\n");
+    assert(input, &["--all-repeated=separate"])
+        .success()
+        .stdout("c\nc\n\na\na\n\nb\nb\nb\n");
+    assert("a\r\na\nb\n", &["--crlf", "--all-repeated"])
+        .success()
+        .stdout("a\r\na\n");
+}
+
+#[test]
+fn group() {
+    let input = "c\na\nb\na\nc\nb\n";
+    assert(input, &["--group"])
+        .success()
+        .stdout("c\nc\n\na\na\n\nb\nb\n");
+    assert(input, &["--group=both"])
+        .success()
+        .stdout("\nc\nc\n\na\na\n\nb\nb\n\n");
+    assert("a\nb\na\n", &["--group=append", "--unique-only"])
+        .success()
+        .stdout("b\n\n");
+}
+
+#[test]
+fn count_range() {
+    let input = "a\nb\nb\nc\nc\nc\nd\nd\nd\nd\n";
+    assert(input, &["-s", "--min-count", "2", "--max-count", "3"])
+        .success()
+        .stdout("2 b\n3 c\n");
+    assert(input, &["--min-count", "3"])
+        .success()
+        .stdout("c\nd\n");
+    assert(input, &["--repeated", "--max-count", "2"])
+        .success()
+        .stdout("b\n");
+}
+
+#[test]
+fn top() {
+    let input = "a\nb\nb\nc\nc\nc\nd\nd\nd\nd\n";
+    assert(input, &["--top", "2"])
+        .success()
+        .stdout("4 d\n3 c\n");
+    assert(input, &["--top", "5", "--max-count", "3"])
+        .success()
+        .stdout("3 c\n2 b\n1 a\n");
+    assert(input, &["--top", "2", "-j", "3"])
+        .success()
+        .stdout("4 d\n3 c\n");
+}
+
+#[test]
+fn bottom() {
+    let input = "a\nb\nb\nc\nc\nc\nd\nd\nd\nd\n";
+    assert(input, &["--bottom", "2"])
+        .success()
+        .stdout("1 a\n2 b\n");
+    assert(input, &["--bottom", "2", "--top", "2"]).failure();
+}
+
+#[test]
+fn cumulative() {
+    let input = "a\nb\nb\nc\nc\nc\nd\nd\nd\nd\n";
+    assert(input, &["-S", "--cumulative"])
+        .success()
+        .stdout("4 40.00% d\n3 70.00% c\n2 90.00% b\n1 100.00% a\n");
+    assert(input, &["--top", "1", "--cumulative"])
+        .success()
+        .stdout("4 40.00% d\n");
+    assert(input, &["-c", "--cumulative"]).failure();
+}
+
+#[test]
+fn format() {
+    let input = "a\nb\nb\nc\nc\nc\nd\nd\nd\nd\n";
+    assert(
+        input,
+        &[
+            "--top",
+            "2",
+            "--format",
+            "{rank}. {line}\\t{count} {percent}%",
+        ],
+    )
+    .success()
+    .stdout("1. d\t4 40.00%\n2. c\t3 30.00%\n");
+    assert("a\n", &["--format", "{{{line}}}"])
+        .success()
+        .stdout("{a}\n");
+    assert("a\n", &["--format", "{lines}"]).failure();
+}
+
+#[test]
+fn uniq_compat() {
+    assert("a\nb\nb\n", &["--uniq-compat", "-s"])
+        .success()
+        .stdout("      1 a\n      2 b\n");
+    assert("a\n", &["--format", "[{count:3}|{line:3}]"])
+        .success()
+        .stdout("[  1|  a]\n");
+}
+
+#[test]
+fn count_tab() {
+    assert("a\nb\nb\n", &["-c", "--count-tab", "-s"])
+        .success()
+        .stdout("1\ta\n2\tb\n");
+    assert("a\nb\nb\n", &["--count-tab", "--cumulative", "-s"])
+        .success()
+        .stdout("1\t33.33%\ta\n2\t100.00%\tb\n");
+}
+
+#[test]
+fn count_last() {
+    assert("1\n2\n2\n", &["-c", "--count-last", "-s"])
+        .success()
+        .stdout("1 1\n2 2\n");
+    assert(
+        "a\nb\nb\n",
+        &["--count-last", "--count-tab", "--cumulative", "-s"],
+    )
+    .success()
+    .stdout("a\t1\t33.33%\nb\t2\t100.00%\n");
+}
+
+#[test]
+fn json() {
+    assert("a\"b\\\nc\td\nc\td\n", &["--json", "-s"])
+        .success()
+        .stdout("{\"count\":1,\"line\":\"a\\\"b\\\\\"}\n{\"count\":2,\"line\":\"c\\td\"}\n");
+    assert("\x01\n", &["--json", "-0"])
+        .success()
+        .stdout("{\"count\":1,\"line\":\"\\u0001\\n\"}\n");
+}
+
+#[test]
+fn csv() {
+    assert("a,b\0c\"d\0c\"d\0", &["-0", "--csv", "-s"])
+        .success()
+        .stdout("1,\"a,b\"\n2,\"c\"\"d\"\n");
+    assert("e\r\nf\0plain\0plain\0", &["-0", "--csv", "-s"])
+        .success()
+        .stdout("1,\"e\r\nf\"\n2,plain\n");
+}
+
+#[test]
+fn tsv() {
+    assert("a\tb\0c\\d\0c\\d\0", &["-0", "--tsv", "-s"])
+        .success()
+        .stdout("1\ta\\tb\n2\tc\\\\d\n");
+    assert("e\r\nf\0", &["-0", "--tsv"])
+        .success()
+        .stdout("1\te\\r\\nf\n");
+}
+
+#[test]
+fn stats() {
+    let out = assert("a\nb\na\n", &["--stats"]).success().stdout("a\nb\n");
+    let stderr = String::from_utf8(out.get_output().stderr.clone()).unwrap();
+    let lines: Vec<&str> = stderr.lines().take(5).collect();
+    assert_eq!(
+        lines,
+        [
+            "records read:          3",
+            "unique records:        2",
+            "duplicates suppressed: 1",
+            "bytes in:              6",
+            "bytes out:             4",
+        ]
+    );
+}
+
+#[test]
+fn stats_overestimated() {
+    // With this seed, more distinct records are estimated than read
+    let input: String = (0..1000).map(|i| format!("{}\n", i + 1)).collect();
+    let out = assert(&input, &["-n", "--estimate", "--seed", "1", "--stats"])
+        .success()
+        .stdout("1002\n");
+    let stderr = String::from_utf8(out.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("duplicates suppressed: 0\n"), "{}", stderr);
+}
+
+#[test]
+fn count_distinct() {
+    assert("a\nb\na\nc\n", &["-n"]).success().stdout("3\n");
+    assert("a\nb\na\nc\n", &["--count-distinct", "-j", "3"])
+        .success()
+        .stdout("3\n");
+    assert("", &["-n"]).success().stdout("0\n");
+}
+
+#[test]
+fn estimate() {
+    assert("a\nb\na\nc\n", &["--estimate"])
+        .success()
+        .stdout("3\n");
+    let input: String = (0..100_000).map(|i| format!("{}\n", i % 50_000)).collect();
+    let out = assert(&input, &["--estimate"]).success();
+    let estimate: f64 = String::from_utf8(out.get_output().stdout.clone())
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+    assert!(
+        (estimate - 50_000.0).abs() < 50_000.0 * 0.03,
+        "{}",
+        estimate
+    );
+}
+
+#[test]
+fn approximate_counts() {
+    let mut input = String::new();
+    for i in 0..20_000 {
+        input.push_str(&format!("{}\n", i));
+    }
+    input.push_str(&"a\n".repeat(300));
+    input.push_str(&"b\n".repeat(200));
+    assert(&input, &["--approximate-counts", "--top", "2"])
+        .success()
+        .stdout("300 a\n200 b\n");
+    assert("a\n", &["--approximate-counts"]).failure();
+}
+
+#[test]
+fn bloom() {
+    assert("a\nb\na\nc\nb\n", &["--bloom"])
+        .success()
+        .stdout("a\nb\nc\n");
+    assert(
+        "a\nb\na\n",
+        &["--bloom", "--fpr", "0.01", "--expected-lines", "100"],
+    )
+    .success()
+    .stdout("a\nb\n");
+    assert("a\n", &["--bloom", "--fpr", "2"]).failure();
+}
+
+#[test]
+fn cuckoo() {
+    assert("a\nb\na\nc\nb\n", &["--cuckoo"])
+        .success()
+        .stdout("a\nb\nc\n");
+    let input: String = (0..100).map(|i| format!("{}\n", i)).collect();
+    assert(&input, &["--cuckoo", "--expected-lines", "4"]).failure();
+    // Expired records are removed, making room for the others
+    assert(
+        &input,
+        &["--cuckoo", "--expected-lines", "4", "--expire", "0s"],
+    )
+    .success()
+    .stdout(input.clone());
+    assert(
+        &input,
+        &["--cuckoo", "--expected-lines", "4", "--expire", "1h"],
+    )
+    .failure();
+    assert(
+        "a
+a
+b
+",
+        &["--cuckoo", "--expire", "1h"],
+    )
+    .success()
+    .stdout(
+        "a
+b
+",
+    );
+}
+
+#[test]
+fn spill_after() {
+    assert("a\nb\na\nc\nb\nd\nc\ne\na", &["--spill-after", "2"])
+        .success()
+        .stdout("a\nb\nc\nd\ne\n");
+    assert(
+        "a\nb\na\nc\nb\nd\nc\ne\na\n",
+        &["--spill-after", "1", "-j", "3"],
+    )
+    .success()
+    .stdout("a\nb\nc\nd\ne\n");
+}
+
+#[test]
+fn max_memory() {
+    let input: String = (0..10_000).map(|i| format!("{}\n", i % 5_000)).collect();
+    let expected: String = (0..5_000).map(|i| format!("{}\n", i)).collect();
+    assert(&input, &["--max-memory", "4K"]).failure();
+    assert(
+        &input,
+        &["--max-memory", "4K", "--on-memory-limit", "spill"],
+    )
+    .success()
+    .stdout(expected.clone());
+    let out = assert(
+        &input,
+        &["--max-memory", "64K", "--on-memory-limit", "approximate"],
+    )
+    .success();
+    let lines = out.get_output().stdout.split(|&b| b == b'\n').count() - 1;
+    assert!(lines <= 5_000 && lines > 4_900, "{}", lines);
+    assert(&input, &["--max-memory", "1M"])
+        .success()
+        .stdout(expected);
+}
+
+#[test]
+fn window() {
+    // a is refreshed by its duplicates, so b and c are forgotten first
+    assert("a\nb\na\nc\na\nd\nb\n", &["--window", "2"])
+        .success()
+        .stdout("a\nb\nc\nd\nb\n");
+}
+
+#[test]
+fn last() {
+    assert("a\na\nb\na\nb\nb\nc\na\n", &["--last", "1"])
+        .success()
+        .stdout("a\nb\na\nb\nc\na\n");
+    assert("a\na\nb\na\nc\nd\na\n", &["--last", "2"])
+        .success()
+        .stdout("a\nb\nc\nd\na\n");
+}
+
+#[test]
+fn expire() {
+    let path = tmp_path("expire");
+    fs::write(&path, "a\na\n").unwrap();
+
+    let mut child = process::Command::new(cargo_bin("huniq"))
+        .args(["-f", "--expire", "1s", &path])
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut out = BufReader::new(child.stdout.take().unwrap()).lines();
+    assert_eq!(out.next().unwrap().unwrap(), "a");
+
+    thread::sleep(Duration::from_millis(1200));
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"a\nb\na\n").unwrap();
+    assert_eq!(out.next().unwrap().unwrap(), "a");
+    assert_eq!(out.next().unwrap().unwrap(), "b");
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn reset_every() {
+    let input = "a\nb\na\nb\nc\na\n";
+    assert(input, &["--reset-every", "3"])
+        .success()
+        .stdout("a\nb\nb\nc\na\n");
+    assert(input, &["--reset-every", "1"])
+        .success()
+        .stdout(input);
+    assert(input, &["--reset-every", "0"]).failure();
+}
+
+#[test]
+fn reset_interval() {
+    assert("a\nb\na\n", &["--reset-interval", "1h"])
+        .success()
+        .stdout("a\nb\n");
+    assert("a\n", &["--reset-interval", "soon"]).failure();
+}
+
+#[test]
+fn stats_on_usr1() {
+    let path = tmp_path("stats_on_usr1");
+    // Ending with a record printed, so it has been read once printed
+    fs::write(&path, "a\na\nb\n").unwrap();
+
+    let mut child = process::Command::new(cargo_bin("huniq"))
+        .args(["-f", &path])
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut out = BufReader::new(child.stdout.take().unwrap()).lines();
+    assert_eq!(out.next().unwrap().unwrap(), "a");
+    assert_eq!(out.next().unwrap().unwrap(), "b");
+
+    let kill = process::Command::new("kill")
+        .args(["-USR1", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(kill.success());
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"c\n").unwrap();
+    assert_eq!(out.next().unwrap().unwrap(), "c");
+
+    child.kill().unwrap();
+    let err = child.wait_with_output().unwrap().stderr;
+    let err = String::from_utf8(err).unwrap();
+    assert!(err.contains("records read:          4\n"), "{}", err);
+    assert!(err.contains("unique records:        2\n"), "{}", err);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn reset_on_usr2() {
+    let path = tmp_path("reset_on_usr2");
+    // Ending with a record printed, so it has been read once printed
+    fs::write(&path, "a\na\nb\n").unwrap();
+
+    let mut child = process::Command::new(cargo_bin("huniq"))
+        .args(["-f", &path])
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut out = BufReader::new(child.stdout.take().unwrap()).lines();
+    assert_eq!(out.next().unwrap().unwrap(), "a");
+    assert_eq!(out.next().unwrap().unwrap(), "b");
+
+    let kill = process::Command::new("kill")
+        .args(["-USR2", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(kill.success());
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"b\nb\nc\n").unwrap();
+    assert_eq!(out.next().unwrap().unwrap(), "b");
+    assert_eq!(out.next().unwrap().unwrap(), "c");
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn partial_counts_on_interrupt() {
+    let mut child = process::Command::new(cargo_bin("huniq"))
+        .arg("-c")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"a\nb\na\n").unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    let kill = process::Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(kill.success());
+    // Noticed with the next record; nothing after it is read
+    stdin.write_all(b"c\n").unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(!out.status.success());
+    assert_eq!(out.stdout, b"2 a\n1 b\n1 c\n");
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(err.contains("partial"), "{}", err);
+    drop(stdin);
+}
+
+#[test]
+fn closed_output() {
+    let path = tmp_path("closed_output");
+    let input: String = (0..1_000_000).map(|i| format!("{}\n", i)).collect();
+    fs::write(&path, input).unwrap();
+
+    for args in [&[][..], &["-c"], &["-j4"], &["-j4", "-c"]] {
+        let mut child = process::Command::new(cargo_bin("huniq"))
+            .args(args)
+            .arg(&path)
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut out = BufReader::new(child.stdout.take().unwrap()).lines();
+        out.next().unwrap().unwrap();
+        drop(out);
+
+        let out = child.wait_with_output().unwrap();
+        assert_eq!(out.status.code(), Some(141));
+        assert_eq!(String::from_utf8(out.stderr).unwrap(), "");
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn closed_output_threads() {
+    let mut child = process::Command::new(cargo_bin("huniq"))
+        .arg("-j4")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Endless, so the queues fill up once the output is closed
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || {
+        for i in 0.. {
+            if writeln!(stdin, "{}", i).is_err() {
+                break;
+            }
+        }
+    });
+    let mut out = BufReader::new(child.stdout.take().unwrap()).lines();
+    out.next().unwrap().unwrap();
+    drop(out);
+
+    let out = child.wait_with_output().unwrap();
+    assert_eq!(out.status.code(), Some(141));
+    assert_eq!(String::from_utf8(out.stderr).unwrap(), "");
+    writer.join().unwrap();
+}
+
+#[test]
+fn state() {
+    let path = tmp_path("state");
+    let _ = fs::remove_file(&path);
+    let state = ["--load-state", &path, "--dump-state", &path];
+    assert("a\nb\na\n", &state).success().stdout("a\nb\n");
+    assert("b\nc\na\n", &state).success().stdout("c\n");
+    assert("d\nc\n", &["--load-state", &path])
+        .success()
+        .stdout("d\n");
+    assert("", &["-c", "--load-state", &path]).failure();
+
+    fs::remove_file(&path).unwrap();
+    let state = ["-c", "--load-state", &path, "--dump-state", &path];
+    assert("a\nb\na\n", &state).success().stdout("2 a\n1 b\n");
+    assert("b\nc\n", &state).success().stdout("2 a\n2 b\n1 c\n");
+    assert(
+        "b\nc\nc\nc\n",
+        &["-c", "--load-state", &path, "--threads", "2", "-S"],
+    )
+    .success()
+    .stdout("4 c\n3 b\n2 a\n");
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn filter_file() {
+    let path = tmp_path("filter_file");
+    fs::write(&path, "b\nd\nb\n").unwrap();
+    assert("a\nb\nc\na\nd\ne\n", &["--filter-file", &path])
+        .success()
+        .stdout("a\nc\ne\n");
+    assert("a\0b\0", &["-0", "--filter-file", &path])
+        .success()
+        .stdout("a\0b\0");
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn dump_lines() {
+    let path = tmp_path("dump_lines");
+    let _ = fs::remove_file(&path);
+    assert("a\nb\na\n", &["--dump-lines", &path])
+        .success()
+        .stdout("a\nb\n");
+    assert_eq!(fs::read_to_string(&path).unwrap(), "a\nb\n");
+
+    let state = ["--filter-file", &path, "--dump-lines", &path];
+    assert("c\nb\nc", &state).success().stdout("c\n");
+    assert_eq!(fs::read_to_string(&path).unwrap(), "a\nb\nc\n");
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn serve() {
+    let path = tmp_path("serve");
+    let mut server = process::Command::new(cargo_bin("huniq"))
+        .args(["--serve", &path])
+        .spawn()
+        .unwrap();
+    while fs::metadata(&path).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let connect = ["--connect", &path];
+    assert("a\nb\na\n", &connect).success().stdout("a\nb\n");
+    assert("c\nb\nd\n", &connect).success().stdout("c\nd\n");
+    assert("a\n", &["--connect", &path, "-c"]).failure();
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn query() {
+    let path = tmp_path("query");
+    let _ = fs::remove_file(&path);
+    assert("a\nb\n", &["--dump-state", &path]).success();
+    assert("b\nc\n", &["--query", &path])
+        .failure()
+        .stdout("seen\tb\nnew\tc\n");
+    assert("a\n", &["--query", &path])
+        .success()
+        .stdout("seen\ta\n");
+    // Unchanged by querying
+    assert("c\n", &["--query", &path]).failure();
+    fs::remove_file(&path).unwrap();
+    assert("a\n", &["--query", &path]).failure();
+}
+
+#[test]
+fn store() {
+    let path = tmp_path("store");
+    let _ = fs::remove_file(&path);
+    let store = ["--store", &path];
+    assert("a\nb\na\n", &store).success().stdout("a\nb\n");
+    assert("b\nc\na\n", &store).success().stdout("c\n");
+
+    // Grows beyond the initial size
+    let input: String = (0..600_000).map(|i| format!("{}\n", i)).collect();
+    let out = assert(&input, &store).success();
+    assert_eq!(out.get_output().stdout, input.as_bytes());
+    assert(&input, &store).success().stdout("");
+    fs::remove_file(&path).unwrap();
+
+    fs::write(&path, "not a store").unwrap();
+    assert("a\n", &store).failure();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn shared_store() {
+    let path = tmp_path("shared_store");
+    let _ = fs::remove_file(&path);
+    // Overlapping inputs, so the store grows while shared
+    let children: Vec<_> = (0..3)
+        .map(|i| {
+            let mut child = process::Command::new(cargo_bin("huniq"))
+                .args(["--store", &path])
+                .stdin(process::Stdio::piped())
+                .stdout(process::Stdio::piped())
+                .spawn()
+                .unwrap();
+            let mut stdin = child.stdin.take().unwrap();
+            thread::spawn(move || {
+                for n in i * 200_000..i * 200_000 + 600_000 {
+                    writeln!(stdin, "{}", n).unwrap();
+                }
+            });
+            child
+        })
+        .collect();
+
+    let mut printed: Vec<u64> = Vec::new();
+    for child in children {
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success());
+        let out = String::from_utf8(out.stdout).unwrap();
+        printed.extend(out.lines().map(|l| l.parse::<u64>().unwrap()));
+    }
+    printed.sort_unstable();
+    assert_eq!(printed, (0..1_000_000).collect::<Vec<_>>());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn merge() {
+    let (a, b) = (tmp_path("merge_a"), tmp_path("merge_b"));
+    fs::write(&a, "2 x\n1 y z\n").unwrap();
+    fs::write(&b, "      3 y z\n5\tw\n").unwrap();
+    assert("", &["--merge", &a, &b])
+        .success()
+        .stdout("2 x\n4 y z\n5 w\n");
+    assert("", &["--merge", "-S", "--count-tab", &a, &b])
+        .success()
+        .stdout("5\tw\n4\ty z\n2\tx\n");
+    assert("x\n", &["--merge"]).failure();
+    fs::remove_file(&a).unwrap();
+    fs::remove_file(&b).unwrap();
+}
+
+#[test]
+fn subtract() {
+    let baseline = tmp_path("subtract");
+    fs::write(&baseline, "2 x\n1 y\n4 z\n").unwrap();
+    let current = "5 x\n1 y\n1 w\n";
+    assert(current, &["--subtract", &baseline])
+        .success()
+        .stdout("+3 x\n+1 w\n-4 z\n");
+    assert(current, &["--subtract", &baseline, "-s", "--count-last"])
+        .success()
+        .stdout("z -4\nw +1\nx +3\n");
+    fs::remove_file(&baseline).unwrap();
+}
+
+#[test]
+fn set_operations() {
+    let (a, b) = (tmp_path("set_a"), tmp_path("set_b"));
+    fs::write(&a, "x\ny\nz\ny\n").unwrap();
+    fs::write(&b, "z\nw\nx\nz\n").unwrap();
+    let (a, b) = (a.as_str(), b.as_str());
+    assert("", &["--union", a, b])
+        .success()
+        .stdout("x\ny\nz\nw\n");
+    assert("", &["--intersect", a, b])
+        .success()
+        .stdout("z\nx\n");
+    assert("y\nx\nv\n", &["--intersect", a, "-", b])
+        .success()
+        .stdout("x\n");
+    assert("", &["--difference", a, b]).success().stdout("y\n");
+    assert("", &["--difference", b, a]).success().stdout("w\n");
+    assert("", &["--membership", a, b])
+        .success()
+        .stdout(format!("{a},{b}\tx\n{a}\ty\n{a},{b}\tz\n{b}\tw\n"));
+    fs::remove_file(a).unwrap();
+    fs::remove_file(b).unwrap();
+}
+
+#[test]
+fn per_file() {
+    let (a, b) = (tmp_path("per_file_a"), tmp_path("per_file_b"));
+    fs::write(&a, "x\ny\nx\n").unwrap();
+    fs::write(&b, "y,z\nx\n").unwrap();
+    let (a, b) = (a.as_str(), b.as_str());
+    assert("", &["--per-file", a, b])
+        .success()
+        .stdout("2 1 x\n1 0 y\n0 1 y,z\n");
+    assert("", &["-c", "--per-file", "--csv", a, b])
+        .success()
+        .stdout("2,1,x\n1,0,y\n0,1,\"y,z\"\n");
+    assert("", &["--per-file", "--tsv", "--unique-only", a, b])
+        .success()
+        .stdout("1\t0\ty\n0\t1\ty,z\n");
+    fs::remove_file(a).unwrap();
+    fs::remove_file(b).unwrap();
+}
+
+#[test]
+fn per_file_reset() {
+    let (a, b) = (tmp_path("reset_a"), tmp_path("reset_b"));
+    fs::write(&a, "x\ny\nx\n").unwrap();
+    fs::write(&b, "y\nz\ny\n").unwrap();
+    let (a, b) = (a.as_str(), b.as_str());
+    assert("", &["--per-file-reset", a, b])
+        .success()
+        .stdout("x\ny\ny\nz\n");
+    assert("", &["--per-file-reset", "-c", a, b])
+        .success()
+        .stdout("2 x\n1 y\n2 y\n1 z\n");
+    assert("", &["--per-file-reset", "--threads", "2", a, b, a, b])
+        .success()
+        .stdout("x\ny\ny\nz\nx\ny\ny\nz\n");
+    assert("", &["--per-file-reset", "--threads", "3", "-c", b, a, b])
+        .success()
+        .stdout("2 y\n1 z\n2 x\n1 y\n2 y\n1 z\n");
+    fs::remove_file(a).unwrap();
+    fs::remove_file(b).unwrap();
+}
+
+#[test]
+fn files_from() {
+    let (a, b) = (tmp_path("files_from_a"), tmp_path("files_from_b"));
+    fs::write(&a, "x\ny\n").unwrap();
+    fs::write(&b, "y\nz\n").unwrap();
+    let (a, b) = (a.as_str(), b.as_str());
+    assert(&format!("{a}\n{b}\n"), &["--files-from", "-"])
+        .success()
+        .stdout("x\ny\nz\n");
+    assert(b, &["--files-from", "-", a])
+        .success()
+        .stdout("x\ny\nz\n");
+    fs::write(a, "x\0y\0").unwrap();
+    fs::write(b, "y\0z\0").unwrap();
+    assert(&format!("{b}\0{a}\0"), &["--files-from", "-", "-0"])
+        .success()
+        .stdout("y\0z\0x\0");
+    fs::remove_file(a).unwrap();
+    fs::remove_file(b).unwrap();
+}
+
+#[test]
+fn recursive() {
+    let dir = tmp_path("recursive");
+    fs::create_dir_all(format!("{dir}/sub")).unwrap();
+    fs::write(format!("{dir}/b.log"), "y\nz\n").unwrap();
+    fs::write(format!("{dir}/a.log"), "x\ny\n").unwrap();
+    fs::write(format!("{dir}/sub/c.txt"), "w\nx\n").unwrap();
+    assert("", &["-r", &dir]).success().stdout("x\ny\nz\nw\n");
+    assert("", &[&format!("{dir}/*.log")])
+        .success()
+        .stdout("x\ny\nz\n");
+    assert("", &[&format!("{dir}/*.csv")]).failure();
+    assert("", &[&dir]).failure();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn fields() {
+    let input = "1 alice x\n2  bob y\n3\talice x\n4 bob z\n5 carol\n";
+    assert(input, &["-k", "2"])
+        .success()
+        .stdout("1 alice x\n2  bob y\n5 carol\n");
+    assert(input, &["--field", "2-"])
+        .success()
+        .stdout("1 alice x\n2  bob y\n4 bob z\n5 carol\n");
+    assert(input, &["-k", "2", "-c"])
+        .success()
+        .stdout("2 alice\n2 bob\n1 carol\n");
+    assert(
+        "a,b,c\na,x,c\nb,b,c\n",
+        &["-k", "1,3", "--field-delim", ","],
+    )
+    .success()
+    .stdout("a,b,c\nb,b,c\n");
+    assert(
+        "a,b,c\nx,b,c\na,b,d\n",
+        &["-k", "2-3", "--field-delim", ","],
+    )
+    .success()
+    .stdout("a,b,c\na,b,d\n");
+    assert("", &["-k", "0"]).failure();
+}
+
+#[test]
+fn skip_fields() {
+    let input = "10:00 GET /\n10:01 GET /\n10:02  GET /\n10:03 POST /\n";
+    assert(input, &["--skip-fields", "1"])
+        .success()
+        .stdout("10:00 GET /\n10:02  GET /\n10:03 POST /\n");
+    assert(input, &["--skip-fields", "2", "-c"])
+        .success()
+        .stdout("4  /\n");
+    assert(input, &["--skip-fields", "5"])
+        .success()
+        .stdout("10:00 GET /\n");
+}
+
+#[test]
+fn skip_chars() {
+    let input = "[10:00] started\n[10:01] started\n[10:02] stopped\n";
+    assert(input, &["--skip-chars", "8"])
+        .success()
+        .stdout("[10:00] started\n[10:02] stopped\n");
+    assert(
+        "a äx\nb öx\nc üy\n",
+        &["--skip-fields", "1", "--skip-chars", "2"],
+    )
+    .success()
+    .stdout("a äx\nc üy\n");
+    assert("ab\nb\n", &["--skip-chars", "3"])
+        .success()
+        .stdout("ab\n");
+}
+
+#[test]
+fn check_chars() {
+    let input = "10.0.0.1 a\n10.0.0.2 b\n10.1.0.1 c\n";
+    assert(input, &["--check-chars", "4"])
+        .success()
+        .stdout("10.0.0.1 a\n10.1.0.1 c\n");
+    assert(input, &["--skip-chars", "5", "--check-chars", "3", "-c"])
+        .success()
+        .stdout("2 0.1\n1 0.2\n");
+    assert("ab\nac\nb\n", &["--check-chars", "0"])
+        .success()
+        .stdout("ab\n");
+}
+
+#[test]
+fn key_bytes() {
+    let input = "0001ABCx\n0002ABCy\n0003XYZx\n";
+    assert(input, &["--key-bytes", "5-7"])
+        .success()
+        .stdout("0001ABCx\n0003XYZx\n");
+    assert(input, &["--key-bytes", "8-", "-c"])
+        .success()
+        .stdout("2 x\n1 y\n");
+    assert("abcbad", &["--record-size", "2", "--key-bytes", "2"])
+        .success()
+        .stdout("abad");
+    assert("", &["--key-bytes", "0-3"]).failure();
+}
+
+#[test]
+fn key_regex() {
+    let input = "GET user=bob\nPOST user=alice\nGET user=bob id=1\nstartup\nstartup\n";
+    assert(input, &["--key-regex", r"user=(\w+)"])
+        .success()
+        .stdout("GET user=bob\nPOST user=alice\nstartup\n");
+    assert(
+        input,
+        &["--key-regex", r"user=(\w+)", "--unmatched", "drop"],
+    )
+    .success()
+    .stdout("GET user=bob\nPOST user=alice\n");
+    assert(
+        input,
+        &["--key-regex", r"user=(\w+)", "--unmatched", "pass"],
+    )
+    .success()
+    .stdout("GET user=bob\nPOST user=alice\nstartup\nstartup\n");
+    assert(input, &["--key-regex", "^[A-Z]+", "-c"])
+        .success()
+        .stdout("2 GET\n1 POST\n2 startup\n");
+    assert("", &["--key-regex", "(a"]).failure();
+}
+
+#[test]
+fn key_command() {
+    let input = "Foo\nfoo\nBAR\nbaz\nbar\n";
+    assert(input, &["--key-command", "tr A-Z a-z"])
+        .success()
+        .stdout("Foo\nBAR\nbaz\n");
+    assert(input, &["--key-command", "tr A-Z a-z", "--unique-only"])
+        .success()
+        .stdout("baz\n");
+    assert("a\0A\0b\0", &["-0", "--key-command", "tr a A"])
+        .success()
+        .stdout("a\0b\0");
+    assert(input, &["--key-command", "head -n 1"]).failure();
+    assert(input, &["--key-command", "cat; echo"]).failure();
+    assert(input, &["--key-command", "cat; exit 1"]).failure();
+}
+
+#[test]
+fn key_command_killed_on_error() {
+    let path = tmp_path("key_command_killed_on_error");
+    // Printing more keys than records, then not exiting by itself
+    let command = format!("echo $$ > {}; sed p; sleep 60", path);
+    let input = "a\n".repeat(100_000);
+    assert(&input, &["--key-command", &command]).failure();
+
+    let pid = fs::read_to_string(&path).unwrap();
+    let alive = process::Command::new("kill")
+        .args(["-0", pid.trim()])
+        .stderr(process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!alive.success());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn key_script() {
+    let input = "/a?x=1\n/A?y=2\n/b\n/b?z\n";
+    assert(
+        input,
+        &["--key-script", "line.to_lowercase().split('?')[0]"],
+    )
+    .success()
+    .stdout("/a?x=1\n/b\n");
+    assert(
+        "a b c\nx y c\nd\n",
+        &["-c", "--key-script", r#"line.split(" ")[-1] + "!""#],
+    )
+    .success()
+    .stdout("2 c!\n1 d!\n");
+    assert(
+        "a,b\n",
+        &[
+            "--key-script",
+            "line.split(',').join(';').sub_string(1, 2)",
+            "-c",
+        ],
+    )
+    .success()
+    .stdout("1 ;b\n");
+    assert("", &["--key-script", "line.split(',')"]).failure();
+    assert("", &["--key-script", "line.sub_string('a', 1)"]).failure();
+}
+
+#[test]
+fn fold_case() {
+    let input = "STRASSE\nstraße\nFoo\nfoo\nÉté\nété\n";
+    assert(input, &["--fold-case"])
+        .success()
+        .stdout("STRASSE\nstraße\nFoo\nÉté\nété\n");
+    assert(input, &["--fold-case=unicode"])
+        .success()
+        .stdout("STRASSE\nFoo\nÉté\n");
+    assert(input, &["--fold-case=unicode", "-c"])
+        .success()
+        .stdout("2 strasse\n2 foo\n2 été\n");
+}
+
+#[test]
+fn normalize() {
+    // Composed, decomposed, and with the accents in another order
+    let input = "\u{e9}\ne\u{301}\nq\u{307}\u{323}\nq\u{323}\u{307}\n\u{d55c}\n\u{1112}\u{1161}\u{11ab}\n\u{fb01}\nfi\n";
+    assert(input, &["--normalize", "nfc"])
+        .success()
+        .stdout("\u{e9}\nq\u{307}\u{323}\n\u{d55c}\n\u{fb01}\nfi\n");
+    assert(input, &["--normalize", "nfkc"])
+        .success()
+        .stdout("\u{e9}\nq\u{307}\u{323}\n\u{d55c}\n\u{fb01}\n");
+    assert("e\u{301}\n", &["--normalize", "nfc", "-c"])
+        .success()
+        .stdout("1 \u{e9}\n");
+    assert("", &["--normalize", "nfd"]).failure();
+}
+
+#[test]
+fn whitespace() {
+    let input = "a b\n a b\na  b \na\t\u{a0}b\nab\n";
+    assert(input, &["--trim"])
+        .success()
+        .stdout("a b\na  b \na\t\u{a0}b\nab\n");
+    assert(input, &["--squeeze-space"])
+        .success()
+        .stdout("a b\n a b\na  b \nab\n");
+    assert(input, &["--trim", "--squeeze-space"])
+        .success()
+        .stdout("a b\nab\n");
+    assert(input, &["--trim", "--squeeze-space", "-c"])
+        .success()
+        .stdout("4 a b\n1 ab\n");
+}
+
+#[test]
+fn strip_regex() {
+    let input = "req 12 done in 5ms\nreq 13 done in 7ms\nreq 14 failed\n";
+    assert(input, &["--strip-regex", r"\d+"])
+        .success()
+        .stdout("req 12 done in 5ms\nreq 14 failed\n");
+    assert(input, &["--strip-regex", r"\d+", "-c"])
+        .success()
+        .stdout("2 req  done in ms\n1 req  failed\n");
+    assert(
+        "a1b\nab\nb\n",
+        &["--strip-regex", "^a", "--strip-regex", r"\d", "-c"],
+    )
+    .success()
+    .stdout("3 b\n");
+    assert("", &["--strip-regex", "("]).failure();
+}
+
+#[test]
+fn ignore_timestamps() {
+    let input = "Oct 14 16:29:01 host sshd: up\n\
+                 Oct  4 06:29:01 host sshd: up\n\
+                 2024-10-14T16:29:01.123+02:00 host sshd: up\n\
+                 [10/Oct/2024:16:29:01 +0200] host sshd: up\n\
+                 [   12.345678] host sshd: up\n\
+                 2024 host sshd: up\n";
+    assert(input, &["--ignore-timestamps"])
+        .success()
+        .stdout("Oct 14 16:29:01 host sshd: up\n2024 host sshd: up\n");
+    assert(input, &["--ignore-timestamps", "-c"])
+        .success()
+        .stdout("5 host sshd: up\n1 2024 host sshd: up\n");
+}
+
+#[test]
+fn json_records() {
+    let input = r#"{"b": 1, "a": [1.0, "x\/y"]}
+{"a":[10e-1,"x/y"],"b":1}
+{"a":[1,"x/y"],"b":0,"b":1}
+{"a":[1,"x/y"],"b":2}
+not json
+not json
+"#;
+    assert(input, &["--json-records"])
+        .success()
+        .stdout("{\"b\": 1, \"a\": [1.0, \"x\\/y\"]}\n{\"a\":[1,\"x/y\"],\"b\":2}\nnot json\n");
+    assert(input, &["--json-records", "-c"])
+        .success()
+        .stdout("3 {\"a\":[1,\"x/y\"],\"b\":1}\n1 {\"a\":[1,\"x/y\"],\"b\":2}\n2 not json\n");
+    assert(input, &["--json-records", "--unmatched", "drop", "-c"])
+        .success()
+        .stdout("3 {\"a\":[1,\"x/y\"],\"b\":1}\n1 {\"a\":[1,\"x/y\"],\"b\":2}\n");
+    assert(
+        input,
+        &["--json-records", "--unmatched", "pass"],
+    )
+    .success()
+    .stdout("{\"b\": 1, \"a\": [1.0, \"x\\/y\"]}\n{\"a\":[1,\"x/y\"],\"b\":2}\nnot json\nnot json\n");
+    // Negative zero equals zero
+    assert(
+        "{\"a\":0}\n{\"a\":-0}\n[-0.0]\n[0e5]\n-0\n0\n",
+        &["--json-records"],
+    )
+    .success()
+    .stdout("{\"a\":0}\n[-0.0]\n-0\n");
+}
+
+#[test]
+fn csv_key() {
+    let input = "1,\"Smith, J\",Berlin\n2,Smith,\"Ber\nlin\"\n3,\"Smith, J\",x\n4,\"Smith\",y\r\n";
+    assert(input, &["--csv-key", "2"])
+        .success()
+        .stdout("1,\"Smith, J\",Berlin\n2,Smith,\"Ber\nlin\"\n");
+    assert(input, &["--csv-key", "2", "-c"])
+        .success()
+        .stdout("2 Smith, J\n2 Smith\n");
+    assert(input, &["--csv-key", "2-3", "-c"])
+        .success()
+        .stdout("1 \"Smith, J\",Berlin\n1 Smith,\"Ber\nlin\"\n1 \"Smith, J\",x\n1 Smith,y\n");
+    assert("", &["--csv-key", "0"]).failure();
+}
+
+#[test]
+fn numeric_key() {
+    let input = "1\n1.0\n01\n+1e0\n2\n.5\n0.50\nx\n-0\n0\n";
+    assert(input, &["--numeric-key"])
+        .success()
+        .stdout("1\n2\n.5\nx\n-0\n");
+    assert(input, &["--numeric-key", "-c"])
+        .success()
+        .stdout("4 1\n1 2\n2 0.5\n1 x\n2 0\n");
+    assert("a 1\nb 1.0\n", &["--numeric-key", "-k", "2"])
+        .success()
+        .stdout("a 1\n");
+}
+
+#[test]
+fn key_ip() {
+    let input = "10.0.0.1\n::ffff:10.0.0.1\n2001:DB8:0::1\n2001:db8::1\nfoo\n10.0.0.9\n";
+    assert(input, &["--key-ip"])
+        .success()
+        .stdout("10.0.0.1\n2001:DB8:0::1\nfoo\n10.0.0.9\n");
+    assert(input, &["--key-ip", "-c", "--prefix", "/24,/32"])
+        .success()
+        .stdout("3 10.0.0.0/24\n2 2001:db8::/32\n1 foo\n");
+    assert(input, &["--key-ip", "--prefix", "/33,/64"]).failure();
+    assert(input, &["--prefix", "/24"]).failure();
+}
+
+#[test]
+fn fuzzy() {
+    let input = "error: connection to 10.0.0.1 port 5432 refused after 3 retries
+error: connection to 10.0.0.2 port 5432 refused after 3 retries
+warning disk almost full on /var
+error: connection to 10.0.0.1 port 5432 refused after 3 retries
+";
+    assert(input, &["--fuzzy"]).success().stdout(
+        "error: connection to 10.0.0.1 port 5432 refused after 3 retries
+warning disk almost full on /var
+",
+    );
+    assert(input, &["--fuzzy", "--threshold", "1"])
+        .success()
+        .stdout(
+            "error: connection to 10.0.0.1 port 5432 refused after 3 retries
+error: connection to 10.0.0.2 port 5432 refused after 3 retries
+warning disk almost full on /var
+",
+        );
+    // The same error with different free text
+    let input = "error: disk quota exceeded for user alice
+warning: request to the billing service timed out
+error: disk quota exceeded for user bob
+warning: request to the payments service timed out
+";
+    assert(input, &["--fuzzy"]).success().stdout(
+        "error: disk quota exceeded for user alice
+warning: request to the billing service timed out
+",
+    );
+    assert(input, &["--fuzzy", "--threshold", "0"]).failure();
+    assert(input, &["--threshold", "0.5"]).failure();
+}
+
+#[test]
+fn max_edit_distance() {
+    let input = "john smith\njon smith\nJohn Smith\njane doe\njohn smyth\nx\ny\n";
+    assert(input, &["--max-edit-distance", "1"])
+        .success()
+        .stdout("john smith\nJohn Smith\njane doe\nx\n");
+    assert(input, &["--max-edit-distance", "2", "--fold-case"])
+        .success()
+        .stdout("john smith\njane doe\nx\n");
+    assert(input, &["--max-edit-distance", "0"])
+        .success()
+        .stdout(input);
+    assert(input, &["--max-edit-distance", "1", "-c"]).failure();
+}
+
+#[test]
+fn as_paths() {
+    let (a, b, c) = (
+        tmp_path("as_paths_a"),
+        tmp_path("as_paths_b"),
+        tmp_path("as_paths_c"),
+    );
+    let missing = tmp_path("as_paths_missing");
+    fs::write(&a, "same\n").unwrap();
+    fs::write(&b, "same\n").unwrap();
+    fs::write(&c, "other\n").unwrap();
+    let input = format!("{}\n{}\n{}\n{}\n{}\n", a, b, c, missing, missing);
+    assert(&input, &["--as-paths"])
+        .success()
+        .stdout(format!("{}\n{}\n{}\n", a, c, missing));
+    assert(&input, &["--as-paths", "--unmatched", "pass"])
+        .success()
+        .stdout(format!("{}\n{}\n{}\n{}\n", a, c, missing, missing));
+
+    // The paths are opened as given, even if keys are normalized
+    let upper = tmp_path("AS_PATHS_UPPER");
+    fs::write(&upper, "same\n").unwrap();
+    let input = format!("{}\n{}\n{}\n", a, upper, c);
+    assert(&input, &["--as-paths", "--fold-case"])
+        .success()
+        .stdout(format!("{}\n{}\n", a, c));
+    assert(
+        &format!("{}\n{}\n", missing, missing.to_uppercase()),
+        &["--as-paths", "--fold-case"],
+    )
+    .success()
+    .stdout(format!("{}\n", missing));
+    fs::remove_file(&upper).unwrap();
+    fs::remove_file(&a).unwrap();
+    fs::remove_file(&b).unwrap();
+    fs::remove_file(&c).unwrap();
+}
+
+#[test]
+fn print_hash() {
+    let out = assert("a\nb\na\n", &["--print-hash"]).success();
+    let stdout = String::from_utf8(out.get_output().stdout.clone()).unwrap();
+    let lines: Vec<_> = stdout
+        .lines()
+        .map(|l| l.split_once('\t').unwrap())
+        .collect();
+    assert_eq!(lines.iter().map(|l| l.1).collect::<Vec<_>>(), ["a", "b"]);
+    assert!(lines
+        .iter()
+        .all(|(hash, _)| hash.len() == 16 && u64::from_str_radix(hash, 16).is_ok()));
+    assert_ne!(lines[0].0, lines[1].0);
+
+    let out = assert("a\na", &["--print-hash=suffix", "--null"]).success();
+    let stdout = String::from_utf8(out.get_output().stdout.clone()).unwrap();
+    let (record, hash) = stdout.strip_suffix('\0').unwrap().split_once('\t').unwrap();
+    assert_eq!(record, "a\na");
+    assert_eq!(hash.len(), 16);
+    assert("a\n", &["--print-hash", "-c"]).failure();
+}
+
+#[test]
+fn hash_input() {
+    let input = "ff\n0xFF\n00ff\n1\nzz\nzz\n";
+    assert(input, &["--hash-input"])
+        .success()
+        .stdout("ff\n1\nzz\n");
+    let out = assert(input, &["--hash-input", "--print-hash"]).success();
+    let stdout = String::from_utf8(out.get_output().stdout.clone()).unwrap();
+    assert!(stdout.starts_with("00000000000000ff\tff\n0000000000000001\t1\n"));
+    assert(input, &["--hash-input", "-c"])
+        .success()
+        .stdout("3 00000000000000ff\n1 0000000000000001\n2 zz\n");
+    assert("255\n0255\nff\n", &["--hash-input=decimal", "-c"])
+        .success()
+        .stdout("2 255\n1 ff\n");
+}
+
+#[test]
+fn exact() {
+    assert("a\nb\na\nc\nb\n", &["--exact"])
+        .success()
+        .stdout("a\nb\nc\n");
+    assert("a 1\nb 1\na 2\n", &["--exact", "-k", "1"])
+        .success()
+        .stdout("a 1\nb 1\n");
+    assert("a\nb\na\nb\nc\na\n", &["--exact", "--reset-every", "3"])
+        .success()
+        .stdout("a\nb\nb\nc\na\n");
+    assert("a\nb\nb\nb\n", &["--exact", "--reset-every", "2"])
+        .success()
+        .stdout("a\nb\nb\n");
+    assert("a\n", &["--exact", "--bloom"]).failure();
+}
+
+#[test]
+fn hash_bits() {
+    assert("a\nb\na\nc\nb\n", &["--hash-bits", "128"])
+        .success()
+        .stdout("a\nb\nc\n");
+    assert("a\nb\na\n", &["--hash-bits", "64"])
+        .success()
+        .stdout("a\nb\n");
+    assert("a\n", &["--hash-bits", "32"]).failure();
+    assert("a\n", &["--hash-bits", "128", "--bloom"]).failure();
+}
+
+#[test]
+fn hasher() {
+    for hasher in ["ahash", "siphash", "wyhash"] {
+        assert("a\nb\na\nc\nb\n", &["--hasher", hasher])
+            .success()
+            .stdout("a\nb\nc\n");
+        assert("a\nb\na\n", &["--hasher", hasher, "--count-distinct"])
+            .success()
+            .stdout("2\n");
+    }
+    // The same everywhere, unlike aHash
+    assert(
+        "1\n",
+        &["--hasher", "wyhash", "--seed", "1", "--print-hash"],
+    )
+    .success()
+    .stdout("32af6304c6a2dc8a\t1\n");
+    assert("a\n", &["--hasher", "md5"]).failure();
+    assert("a\n", &["--hasher", "siphash", "--dump-state", "x"]).failure();
+}
+
+#[test]
+fn secure() {
+    assert("a\nb\na\nc\nb\n", &["--secure"])
+        .success()
+        .stdout("a\nb\nc\n");
+    assert("a\nb\na\n", &["--secure", "--difference"]).success();
+    assert("a\n", &["--secure", "--hasher", "ahash"]).failure();
+    assert("ff\n", &["--secure", "--hash-input"]).failure();
+}
+
+#[test]
+fn seed() {
+    let hashes = || {
+        let out = assert("a\nb\n", &["--seed", "c0ffee", "--print-hash"]).success();
+        out.get_output().stdout.clone()
+    };
+    assert_eq!(hashes(), hashes());
+    let state = tmp_path("seed_state");
+    assert("a\n", &["--seed", "1", "--dump-state", &state]).success();
+    assert("a\nb\n", &["--seed", "0x01", "--load-state", &state])
+        .success()
+        .stdout("b\n");
+    assert("a\nb\n", &["--seed", "2", "--load-state", &state]).failure();
+    fs::remove_file(&state).unwrap();
+    assert("a\n", &["--seed", "xyz"]).failure();
+}
+
+#[test]
+fn detect_collisions() {
+    let out = assert("a\nb\na\nc\nb\n", &["--detect-collisions"])
+        .success()
+        .stdout("a\nb\nc\n");
+    assert!(out.get_output().stderr.is_empty());
+    assert("a b\nc b\n", &["--detect-collisions", "-k", "2"])
+        .success()
+        .stdout("a b\n");
+    let out = assert(
+        "a\nb\na\nb\nb\n",
+        &["--detect-collisions", "--reset-every", "3"],
+    )
+    .success()
+    .stdout("a\nb\nb\n");
+    assert!(out.get_output().stderr.is_empty());
+    // Only the 64 bit hashes are checked
+    for args in [&["--exact"][..], &["--hash-bits", "128"], &["--fuzzy"]] {
+        assert("a\n", &[&["--detect-collisions"], args].concat()).failure();
+    }
+    assert("a\n", &["--detect-collisions", "-c"]).failure();
+}
+
+fn tmp_path(name: &str) -> String {
+    let mut path = env::temp_dir();
+    path.push(format!("huniq-test-{}-{}", process::id(), name));
+    path.to_str().unwrap().to_owned()
+}
+
+fn fixture(name: &str) -> String {
+    format!("{}/test/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
 fn assert(input: &str, args: &[&str]) -> Assert {
     let mut cmd = Command::cargo_bin("huniq").unwrap();
     cmd.args(args).write_stdin(input).assert()
@@ -0,0 +1,89 @@
+#! /usr/bin/env python3
+
+# Generates src/unicode_tables.rs, the tables for normalizing Unicode
+# text, from the Unicode database of the Python running this; run
+# `cargo fmt` afterwards.
+
+import os
+import sys
+import unicodedata
+
+HANGUL = range(0xAC00, 0xD7A4)
+
+
+def char(cp):
+    return "'\\u{%x}'" % cp
+
+
+def decompositions():
+    canonical, compatibility = [], []
+    for cp in range(sys.maxunicode + 1):
+        decomp = unicodedata.decomposition(chr(cp))
+        if not decomp or cp in HANGUL:
+            continue
+        parts = decomp.split()
+        table = canonical
+        if parts[0].startswith("<"):
+            table = compatibility
+            parts = parts[1:]
+        table.append((cp, [int(p, 16) for p in parts]))
+    return canonical, compatibility
+
+
+def combining_classes():
+    ranges = []
+    for cp in range(sys.maxunicode + 1):
+        ccc = unicodedata.combining(chr(cp))
+        if not ccc:
+            continue
+        if ranges and ranges[-1][1] == cp - 1 and ranges[-1][2] == ccc:
+            ranges[-1][1] = cp
+        else:
+            ranges.append([cp, cp, ccc])
+    return ranges
+
+
+def compositions(canonical):
+    pairs = []
+    for cp, parts in canonical:
+        # Leaves out the exclusions, which don't compose back
+        if len(parts) == 2 and unicodedata.normalize("NFC", "".join(map(chr, parts))) == chr(cp):
+            pairs.append((parts[0], parts[1], cp))
+    return sorted(pairs)
+
+
+def main():
+    canonical, compatibility = decompositions()
+    out = []
+    out.append("//! Tables for normalizing Unicode text, generated by unicode_tables.py")
+    out.append("//! from Unicode %s; do not edit." % unicodedata.unidata_version)
+    out.append("")
+    out.append("/// Ranges of characters with a canonical combining class other than zero")
+    out.append("pub static COMBINING_CLASSES: &[(char, char, u8)] = &[")
+    for lo, hi, ccc in combining_classes():
+        out.append("    (%s, %s, %d)," % (char(lo), char(hi), ccc))
+    out.append("];")
+    for name, doc, table in [
+        ("CANONICAL", "canonical decomposition", canonical),
+        ("COMPATIBILITY", "compatibility decomposition", compatibility),
+    ]:
+        out.append("")
+        out.append("/// The %s of each character having one, one level deep;" % doc)
+        out.append("/// without the Hangul syllables, which are decomposed algorithmically")
+        out.append("pub static %s: &[(char, &[char])] = &[" % name)
+        for cp, parts in table:
+            out.append("    (%s, &[%s])," % (char(cp), ", ".join(map(char, parts))))
+        out.append("];")
+    out.append("")
+    out.append("/// The characters that pairs of characters compose to, sorted by the pair")
+    out.append("pub static COMPOSITIONS: &[((char, char), char)] = &[")
+    for first, second, cp in compositions(canonical):
+        out.append("    ((%s, %s), %s)," % (char(first), char(second), char(cp)))
+    out.append("];")
+
+    path = os.path.join(os.path.dirname(os.path.abspath(__file__)), "src", "unicode_tables.rs")
+    with open(path, "w") as f:
+        f.write("\n".join(out) + "\n")
+
+
+main()